@@ -14,7 +14,7 @@ FILE_DIR=$(dirname "$FILE_PATH")
 FILE_NAME=$(basename "$FILE_PATH" .s)
 
 # Assemble the assembly file into an object file in the same directory
-riscv64-unknown-elf-as -march=rv32i -o "${FILE_DIR}/${FILE_NAME}.o" "$FILE_PATH"
+riscv64-unknown-elf-as -march=rv32imafdc -o "${FILE_DIR}/${FILE_NAME}.o" "$FILE_PATH"
 
 # Link the object file to create an executable in the same directory
 riscv64-unknown-elf-ld -m elf32lriscv -o "${FILE_DIR}/${FILE_NAME}" "${FILE_DIR}/${FILE_NAME}.o"
@@ -0,0 +1,1124 @@
+//! Small two pass assembler for RV32IMA + Zicsr, enough to write test programs inline instead
+//! of checking in binaries. Supports labels, `.text`/`.data`, `.word`/`.half`/`.byte`,
+//! `.ascii`/`.asciz`, `.zero` and `.align`, `%hi`/`%lo` and the common pseudo-instructions
+//! (li, la, mv, j, call, ret, beqz, csrr, ...). `.data` is placed after `.text` in one image.
+//! No floating point or compressed instructions.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::csr;
+use crate::instruction::{
+    RV5Instruction, RV5Itype, RV5Jtype, RV5Rtype, RV5SBtype, RV5Stype, RVUtype, REGISTER_NAMES,
+};
+use crate::symbols::{Symbol, SymbolKind, SymbolTable};
+
+const LOAD: u32 = 0b0000011;
+const MISC_MEM: u32 = 0b0001111;
+const OP_IMM: u32 = 0b0010011;
+const AUIPC: u32 = 0b0010111;
+const STORE: u32 = 0b0100011;
+const AMO: u32 = 0b0101111;
+const OP: u32 = 0b0110011;
+const LUI: u32 = 0b0110111;
+const BRANCH: u32 = 0b1100011;
+const JALR: u32 = 0b1100111;
+const JAL: u32 = 0b1101111;
+const SYSTEM: u32 = 0b1110011;
+
+#[derive(Debug)]
+pub struct Program {
+    /// address of the first byte of `image`
+    pub base: u32,
+    /// `.text` followed by `.data`
+    pub image: Vec<u8>,
+    /// every label, as untyped symbols
+    pub symbols: SymbolTable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownInstruction(String),
+    UnknownDirective(String),
+    /// wrong number or kind of operands, carries the expected syntax
+    Operands(&'static str),
+    UnknownRegister(String),
+    InvalidValue(String),
+    UndefinedSymbol(String),
+    DuplicateLabel(String),
+    /// immediate or offset that doesn't fit its field
+    OutOfRange(i64),
+    /// branch or jump offset that isn't a multiple of 2
+    Misaligned(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based source line
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownInstruction(name) => write!(f, "unknown instruction {}", name),
+            AsmErrorKind::UnknownDirective(name) => write!(f, "unknown directive {}", name),
+            AsmErrorKind::Operands(syntax) => write!(f, "expected operands {}", syntax),
+            AsmErrorKind::UnknownRegister(name) => write!(f, "unknown register {}", name),
+            AsmErrorKind::InvalidValue(value) => write!(f, "invalid value {}", value),
+            AsmErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol {}", name),
+            AsmErrorKind::DuplicateLabel(name) => write!(f, "label {} defined twice", name),
+            AsmErrorKind::OutOfRange(value) => write!(f, "{} is out of range", value),
+            AsmErrorKind::Misaligned(offset) => {
+                write!(f, "offset {} isn't a multiple of 2", offset)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+enum Item<'a> {
+    Instruction {
+        mnemonic: &'a str,
+        operands: Vec<&'a str>,
+    },
+    Directive {
+        name: &'a str,
+        args: &'a str,
+    },
+}
+
+struct Statement<'a> {
+    line: usize,
+    section: Section,
+    /// bytes it takes, worked out in the first pass
+    size: u32,
+    item: Item<'a>,
+}
+
+/// Assemble `source` into an image loaded at `base`
+pub fn assemble(source: &str, base: u32) -> Result<Program, AsmError> {
+    // first pass: sizes and label offsets within their section
+    let mut statements = Vec::new();
+    let mut labels: Vec<(&str, Section, u32)> = Vec::new();
+    let mut globals = HashSet::new();
+    let mut section = Section::Text;
+    let mut sizes = [0u32; 2];
+    let mut data_align = 4;
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| AsmError { line, kind };
+        let mut rest = strip_comment(raw).trim();
+        while let Some((label, after)) = split_label(rest) {
+            if labels.iter().any(|&(name, _, _)| name == label) {
+                return Err(error(AsmErrorKind::DuplicateLabel(label.to_string())));
+            }
+            labels.push((label, section, sizes[section as usize]));
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (head, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let tail = tail.trim();
+        let offset = sizes[section as usize];
+        let (size, item) = match head {
+            ".text" => {
+                section = Section::Text;
+                continue;
+            }
+            ".data" | ".rodata" | ".bss" => {
+                section = Section::Data;
+                continue;
+            }
+            ".section" => {
+                let name = tail.split(',').next().unwrap_or("").trim();
+                section = match name.split('.').nth(1) {
+                    Some("text") => Section::Text,
+                    Some("data" | "rodata" | "bss" | "sdata" | "sbss") => Section::Data,
+                    _ => return Err(error(AsmErrorKind::UnknownDirective(rest.to_string()))),
+                };
+                continue;
+            }
+            ".globl" | ".global" => {
+                globals.extend(tail.split(',').map(str::trim));
+                continue;
+            }
+            _ if head.starts_with('.') => {
+                let size = directive_size(head, tail, offset).map_err(error)?;
+                if let Some(align) = alignment(head, tail) {
+                    if section == Section::Data {
+                        data_align = data_align.max(align);
+                    }
+                }
+                (
+                    size,
+                    Item::Directive {
+                        name: head,
+                        args: tail,
+                    },
+                )
+            }
+            _ => {
+                let operands: Vec<&str> = match tail {
+                    "" => Vec::new(),
+                    _ => split_args(tail),
+                };
+                let size = 4 * instruction_words(head, &operands);
+                (
+                    size,
+                    Item::Instruction {
+                        mnemonic: head,
+                        operands,
+                    },
+                )
+            }
+        };
+        sizes[section as usize] += size;
+        statements.push(Statement {
+            line,
+            section,
+            size,
+            item,
+        });
+    }
+
+    let data_base = align_up(base.wrapping_add(sizes[Section::Text as usize]), data_align);
+    let section_base = |section| match section {
+        Section::Text => base,
+        Section::Data => data_base,
+    };
+    let assembler = Assembler {
+        symbols: labels
+            .iter()
+            .map(|&(name, section, offset)| {
+                (name.to_string(), section_base(section).wrapping_add(offset))
+            })
+            .collect(),
+    };
+
+    // second pass: encode with every label known
+    let mut text = Vec::new();
+    let mut data = Vec::new();
+    for statement in &statements {
+        let buffer = match statement.section {
+            Section::Text => &mut text,
+            Section::Data => &mut data,
+        };
+        let pc = section_base(statement.section).wrapping_add(buffer.len() as u32);
+        let start = buffer.len();
+        let emitted = match &statement.item {
+            Item::Instruction { mnemonic, operands } => assembler
+                .instruction(mnemonic, operands, pc)
+                .map(|instructions| {
+                    for instruction in instructions {
+                        buffer.extend(instruction.encode().to_le_bytes());
+                    }
+                }),
+            Item::Directive { name, args } => assembler.directive(name, args, buffer),
+        };
+        emitted.map_err(|kind| AsmError {
+            line: statement.line,
+            kind,
+        })?;
+        debug_assert_eq!(buffer.len() - start, statement.size as usize);
+    }
+
+    let mut image = text;
+    image.resize((data_base.wrapping_sub(base)) as usize, 0);
+    image.extend(data);
+    let mut symbols = SymbolTable::new();
+    for (name, &addr) in &assembler.symbols {
+        symbols.insert(Symbol {
+            name: name.clone(),
+            addr,
+            size: 0,
+            kind: SymbolKind::Label,
+            global: globals.contains(name.as_str()),
+        });
+    }
+    Ok(Program {
+        base,
+        image,
+        symbols,
+    })
+}
+
+struct Assembler {
+    symbols: HashMap<String, u32>,
+}
+
+impl Assembler {
+    /// A number, `symbol`, `symbol+offset`, `%hi(...)` or `%lo(...)`
+    fn value(&self, expr: &str) -> Result<i64, AsmErrorKind> {
+        let expr = expr.trim();
+        if let Some(inner) = strip_call(expr, "%hi") {
+            return Ok((self.value(inner)? + 0x800) >> 12 & 0xFFFFF);
+        }
+        if let Some(inner) = strip_call(expr, "%lo") {
+            return Ok(sign_extend_12(self.value(inner)?));
+        }
+        if let Some(value) = parse_number(expr) {
+            return Ok(value);
+        }
+        let (name, offset) = match expr.rfind(['+', '-']) {
+            Some(at) if at > 0 => {
+                let offset = parse_number(expr[at + 1..].trim())
+                    .ok_or_else(|| AsmErrorKind::InvalidValue(expr.to_string()))?;
+                let offset = if &expr[at..at + 1] == "-" {
+                    -offset
+                } else {
+                    offset
+                };
+                (expr[..at].trim(), offset)
+            }
+            _ => (expr, 0),
+        };
+        match self.symbols.get(name) {
+            Some(&addr) => Ok(addr as i64 + offset),
+            None if is_identifier(name) => Err(AsmErrorKind::UndefinedSymbol(name.to_string())),
+            None => Err(AsmErrorKind::InvalidValue(expr.to_string())),
+        }
+    }
+
+    /// Offset from `pc` to the target address `expr`
+    fn offset(&self, expr: &str, pc: u32) -> Result<i64, AsmErrorKind> {
+        Ok(self.value(expr)? - pc as i64)
+    }
+
+    /// `imm(reg)`, the immediate may be left out
+    fn memory(&self, operand: &str) -> Result<(i64, u32), AsmErrorKind> {
+        let syntax = AsmErrorKind::Operands("offset(register)");
+        // the last parenthesis, the offset may be `%lo(symbol)`
+        let (offset, base) = operand.rsplit_once('(').ok_or(syntax.clone())?;
+        let base = base.strip_suffix(')').ok_or(syntax)?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.value(offset)?,
+        };
+        Ok((offset, register(base)?))
+    }
+
+    fn csr(&self, operand: &str) -> Result<u32, AsmErrorKind> {
+        match csr::address(operand) {
+            Some(addr) => Ok(addr as u32),
+            None => match self.value(operand)? {
+                addr @ 0..=0xFFF => Ok(addr as u32),
+                addr => Err(AsmErrorKind::OutOfRange(addr)),
+            },
+        }
+    }
+
+    fn instruction(
+        &self,
+        mnemonic: &str,
+        ops: &[&str],
+        pc: u32,
+    ) -> Result<Vec<RV5Instruction>, AsmErrorKind> {
+        if let Some((funct3, funct7)) = register_op(mnemonic) {
+            let [rd, rs1, rs2] = operands(ops, "rd, rs1, rs2")?;
+            return Ok(vec![r(
+                OP,
+                funct3,
+                funct7,
+                register(rd)?,
+                register(rs1)?,
+                register(rs2)?,
+            )]);
+        }
+        if let Some(funct3) = immediate_op(mnemonic) {
+            let [rd, rs1, imm] = operands(ops, "rd, rs1, imm")?;
+            let (rd, rs1, imm) = (register(rd)?, register(rs1)?, self.value(imm)?);
+            let instruction = match mnemonic {
+                "slli" | "srli" | "srai" => {
+                    if !(0..32).contains(&imm) {
+                        return Err(AsmErrorKind::OutOfRange(imm));
+                    }
+                    let funct7 = if mnemonic == "srai" { 0x400 } else { 0 };
+                    i(OP_IMM, funct3, rd, rs1, imm | funct7)?
+                }
+                _ => i(OP_IMM, funct3, rd, rs1, imm)?,
+            };
+            return Ok(vec![instruction]);
+        }
+        if let Some(funct3) = load_op(mnemonic) {
+            let [rd, address] = operands(ops, "rd, offset(rs1)")?;
+            let (offset, rs1) = self.memory(address)?;
+            return Ok(vec![i(LOAD, funct3, register(rd)?, rs1, offset)?]);
+        }
+        if let Some(funct3) = store_op(mnemonic) {
+            let [rs2, address] = operands(ops, "rs2, offset(rs1)")?;
+            let (offset, rs1) = self.memory(address)?;
+            return Ok(vec![s(funct3, register(rs2)?, rs1, offset)?]);
+        }
+        if let Some(funct3) = branch_op(mnemonic) {
+            let [rs1, rs2, target] = operands(ops, "rs1, rs2, target")?;
+            let offset = self.offset(target, pc)?;
+            return Ok(vec![b(funct3, register(rs1)?, register(rs2)?, offset)?]);
+        }
+        if let Some((funct3, immediate)) = csr_op(mnemonic) {
+            let [rd, csr, source] = operands(ops, "rd, csr, rs1")?;
+            let source = self.csr_source(source, immediate)?;
+            return Ok(vec![csr_instruction(
+                funct3,
+                register(rd)?,
+                self.csr(csr)?,
+                source,
+            )]);
+        }
+        if let Some(instruction) = self.atomic(mnemonic, ops)? {
+            return Ok(vec![instruction]);
+        }
+
+        let instructions = match mnemonic {
+            "lui" | "auipc" => {
+                let [rd, imm] = operands(ops, "rd, imm")?;
+                let opcode = if mnemonic == "lui" { LUI } else { AUIPC };
+                vec![u(opcode, register(rd)?, self.value(imm)?)?]
+            }
+            "jal" => match ops {
+                [target] => vec![j(1, self.offset(target, pc)?)?],
+                [rd, target] => vec![j(register(rd)?, self.offset(target, pc)?)?],
+                _ => return Err(AsmErrorKind::Operands("[rd,] target")),
+            },
+            "jalr" => match ops {
+                [rs1] => vec![i(JALR, 0, 1, register(rs1)?, 0)?],
+                [rd, address] => {
+                    let (offset, rs1) = self.memory(address)?;
+                    vec![i(JALR, 0, register(rd)?, rs1, offset)?]
+                }
+                [rd, rs1, offset] => {
+                    vec![i(
+                        JALR,
+                        0,
+                        register(rd)?,
+                        register(rs1)?,
+                        self.value(offset)?,
+                    )?]
+                }
+                _ => return Err(AsmErrorKind::Operands("rd, offset(rs1)")),
+            },
+            "ecall" | "ebreak" | "mret" | "sret" | "wfi" | "nop" | "ret" | "fence.i" => {
+                operands::<0>(ops, "")?;
+                vec![match mnemonic {
+                    "ecall" => RV5Instruction::ECALL,
+                    "ebreak" => RV5Instruction::EBREAK,
+                    "mret" => RV5Instruction::MRET,
+                    "sret" => RV5Instruction::SRET,
+                    "wfi" => RV5Instruction::WFI,
+                    "nop" => i(OP_IMM, 0, 0, 0, 0)?,
+                    "ret" => i(JALR, 0, 0, 1, 0)?,
+                    _ => i(MISC_MEM, 0b001, 0, 0, 0)?,
+                }]
+            }
+            "fence" => {
+                let (pred, succ) = match ops {
+                    [] => (0xF, 0xF),
+                    [pred, succ] => (fence_set(pred)?, fence_set(succ)?),
+                    _ => return Err(AsmErrorKind::Operands("[pred, succ]")),
+                };
+                vec![i(MISC_MEM, 0, 0, 0, (pred << 4 | succ) as i64)?]
+            }
+            "sfence.vma" => {
+                let (rs1, rs2) = match ops {
+                    [] => (0, 0),
+                    [rs1] => (register(rs1)?, 0),
+                    [rs1, rs2] => (register(rs1)?, register(rs2)?),
+                    _ => return Err(AsmErrorKind::Operands("[rs1[, rs2]]")),
+                };
+                vec![RV5Instruction::SFENCE(RV5Rtype {
+                    funct7: 0b0001001,
+                    rs2,
+                    rs1,
+                    funct3: 0,
+                    rd: 0,
+                    opcode: SYSTEM,
+                })]
+            }
+
+            // pseudo-instructions
+            "li" => {
+                let [rd, imm] = operands(ops, "rd, imm")?;
+                let rd = register(rd)?;
+                let value = self.value(imm)?;
+                if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                    return Err(AsmErrorKind::OutOfRange(value));
+                }
+                let value = value as u32 as i32 as i64;
+                match li_words(parse_number(imm)) {
+                    1 if (-2048..2048).contains(&value) => vec![i(OP_IMM, 0, rd, 0, value)?],
+                    1 => vec![u(LUI, rd, value >> 12 & 0xFFFFF)?],
+                    _ => vec![
+                        u(LUI, rd, (value + 0x800) >> 12 & 0xFFFFF)?,
+                        i(OP_IMM, 0, rd, rd, sign_extend_12(value))?,
+                    ],
+                }
+            }
+            "la" => {
+                let [rd, symbol] = operands(ops, "rd, symbol")?;
+                let rd = register(rd)?;
+                let (hi, lo) = pc_relative(self.offset(symbol, pc)?);
+                vec![u(AUIPC, rd, hi)?, i(OP_IMM, 0, rd, rd, lo)?]
+            }
+            "call" | "tail" => {
+                let [target] = operands(ops, "target")?;
+                let (hi, lo) = pc_relative(self.offset(target, pc)?);
+                // call links through ra, tail goes through t1 and doesn't link
+                let (scratch, rd) = if mnemonic == "call" { (1, 1) } else { (6, 0) };
+                vec![u(AUIPC, scratch, hi)?, i(JALR, 0, rd, scratch, lo)?]
+            }
+            "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" => {
+                let [rd, rs] = operands(ops, "rd, rs")?;
+                let (rd, rs) = (register(rd)?, register(rs)?);
+                vec![match mnemonic {
+                    "mv" => i(OP_IMM, 0, rd, rs, 0)?,
+                    "not" => i(OP_IMM, 0b100, rd, rs, -1)?,
+                    "neg" => r(OP, 0b000, 0b0100000, rd, 0, rs),
+                    "seqz" => i(OP_IMM, 0b011, rd, rs, 1)?,
+                    "snez" => r(OP, 0b011, 0, rd, 0, rs),
+                    "sltz" => r(OP, 0b010, 0, rd, rs, 0),
+                    _ => r(OP, 0b010, 0, rd, 0, rs),
+                }]
+            }
+            "j" => {
+                let [target] = operands(ops, "target")?;
+                vec![j(0, self.offset(target, pc)?)?]
+            }
+            "jr" => {
+                let [rs] = operands(ops, "rs")?;
+                vec![i(JALR, 0, 0, register(rs)?, 0)?]
+            }
+            "beqz" | "bnez" | "bltz" | "bgez" | "blez" | "bgtz" => {
+                let [rs, target] = operands(ops, "rs, target")?;
+                let (rs, offset) = (register(rs)?, self.offset(target, pc)?);
+                vec![match mnemonic {
+                    "beqz" => b(0b000, rs, 0, offset)?,
+                    "bnez" => b(0b001, rs, 0, offset)?,
+                    "bltz" => b(0b100, rs, 0, offset)?,
+                    "bgez" => b(0b101, rs, 0, offset)?,
+                    "blez" => b(0b101, 0, rs, offset)?,
+                    _ => b(0b100, 0, rs, offset)?,
+                }]
+            }
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                // the swapped operands of blt, bge, bltu and bgeu
+                let [rs1, rs2, target] = operands(ops, "rs1, rs2, target")?;
+                let funct3 = match mnemonic {
+                    "bgt" => 0b100,
+                    "ble" => 0b101,
+                    "bgtu" => 0b110,
+                    _ => 0b111,
+                };
+                let offset = self.offset(target, pc)?;
+                vec![b(funct3, register(rs2)?, register(rs1)?, offset)?]
+            }
+            "csrr" => {
+                let [rd, csr] = operands(ops, "rd, csr")?;
+                vec![csr_instruction(0b010, register(rd)?, self.csr(csr)?, 0)]
+            }
+            "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+                let [csr, source] = operands(ops, "csr, rs1")?;
+                let (funct3, immediate) = csr_op(&format!("csrr{}", &mnemonic[3..])).unwrap();
+                let source = self.csr_source(source, immediate)?;
+                vec![csr_instruction(funct3, 0, self.csr(csr)?, source)]
+            }
+            _ => return Err(AsmErrorKind::UnknownInstruction(mnemonic.to_string())),
+        };
+        Ok(instructions)
+    }
+
+    /// A register, or a 5-bit unsigned immediate for the `i` forms
+    fn csr_source(&self, source: &str, immediate: bool) -> Result<u32, AsmErrorKind> {
+        if !immediate {
+            return register(source);
+        }
+        match self.value(source)? {
+            uimm @ 0..32 => Ok(uimm as u32),
+            uimm => Err(AsmErrorKind::OutOfRange(uimm)),
+        }
+    }
+
+    /// `lr.w`, `sc.w` and the AMOs, with an optional `.aq`, `.rl` or `.aqrl`
+    fn atomic(&self, mnemonic: &str, ops: &[&str]) -> Result<Option<RV5Instruction>, AsmErrorKind> {
+        let (base, ordering) = [(".aqrl", 0b11), (".aq", 0b10), (".rl", 0b01)]
+            .iter()
+            .find_map(|&(suffix, bits)| Some((mnemonic.strip_suffix(suffix)?, bits)))
+            .unwrap_or((mnemonic, 0));
+        let funct5 = match base {
+            "lr.w" => 0b00010,
+            "sc.w" => 0b00011,
+            "amoswap.w" => 0b00001,
+            "amoadd.w" => 0b00000,
+            "amoxor.w" => 0b00100,
+            "amoand.w" => 0b01100,
+            "amoor.w" => 0b01000,
+            "amomin.w" => 0b10000,
+            "amomax.w" => 0b10100,
+            "amominu.w" => 0b11000,
+            "amomaxu.w" => 0b11100,
+            _ => return Ok(None),
+        };
+        let (rd, rs2, address) = match base {
+            "lr.w" => {
+                let [rd, address] = operands(ops, "rd, (rs1)")?;
+                (rd, None, address)
+            }
+            _ => {
+                let [rd, rs2, address] = operands(ops, "rd, rs2, (rs1)")?;
+                (rd, Some(rs2), address)
+            }
+        };
+        let (offset, rs1) = self.memory(address)?;
+        if offset != 0 {
+            return Err(AsmErrorKind::Operands("rd, rs2, (rs1)"));
+        }
+        let rs2 = rs2.map(register).transpose()?.unwrap_or(0);
+        Ok(Some(r(
+            AMO,
+            0b010,
+            funct5 << 2 | ordering,
+            register(rd)?,
+            rs1,
+            rs2,
+        )))
+    }
+
+    /// Append the bytes of a data directive to `buffer`
+    fn directive(&self, name: &str, args: &str, buffer: &mut Vec<u8>) -> Result<(), AsmErrorKind> {
+        match name {
+            ".word" | ".half" | ".byte" => {
+                let width = match name {
+                    ".word" => 4,
+                    ".half" => 2,
+                    _ => 1,
+                };
+                for arg in split_args(args) {
+                    let value = self.value(arg)?;
+                    let limit = 1i64 << (8 * width);
+                    if value < -(limit / 2) || value >= limit {
+                        return Err(AsmErrorKind::OutOfRange(value));
+                    }
+                    buffer.extend(&value.to_le_bytes()[..width]);
+                }
+            }
+            ".ascii" | ".asciz" | ".string" => {
+                buffer.extend(parse_strings(args)?);
+                if name != ".ascii" {
+                    buffer.push(0);
+                }
+            }
+            _ => {
+                let size = directive_size(name, args, buffer.len() as u32)?;
+                buffer.resize(buffer.len() + size as usize, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Exactly `N` operands
+fn operands<'a, const N: usize>(
+    ops: &[&'a str],
+    syntax: &'static str,
+) -> Result<[&'a str; N], AsmErrorKind> {
+    ops.try_into().map_err(|_| AsmErrorKind::Operands(syntax))
+}
+
+fn register(name: &str) -> Result<u32, AsmErrorKind> {
+    let name = name.trim();
+    let index = match name {
+        "fp" => Some(8),
+        _ => match name.strip_prefix('x').and_then(|n| n.parse::<u32>().ok()) {
+            Some(n) if n < 32 => Some(n),
+            _ => REGISTER_NAMES
+                .iter()
+                .position(|&abi| abi == name)
+                .map(|n| n as u32),
+        },
+    };
+    index.ok_or_else(|| AsmErrorKind::UnknownRegister(name.to_string()))
+}
+
+/// funct3 and funct7 of the register-register ALU instructions
+fn register_op(mnemonic: &str) -> Option<(u32, u32)> {
+    Some(match mnemonic {
+        "add" => (0b000, 0),
+        "sub" => (0b000, 0b0100000),
+        "sll" => (0b001, 0),
+        "slt" => (0b010, 0),
+        "sltu" => (0b011, 0),
+        "xor" => (0b100, 0),
+        "srl" => (0b101, 0),
+        "sra" => (0b101, 0b0100000),
+        "or" => (0b110, 0),
+        "and" => (0b111, 0),
+        "mul" => (0b000, 1),
+        "mulh" => (0b001, 1),
+        "mulhsu" => (0b010, 1),
+        "mulhu" => (0b011, 1),
+        "div" => (0b100, 1),
+        "divu" => (0b101, 1),
+        "rem" => (0b110, 1),
+        "remu" => (0b111, 1),
+        _ => return None,
+    })
+}
+
+fn immediate_op(mnemonic: &str) -> Option<u32> {
+    Some(match mnemonic {
+        "addi" => 0b000,
+        "slli" => 0b001,
+        "slti" => 0b010,
+        "sltiu" => 0b011,
+        "xori" => 0b100,
+        "srli" | "srai" => 0b101,
+        "ori" => 0b110,
+        "andi" => 0b111,
+        _ => return None,
+    })
+}
+
+fn load_op(mnemonic: &str) -> Option<u32> {
+    Some(match mnemonic {
+        "lb" => 0b000,
+        "lh" => 0b001,
+        "lw" => 0b010,
+        "lbu" => 0b100,
+        "lhu" => 0b101,
+        _ => return None,
+    })
+}
+
+fn store_op(mnemonic: &str) -> Option<u32> {
+    Some(match mnemonic {
+        "sb" => 0b000,
+        "sh" => 0b001,
+        "sw" => 0b010,
+        _ => return None,
+    })
+}
+
+fn branch_op(mnemonic: &str) -> Option<u32> {
+    Some(match mnemonic {
+        "beq" => 0b000,
+        "bne" => 0b001,
+        "blt" => 0b100,
+        "bge" => 0b101,
+        "bltu" => 0b110,
+        "bgeu" => 0b111,
+        _ => return None,
+    })
+}
+
+/// funct3 of the Zicsr instructions and whether the source is an immediate
+fn csr_op(mnemonic: &str) -> Option<(u32, bool)> {
+    Some(match mnemonic {
+        "csrrw" => (0b001, false),
+        "csrrs" => (0b010, false),
+        "csrrc" => (0b011, false),
+        "csrrwi" => (0b101, true),
+        "csrrsi" => (0b110, true),
+        "csrrci" => (0b111, true),
+        _ => return None,
+    })
+}
+
+fn r(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> RV5Instruction {
+    RV5Instruction::R(RV5Rtype {
+        funct7,
+        rs2,
+        rs1,
+        funct3,
+        rd,
+        opcode,
+    })
+}
+
+fn i(
+    opcode: u32,
+    funct3: u32,
+    rd: u32,
+    rs1: u32,
+    imm: i64,
+) -> Result<RV5Instruction, AsmErrorKind> {
+    if !(-2048..2048).contains(&imm) {
+        return Err(AsmErrorKind::OutOfRange(imm));
+    }
+    Ok(RV5Instruction::I(RV5Itype {
+        imm: imm as u32 & 0xFFF,
+        rs1,
+        funct3,
+        rd,
+        opcode,
+    }))
+}
+
+fn csr_instruction(funct3: u32, rd: u32, csr: u32, rs1: u32) -> RV5Instruction {
+    RV5Instruction::CSR(RV5Itype {
+        imm: csr,
+        rs1,
+        funct3,
+        rd,
+        opcode: SYSTEM,
+    })
+}
+
+fn s(funct3: u32, rs2: u32, rs1: u32, imm: i64) -> Result<RV5Instruction, AsmErrorKind> {
+    if !(-2048..2048).contains(&imm) {
+        return Err(AsmErrorKind::OutOfRange(imm));
+    }
+    Ok(RV5Instruction::S(RV5Stype {
+        imm: imm as u32 & 0xFFF,
+        rs2,
+        rs1,
+        funct3,
+        opcode: STORE,
+    }))
+}
+
+fn b(funct3: u32, rs1: u32, rs2: u32, offset: i64) -> Result<RV5Instruction, AsmErrorKind> {
+    if offset % 2 != 0 {
+        return Err(AsmErrorKind::Misaligned(offset));
+    }
+    if !(-4096..4096).contains(&offset) {
+        return Err(AsmErrorKind::OutOfRange(offset));
+    }
+    Ok(RV5Instruction::SB(RV5SBtype {
+        imm: offset as u32 & 0x1FFF,
+        rs2,
+        rs1,
+        funct3,
+        opcode: BRANCH,
+    }))
+}
+
+fn j(rd: u32, offset: i64) -> Result<RV5Instruction, AsmErrorKind> {
+    if offset % 2 != 0 {
+        return Err(AsmErrorKind::Misaligned(offset));
+    }
+    if !(-(1 << 20)..1 << 20).contains(&offset) {
+        return Err(AsmErrorKind::OutOfRange(offset));
+    }
+    Ok(RV5Instruction::J(RV5Jtype {
+        imm: offset as u32 & 0x1FFFFF,
+        rd,
+        opcode: JAL,
+    }))
+}
+
+fn u(opcode: u32, rd: u32, imm20: i64) -> Result<RV5Instruction, AsmErrorKind> {
+    if !(0..1 << 20).contains(&imm20) {
+        return Err(AsmErrorKind::OutOfRange(imm20));
+    }
+    Ok(RV5Instruction::U(RVUtype {
+        imm20: imm20 as u32,
+        rd,
+        opcode,
+    }))
+}
+
+/// auipc and the low 12 bits reaching `offset`
+fn pc_relative(offset: i64) -> (i64, i64) {
+    let offset = offset as u32 as i32 as i64;
+    ((offset + 0x800) >> 12 & 0xFFFFF, sign_extend_12(offset))
+}
+
+fn sign_extend_12(value: i64) -> i64 {
+    ((value & 0xFFF) ^ 0x800) - 0x800
+}
+
+/// Instructions `li` becomes, a value only known in the second pass takes lui + addi
+fn li_words(value: Option<i64>) -> u32 {
+    match value {
+        Some(value) => {
+            let value = value as u32 as i32 as i64;
+            match (-2048..2048).contains(&value) || value & 0xFFF == 0 {
+                true => 1,
+                false => 2,
+            }
+        }
+        None => 2,
+    }
+}
+
+/// How many instructions a mnemonic expands to, known before the labels are
+fn instruction_words(mnemonic: &str, operands: &[&str]) -> u32 {
+    match mnemonic {
+        "la" | "call" | "tail" => 2,
+        "li" => li_words(operands.get(1).and_then(|imm| parse_number(imm))),
+        _ => 1,
+    }
+}
+
+/// `.align` takes a power of two like the RISC-V GNU assembler, `.balign` bytes
+fn alignment(name: &str, args: &str) -> Option<u32> {
+    let value = parse_number(args.split(',').next()?.trim())?;
+    match name {
+        ".align" | ".p2align" => 1u32.checked_shl(value as u32),
+        ".balign" => Some(value as u32),
+        _ => None,
+    }
+}
+
+/// Bytes a data directive takes at `offset`
+fn directive_size(name: &str, args: &str, offset: u32) -> Result<u32, AsmErrorKind> {
+    let count = split_args(args).len() as u32;
+    let invalid = || AsmErrorKind::InvalidValue(args.to_string());
+    match name {
+        ".word" => Ok(4 * count),
+        ".half" => Ok(2 * count),
+        ".byte" => Ok(count),
+        ".ascii" => Ok(parse_strings(args)?.len() as u32),
+        ".asciz" | ".string" => Ok(parse_strings(args)?.len() as u32 + 1),
+        ".zero" | ".space" => parse_number(args)
+            .and_then(|size| u32::try_from(size).ok())
+            .ok_or_else(invalid),
+        ".align" | ".p2align" | ".balign" => {
+            let align = alignment(name, args).filter(|align| align.is_power_of_two());
+            let align = align.ok_or_else(invalid)?;
+            Ok(align_up(offset, align) - offset)
+        }
+        _ => Err(AsmErrorKind::UnknownDirective(name.to_string())),
+    }
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    value.wrapping_add(align - 1) & !(align - 1)
+}
+
+/// `pred`/`succ` of a fence from its `iorw` letters
+fn fence_set(letters: &str) -> Result<u32, AsmErrorKind> {
+    letters.chars().try_fold(0, |set, letter| match letter {
+        'i' => Ok(set | 0b1000),
+        'o' => Ok(set | 0b0100),
+        'r' => Ok(set | 0b0010),
+        'w' => Ok(set | 0b0001),
+        _ => Err(AsmErrorKind::InvalidValue(letters.to_string())),
+    })
+}
+
+/// Decimal, `0x` hex, `0b` binary or a `'c'` character, optionally negative
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if let Some(char) = digits.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let mut chars = char.chars();
+        let c = match chars.next()? {
+            '\\' => escape(chars.next()?)?,
+            c => c,
+        };
+        match chars.next() {
+            None => c as i64,
+            Some(_) => return None,
+        }
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Character of a C escape sequence after the backslash
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+/// Comma separated string literals with C escapes
+fn parse_strings(args: &str) -> Result<Vec<u8>, AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidValue(args.to_string());
+    let mut bytes = Vec::new();
+    for literal in split_args(args) {
+        let mut chars = literal.strip_prefix('"').ok_or_else(invalid)?.chars();
+        loop {
+            let c = match chars.next().ok_or_else(invalid)? {
+                '"' => break,
+                '\\' => chars.next().and_then(escape).ok_or_else(invalid)?,
+                c => c,
+            };
+            let mut utf8 = [0; 4];
+            bytes.extend(c.encode_utf8(&mut utf8).as_bytes());
+        }
+        if !chars.as_str().is_empty() {
+            return Err(invalid());
+        }
+    }
+    Ok(bytes)
+}
+
+/// Characters of `line` that aren't inside a string or character literal, with their offsets
+fn unquoted(line: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut quote = None;
+    let mut escaped = false;
+    line.char_indices().filter(move |&(_, c)| {
+        let outside = quote.is_none() && !matches!(c, '"' | '\'');
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote.is_some() => escaped = true,
+            '"' | '\'' if quote == Some(c) => quote = None,
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ => {}
+        }
+        outside
+    })
+}
+
+/// Comma separated operands or arguments, commas in literals don't count
+fn split_args(args: &str) -> Vec<&str> {
+    if args.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (at, _) in unquoted(args).filter(|&(_, c)| c == ',') {
+        parts.push(args[start..at].trim());
+        start = at + 1;
+    }
+    parts.push(args[start..].trim());
+    parts
+}
+
+/// The line up to a `#` comment that isn't inside a literal
+fn strip_comment(line: &str) -> &str {
+    match unquoted(line).find(|&(_, c)| c == '#') {
+        Some((at, _)) => &line[..at],
+        None => line,
+    }
+}
+
+/// `label:` at the start of the line and what follows it
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    is_identifier(label).then_some((label, rest))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+}
+
+/// `inner` of `name(inner)`
+fn strip_call<'a>(expr: &'a str, name: &str) -> Option<&'a str> {
+    expr.strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let program = assemble(
+            "
+            .globl _start
+            _start:
+                li    a0, 5          # fits addi
+                li    a1, 0x12345678
+                la    t0, value
+            loop: addi  a0, a0, -1
+                bnez  a0, loop
+                sw    a1, 4(t0)
+                csrr  t1, mstatus
+                amoswap.w.aq a0, a2, (a1)
+                call  loop
+                ret
+                lui   a0, %hi(value)
+                lw    a0, %lo(value)(a0)
+                sw    a0, %lo(value)(a0)
+            .data
+            value: .word 0xdeadbeef, loop
+            text:  .asciz \"hi\\n\"
+                   .byte ',', '#', '\\n'
+            ",
+            0x8000_0000,
+        )
+        .unwrap();
+        let words: Vec<u32> = program
+            .image
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap_or([word[0], 0, 0, 0])))
+            .collect();
+        assert_eq!(
+            &words[..16],
+            &[
+                0x00500513, // li a0, 5
+                0x123455b7, // lui a1, 0x12345
+                0x67858593, // addi a1, a1, 0x678
+                0x00000297, // auipc t0, 0
+                0x03428293, // addi t0, t0, 52
+                0xfff50513, // addi a0, a0, -1
+                0xfe051ee3, // bnez a0, loop
+                0x00b2a223, // sw a1, 4(t0)
+                0x30002373, // csrr t1, mstatus
+                0x0cc5a52f, // amoswap.w.aq a0, a2, (a1)
+                0x00000097, // auipc ra, 0
+                0xfec080e7, // jalr ra, -20(ra)
+                0x00008067, // ret
+                0x80000537, // lui a0, %hi(value)
+                0x04052503, // lw a0, %lo(value)(a0)
+                0x04a52023, // sw a0, %lo(value)(a0)
+            ]
+        );
+        assert_eq!(&words[16..18], &[0xdeadbeef, 0x80000014]);
+        assert_eq!(&program.image[72..], b"hi\n\0,#\n");
+        assert_eq!(program.symbols.lookup("value"), Some(0x80000040));
+        assert_eq!(program.symbols.lookup("_start"), Some(0x80000000));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source, 0).unwrap_err();
+        assert_eq!(
+            error("nop\nfoo a0"),
+            AsmError {
+                line: 2,
+                kind: AsmErrorKind::UnknownInstruction("foo".to_string())
+            }
+        );
+        assert_eq!(
+            error("addi a0, a0, 2048").kind,
+            AsmErrorKind::OutOfRange(2048)
+        );
+        assert_eq!(
+            error("j nowhere").kind,
+            AsmErrorKind::UndefinedSymbol("nowhere".to_string())
+        );
+        assert_eq!(
+            error("a: nop\na: nop").to_string(),
+            "line 2: label a defined twice"
+        );
+        assert_eq!(
+            error("add a0, a1").to_string(),
+            "line 1: expected operands rd, rs1, rs2"
+        );
+        assert_eq!(
+            error("mv a0, x32").kind,
+            AsmErrorKind::UnknownRegister("x32".to_string())
+        );
+    }
+}
@@ -1,34 +1,223 @@
-use std::thread;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process::ExitCode;
 
+use config::MachineConfig;
 use cpu::CPU;
+use debugger::Debugger;
+use gdbstub::GdbStub;
 use glob::glob;
+use loader::ElfError;
 use rv32i_lib::*;
+use trace::Tracer;
+use trap::Exception;
+use uart::{Uart, DEFAULT_UART_BASE, DEFAULT_UART_IRQ};
 
-fn main() {
-    let pattern = "riscv-tests/isa/rv32ui-*";
-    for entry in glob(pattern).expect("Failed to read glob pattern") {
+/// Upper bound on executed instructions before a test is considered hung
+const MAX_STEPS: usize = 1_000_000;
+
+enum TestResult {
+    Pass,
+    /// the file isn't an executable this machine can load
+    NotLoadable(ElfError),
+    /// failing riscv-tests test number (TESTNUM)
+    Fail(u32),
+    /// the program stopped without reporting a result
+    Stopped,
+    /// the program raised an exception the runner can't handle, where and on which instruction
+    Trapped(Exception, String),
+    Timeout,
+}
+
+/// Run a riscv-tests binary until it reports through `tohost` or the exit syscall.
+///
+/// Both conventions encode the result the same way: `1` (tohost) / `0` (a0) on success,
+/// `(TESTNUM << 1) | 1` on failure.
+fn run_test(path: &Path) -> TestResult {
+    let file_data = std::fs::read(path).expect("Could not read file.");
+    let binary_data = file_data.as_slice();
+
+    let mut cpu = CPU::new();
+    if let Err(error) = cpu.load_elf(binary_data) {
+        return TestResult::NotLoadable(error);
+    }
+
+    for _ in 0..MAX_STEPS {
+        if let Err(exception) = cpu.execute_ins() {
+            return TestResult::Trapped(exception, trapped_at(&mut cpu));
+        }
+
+        if let Some(value) = cpu.tohost_value().filter(|&value| value != 0) {
+            return match value {
+                1 => TestResult::Pass,
+                _ => TestResult::Fail(value >> 1),
+            };
+        }
+        if cpu.is_exited() {
+            return match cpu.exit_code {
+                Some(0) => TestResult::Pass,
+                Some(code) => TestResult::Fail(code >> 1),
+                None => TestResult::Stopped,
+            };
+        }
+    }
+    TestResult::Timeout
+}
+
+/// `0x80000104 <test_2+0x8> (lw a0,0(a1))`, the instruction left out if it can't be read
+fn trapped_at(cpu: &mut CPU) -> String {
+    let pc = cpu.pc();
+    match cpu.read_memory(pc, 4) {
+        Ok(bytes) => {
+            let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let text = disasm::disassemble(instruction, pc);
+            format!(
+                "{} ({})",
+                cpu.symbols.location(pc),
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            )
+        }
+        Err(_) => cpu.symbols.location(pc),
+    }
+}
+
+/// `cpu` with `program` loaded, errors are reported on stderr
+fn load_program(mut cpu: CPU, program: &Path) -> Option<CPU> {
+    let loaded = std::fs::read(program)
+        .map_err(|error| error.to_string())
+        .and_then(|data| cpu.load_elf(&data).map_err(|error| error.to_string()));
+    match loaded {
+        Ok(()) => Some(cpu),
+        Err(error) => {
+            eprintln!("{}: {}", program.display(), error);
+            None
+        }
+    }
+}
+
+/// Step through `program` at a prompt on the terminal
+fn debug(program: &Path) -> ExitCode {
+    // the terminal belongs to the prompt while the guest is stopped, a UART thread reading it
+    // would take the commands. The guest reads it with read(0) instead.
+    let mut cpu = CPU::with_config(MachineConfig {
+        uart_base: None,
+        ..Default::default()
+    });
+    let (uart, _) = Uart::new(Box::new(std::io::stdout()));
+    cpu.bus
+        .attach_with_irq(DEFAULT_UART_BASE, DEFAULT_UART_IRQ, Box::new(uart));
+    let Some(mut cpu) = load_program(cpu, program) else {
+        return ExitCode::FAILURE;
+    };
+    let read_line = |line: &mut String| std::io::stdin().read_line(line);
+    match Debugger::new().run(&mut cpu, read_line, std::io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run `program` to the end with a commit log of every instruction on stderr, like spike
+fn trace(program: &Path) -> ExitCode {
+    let Some(mut cpu) = load_program(CPU::new(), program) else {
+        return ExitCode::FAILURE;
+    };
+    let stderr = BufWriter::new(std::io::stderr());
+    cpu.set_tracer(Some(Tracer::new(Box::new(stderr))));
+    while !cpu.is_exited() && cpu.tohost_value().is_none_or(|value| value == 0) {
+        if let Err(exception) = cpu.execute_ins() {
+            // flush the log before the message
+            cpu.set_tracer(None);
+            eprintln!("{} at pc {}", exception, trapped_at(&mut cpu));
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Load `program` and let gdb drive it. `addr` is `host:port`, or a path for a Unix socket.
+fn serve_gdb(addr: &str, program: &Path) -> ExitCode {
+    let Some(mut cpu) = load_program(CPU::new(), program) else {
+        return ExitCode::FAILURE;
+    };
+
+    println!("waiting for gdb on {}", addr);
+    let result = if addr.contains(':') && !addr.contains('/') {
+        GdbStub::accept_tcp(addr).and_then(|mut stub| stub.run(&mut cpu))
+    } else {
+        #[cfg(unix)]
+        {
+            GdbStub::accept_unix(addr).and_then(|mut stub| stub.run(&mut cpu))
+        }
+        #[cfg(not(unix))]
+        {
+            Err(std::io::Error::other("Unix sockets aren't supported here"))
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("gdb connection: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    match args.as_slice() {
+        [_, flag, program] if flag == "--debug" => return debug(Path::new(program)),
+        [_, flag, program] if flag == "--trace" => return trace(Path::new(program)),
+        [_, flag, addr, program] if flag == "--gdb" => return serve_gdb(addr, Path::new(program)),
+        _ => {}
+    }
+
+    let pattern = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| "riscv-tests/isa/rv32ui-p-*".to_string());
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for entry in glob(&pattern).expect("Failed to read glob pattern") {
         match entry {
             Ok(path) => {
                 if path.extension().and_then(|ext| ext.to_str()) == Some("dump") {
                     continue;
-                } else {
-                    let file_data = std::fs::read(&path).expect("Could not read file.");
-                    let binary_data = file_data.as_slice();
-
-                    let mut cpu = CPU::new();
-                    cpu.load_elf(binary_data);
-
-                    loop {
-                        cpu.execute_ins();
-                        if cpu.is_exited() {
-                            break;
-                        }
-                    }
+                }
 
-                    println!("CPU finished executing test: {:?}", path);
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                match run_test(&path) {
+                    TestResult::Pass => {
+                        passed += 1;
+                        println!("{:<24} PASS", name);
+                    }
+                    result => {
+                        failed += 1;
+                        let reason = match result {
+                            TestResult::NotLoadable(error) => error.to_string(),
+                            TestResult::Fail(test) => format!("test #{}", test),
+                            TestResult::Stopped => "stopped without result".to_string(),
+                            TestResult::Trapped(exception, pc) => {
+                                format!("{} at pc {}", exception, pc)
+                            }
+                            TestResult::Timeout => format!("no result after {} steps", MAX_STEPS),
+                            TestResult::Pass => unreachable!(),
+                        };
+                        println!("{:<24} FAIL ({})", name, reason);
+                    }
                 }
             }
             Err(e) => println!("Error: {:?}", e),
         }
     }
+
+    println!("\n{} passed, {} failed", passed, failed);
+    if failed > 0 || passed == 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
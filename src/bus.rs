@@ -0,0 +1,286 @@
+//! Physical address space: RAM, ROM and memory mapped peripherals attached at fixed ranges
+
+use std::fmt;
+
+use crate::ram::MemoryAccessSize;
+
+/// Why a bus access failed, the CPU turns these into access fault exceptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// no device covers the whole access
+    Unmapped,
+    /// the device refused the access, e.g. a store to ROM or an unsupported width
+    Denied,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::Unmapped => write!(f, "unmapped address"),
+            BusError::Denied => write!(f, "access denied by device"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Anything that can be mapped into the address space. Offsets are relative to the base address
+/// the device is attached at, and an access never crosses the end of the device.
+pub trait Device {
+    /// size of the address window in bytes, up to the whole 32-bit address space
+    fn size(&self) -> u64;
+
+    /// Read `size` bytes at `offset`, zero extended
+    fn read(&mut self, offset: u32, size: MemoryAccessSize) -> Result<u32, BusError>;
+
+    /// Write the low `size` bytes of `value` at `offset`
+    fn write(&mut self, offset: u32, size: MemoryAccessSize, value: u32) -> Result<(), BusError>;
+
+    /// Copy a program image in, bypassing write protection. Only memories support this.
+    fn load_image(&mut self, _offset: u32, _data: &[u8]) -> Result<(), BusError> {
+        Err(BusError::Denied)
+    }
+
+    /// Called once per executed instruction, for devices that keep time
+    fn tick(&mut self, _cycles: u32) {}
+
+    /// mip bits this device currently drives, e.g. the timer interrupt
+    fn interrupts(&mut self) -> u32 {
+        0
+    }
+
+    /// Level of the interrupt line, for devices wired to an interrupt controller
+    fn irq_line(&mut self) -> bool {
+        false
+    }
+
+    /// Levels of all interrupt sources (bit n is source n), for interrupt controllers
+    fn set_irq_lines(&mut self, _lines: u32) {}
+}
+
+struct Mapping {
+    base: u32,
+    size: u64,
+    /// interrupt controller source the device's line is wired to
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    /// Offset of `[addr, addr + len)` into this device if it lies completely inside it
+    fn offset(&self, addr: u32, len: u32) -> Option<u32> {
+        let offset = addr.checked_sub(self.base)?;
+        (offset as u64 + len as u64 <= self.size).then_some(offset)
+    }
+}
+
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<Mapping>,
+    /// word reserved by the last LR.W, any store overlapping it drops the reservation
+    pub reservation: Option<u32>,
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for mapping in &self.devices {
+            map.entry(
+                &format_args!("0x{:08x}", mapping.base),
+                &format_args!("0x{:x} bytes", mapping.size),
+            );
+        }
+        map.finish()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map `device` at `base`, panics if it overlaps a device that is already attached or runs
+    /// past the end of the address space
+    pub fn attach(&mut self, base: u32, device: Box<dyn Device>) {
+        self.map(base, None, device);
+    }
+
+    /// Map `device` at `base` with its interrupt line wired to source `irq` of the interrupt
+    /// controller, panics like `attach` or if the source doesn't exist
+    pub fn attach_with_irq(&mut self, base: u32, irq: u32, device: Box<dyn Device>) {
+        assert!(
+            (1..32).contains(&irq),
+            "there is no interrupt source {}",
+            irq
+        );
+        self.map(base, Some(irq), device);
+    }
+
+    fn map(&mut self, base: u32, irq: Option<u32>, device: Box<dyn Device>) {
+        let size = device.size();
+        let end = base as u64 + size;
+        assert!(
+            end <= 1 << 32,
+            "device at 0x{:08x} exceeds the address space",
+            base
+        );
+        assert!(
+            self.devices
+                .iter()
+                .all(|m| end <= m.base as u64 || base as u64 >= m.base as u64 + m.size),
+            "device at 0x{:08x} overlaps another device",
+            base
+        );
+        self.devices.push(Mapping {
+            base,
+            size,
+            irq,
+            device,
+        });
+    }
+
+    fn find(&mut self, addr: u32, len: u32) -> Result<(&mut Mapping, u32), BusError> {
+        self.devices
+            .iter_mut()
+            .find_map(|m| m.offset(addr, len).map(|offset| (m, offset)))
+            .ok_or(BusError::Unmapped)
+    }
+
+    /// Whether `[addr, addr + len)` lies inside a single device
+    pub fn is_mapped(&self, addr: u32, len: u32) -> bool {
+        self.devices.iter().any(|m| m.offset(addr, len).is_some())
+    }
+
+    pub fn read(&mut self, addr: u32, size: MemoryAccessSize) -> Result<u32, BusError> {
+        let (mapping, offset) = self.find(addr, size.byte_size())?;
+        mapping.device.read(offset, size)
+    }
+
+    pub fn write(&mut self, addr: u32, size: MemoryAccessSize, value: u32) -> Result<(), BusError> {
+        let len = size.byte_size();
+        let (mapping, offset) = self.find(addr, len)?;
+        mapping.device.write(offset, size, value)?;
+        if let Some(reserved) = self.reservation {
+            let (start, end) = (addr as u64, addr as u64 + len as u64);
+            if start < reserved as u64 + 4 && (reserved as u64) < end {
+                self.reservation = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a program image to `addr`, it has to fit in a single memory device
+    pub fn load_image(&mut self, addr: u32, data: &[u8]) -> Result<(), BusError> {
+        let (mapping, offset) = self.find(addr, data.len() as u32)?;
+        mapping.device.load_image(offset, data)
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for mapping in &mut self.devices {
+            mapping.device.tick(cycles);
+        }
+    }
+
+    /// Route the device interrupt lines to the interrupt controllers, then collect the mip bits
+    /// driven by all devices together
+    pub fn interrupts(&mut self) -> u32 {
+        let mut lines = 0;
+        for mapping in &mut self.devices {
+            if let Some(irq) = mapping.irq {
+                if mapping.device.irq_line() {
+                    lines |= 1 << irq;
+                }
+            }
+        }
+        for mapping in &mut self.devices {
+            mapping.device.set_irq_lines(lines);
+        }
+        self.devices
+            .iter_mut()
+            .fold(0, |pending, mapping| pending | mapping.device.interrupts())
+    }
+
+    /// Read `len` bytes one at a time, for dumping memory
+    pub fn read_bytes(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, BusError> {
+        (0..len)
+            .map(|i| {
+                self.read(addr.wrapping_add(i), MemoryAccessSize::Byte)
+                    .map(|byte| byte as u8)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+
+    /// Counts its reads, and ignores writes
+    struct Counter {
+        reads: u32,
+    }
+
+    impl Device for Counter {
+        fn size(&self) -> u64 {
+            4
+        }
+
+        fn read(&mut self, _offset: u32, _size: MemoryAccessSize) -> Result<u32, BusError> {
+            self.reads += 1;
+            Ok(self.reads)
+        }
+
+        fn write(
+            &mut self,
+            _offset: u32,
+            _size: MemoryAccessSize,
+            _value: u32,
+        ) -> Result<(), BusError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_address_map() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, Box::new(RAM::new(0x100)));
+        bus.attach(0x2000, Box::new(Counter { reads: 0 }));
+
+        bus.write(0x10FC, MemoryAccessSize::Word, 0xDEAD_BEEF)
+            .unwrap();
+        assert_eq!(bus.read(0x10FE, MemoryAccessSize::HalfWord), Ok(0xDEAD));
+        assert_eq!(bus.read(0x2000, MemoryAccessSize::Word), Ok(1));
+        assert_eq!(bus.read(0x2000, MemoryAccessSize::Byte), Ok(2));
+
+        // crossing the end of a device or touching nothing at all
+        assert_eq!(
+            bus.read(0x10FE, MemoryAccessSize::Word),
+            Err(BusError::Unmapped)
+        );
+        assert_eq!(
+            bus.write(0x3000, MemoryAccessSize::Byte, 0),
+            Err(BusError::Unmapped)
+        );
+        assert_eq!(bus.load_image(0x2000, &[0]), Err(BusError::Denied));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_overlapping_devices() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, Box::new(RAM::new(0x100)));
+        bus.attach(0x10FC, Box::new(Counter { reads: 0 }));
+    }
+
+    #[test]
+    fn test_store_drops_reservation() {
+        let mut bus = Bus::new();
+        bus.attach(0, Box::new(RAM::new(0x100)));
+        bus.reservation = Some(8);
+        bus.write(4, MemoryAccessSize::Word, 1).unwrap();
+        assert_eq!(bus.reservation, Some(8));
+        bus.write(11, MemoryAccessSize::Byte, 1).unwrap();
+        assert_eq!(bus.reservation, None);
+    }
+}
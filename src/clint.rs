@@ -0,0 +1,196 @@
+//! Core local interruptor with the SiFive register layout: msip, mtimecmp and mtime for a single
+//! hart. Drives the machine software and timer interrupt lines.
+
+use std::time::Instant;
+
+use crate::bus::{BusError, Device};
+use crate::csr::{MIP_MSIP, MIP_MTIP};
+use crate::ram::MemoryAccessSize;
+
+/// where QEMU `virt` and SiFive boards put the CLINT
+pub const DEFAULT_CLINT_BASE: u32 = 0x0200_0000;
+const CLINT_SIZE: u32 = 0x10000;
+
+// register offsets of hart 0
+pub const MSIP: u32 = 0x0000;
+pub const MTIMECMP: u32 = 0x4000;
+pub const MTIME: u32 = 0xBFF8;
+
+/// What makes mtime advance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timebase {
+    /// one tick every n executed instructions, deterministic
+    Cycles(u32),
+    /// ticks per second of host time
+    Host(u64),
+}
+
+impl Default for Timebase {
+    fn default() -> Self {
+        Timebase::Cycles(1)
+    }
+}
+
+#[derive(Debug)]
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    timebase: Timebase,
+    /// mtime for the cycle timebase, the offset to the elapsed host time otherwise
+    mtime: u64,
+    /// cycles not yet turned into a tick
+    cycles: u32,
+    started: Instant,
+}
+
+impl Clint {
+    pub fn new(timebase: Timebase) -> Self {
+        Self {
+            msip: false,
+            // no timer interrupt until software programs mtimecmp
+            mtimecmp: u64::MAX,
+            timebase,
+            mtime: 0,
+            cycles: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        match self.timebase {
+            Timebase::Cycles(_) => self.mtime,
+            Timebase::Host(frequency) => self.mtime.wrapping_add(self.host_ticks(frequency)),
+        }
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = match self.timebase {
+            Timebase::Cycles(_) => value,
+            Timebase::Host(frequency) => value.wrapping_sub(self.host_ticks(frequency)),
+        };
+    }
+
+    fn host_ticks(&self, frequency: u64) -> u64 {
+        (self.started.elapsed().as_nanos() * frequency as u128 / 1_000_000_000) as u64
+    }
+}
+
+/// Bytes `[offset, offset + size)` of a little endian register
+fn read_part(register: u64, offset: u32, size: MemoryAccessSize) -> u32 {
+    let value = register >> (offset * 8);
+    match size {
+        MemoryAccessSize::Byte => value as u8 as u32,
+        MemoryAccessSize::HalfWord => value as u16 as u32,
+        _ => value as u32,
+    }
+}
+
+/// `register` with bytes `[offset, offset + size)` replaced by the low bytes of `value`
+fn write_part(register: u64, offset: u32, size: MemoryAccessSize, value: u32) -> u64 {
+    let bits = size.size().min(32);
+    let mask = ((1u64 << bits) - 1) << (offset * 8);
+    (register & !mask) | (((value as u64) << (offset * 8)) & mask)
+}
+
+impl Device for Clint {
+    fn size(&self) -> u64 {
+        CLINT_SIZE as u64
+    }
+
+    fn read(&mut self, offset: u32, size: MemoryAccessSize) -> Result<u32, BusError> {
+        let value = match offset {
+            MSIP..=0x0003 => read_part(self.msip as u64, offset - MSIP, size),
+            MTIMECMP..=0x4007 => read_part(self.mtimecmp, offset - MTIMECMP, size),
+            MTIME..=0xBFFF => read_part(self.mtime(), offset - MTIME, size),
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u32, size: MemoryAccessSize, value: u32) -> Result<(), BusError> {
+        match offset {
+            MSIP..=0x0003 => {
+                let msip = write_part(self.msip as u64, offset - MSIP, size, value);
+                self.msip = msip & 1 != 0;
+            }
+            MTIMECMP..=0x4007 => {
+                self.mtimecmp = write_part(self.mtimecmp, offset - MTIMECMP, size, value)
+            }
+            MTIME..=0xBFFF => {
+                let mtime = write_part(self.mtime(), offset - MTIME, size, value);
+                self.set_mtime(mtime);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Timebase::Cycles(divider) = self.timebase {
+            self.cycles += cycles;
+            let divider = divider.max(1);
+            self.mtime = self.mtime.wrapping_add((self.cycles / divider) as u64);
+            self.cycles %= divider;
+        }
+    }
+
+    fn interrupts(&mut self) -> u32 {
+        let software = if self.msip { MIP_MSIP } else { 0 };
+        let timer = if self.mtime() >= self.mtimecmp {
+            MIP_MTIP
+        } else {
+            0
+        };
+        software | timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut clint = Clint::new(Timebase::Cycles(2));
+        assert_eq!(clint.interrupts(), 0);
+
+        clint.write(MTIMECMP, MemoryAccessSize::Word, 3).unwrap();
+        clint
+            .write(MTIMECMP + 4, MemoryAccessSize::Word, 0)
+            .unwrap();
+        for _ in 0..5 {
+            clint.tick(1);
+        }
+        assert_eq!(clint.read(MTIME, MemoryAccessSize::Word), Ok(2));
+        assert_eq!(clint.interrupts(), 0);
+        clint.tick(1);
+        assert_eq!(clint.interrupts(), MIP_MTIP);
+
+        // moving mtimecmp ahead clears the interrupt
+        clint.write(MTIMECMP, MemoryAccessSize::Word, 100).unwrap();
+        assert_eq!(clint.interrupts(), 0);
+
+        clint.write(MTIME + 4, MemoryAccessSize::Word, 1).unwrap();
+        assert_eq!(clint.mtime(), 0x1_0000_0003);
+    }
+
+    #[test]
+    fn test_software_interrupt() {
+        let mut clint = Clint::new(Timebase::default());
+        clint
+            .write(MSIP, MemoryAccessSize::Word, 0xFFFF_FFFF)
+            .unwrap();
+        assert_eq!(clint.read(MSIP, MemoryAccessSize::Word), Ok(1));
+        assert_eq!(clint.interrupts(), MIP_MSIP);
+        clint.write(MSIP, MemoryAccessSize::Word, 0).unwrap();
+        assert_eq!(clint.interrupts(), 0);
+    }
+
+    #[test]
+    fn test_host_timebase() {
+        let mut clint = Clint::new(Timebase::Host(1_000_000_000));
+        clint.write(MTIME, MemoryAccessSize::Word, 0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(clint.mtime() >= 1_000_000);
+    }
+}
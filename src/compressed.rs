@@ -0,0 +1,284 @@
+//! RVC: 16-bit compressed instructions are expanded into the 32-bit instruction they stand
+//! for and then go through the normal decoder
+
+use crate::trap::Exception;
+
+// 32-bit opcodes the compressed instructions expand to
+const OP_LOAD: u32 = 0b0000011;
+const OP_LOAD_FP: u32 = 0b0000111;
+const OP_IMM: u32 = 0b0010011;
+const OP_STORE: u32 = 0b0100011;
+const OP_STORE_FP: u32 = 0b0100111;
+const OP: u32 = 0b0110011;
+const OP_LUI: u32 = 0b0110111;
+const OP_BRANCH: u32 = 0b1100011;
+const OP_JALR: u32 = 0b1100111;
+const OP_JAL: u32 = 0b1101111;
+
+/// Is this the low half of a compressed (16-bit) instruction
+pub fn is_compressed(instruction: u32) -> bool {
+    instruction & 0b11 != 0b11
+}
+
+/// Expand a compressed instruction into the equivalent 32-bit instruction
+pub fn expand(instruction: u16) -> Result<u32, Exception> {
+    let ins = instruction as u32;
+    let illegal = Exception::IllegalInstruction(ins);
+    let funct3 = bits(ins, 15, 13);
+    // full 5-bit register fields and the 3-bit x8-x15 ones
+    let rd = bits(ins, 11, 7);
+    let rs2 = bits(ins, 6, 2);
+    let rd_p = bits(ins, 4, 2) + 8;
+    let rs1_p = bits(ins, 9, 7) + 8;
+
+    let expanded = match (ins & 0b11, funct3) {
+        // the all zero parcel is defined to be illegal
+        _ if ins == 0 => return Err(illegal),
+        (0b00, 0b000) => {
+            // C.ADDI4SPN: addi rd', x2, nzuimm
+            let imm = bits(ins, 12, 11) << 4
+                | bits(ins, 10, 7) << 6
+                | bits(ins, 6, 6) << 2
+                | bits(ins, 5, 5) << 3;
+            if imm == 0 {
+                return Err(illegal);
+            }
+            i_type(imm, 2, 0b000, rd_p, OP_IMM)
+        }
+        (0b00, 0b001) => i_type(cl_d_offset(ins), rs1_p, 0b011, rd_p, OP_LOAD_FP), // C.FLD
+        (0b00, 0b010) => i_type(cl_w_offset(ins), rs1_p, 0b010, rd_p, OP_LOAD),    // C.LW
+        (0b00, 0b011) => i_type(cl_w_offset(ins), rs1_p, 0b010, rd_p, OP_LOAD_FP), // C.FLW
+        (0b00, 0b101) => s_type(cl_d_offset(ins), rd_p, rs1_p, 0b011, OP_STORE_FP), // C.FSD
+        (0b00, 0b110) => s_type(cl_w_offset(ins), rd_p, rs1_p, 0b010, OP_STORE),   // C.SW
+        (0b00, 0b111) => s_type(cl_w_offset(ins), rd_p, rs1_p, 0b010, OP_STORE_FP), // C.FSW
+
+        (0b01, 0b000) => i_type(ci_imm(ins), rd, 0b000, rd, OP_IMM), // C.ADDI / C.NOP
+        (0b01, 0b001) => j_type(cj_offset(ins), 1),                  // C.JAL
+        (0b01, 0b010) => i_type(ci_imm(ins), 0, 0b000, rd, OP_IMM),  // C.LI
+        (0b01, 0b011) if rd == 2 => {
+            // C.ADDI16SP: addi x2, x2, nzimm
+            let imm = sign_extend(
+                bits(ins, 12, 12) << 9
+                    | bits(ins, 6, 6) << 4
+                    | bits(ins, 5, 5) << 6
+                    | bits(ins, 4, 3) << 7
+                    | bits(ins, 2, 2) << 5,
+                10,
+            );
+            if imm == 0 {
+                return Err(illegal);
+            }
+            i_type(imm, 2, 0b000, 2, OP_IMM)
+        }
+        (0b01, 0b011) => {
+            // C.LUI: lui rd, nzimm
+            let imm = ci_imm(ins);
+            if imm == 0 {
+                return Err(illegal);
+            }
+            (imm & 0xFFFFF) << 12 | rd << 7 | OP_LUI
+        }
+        (0b01, 0b100) => {
+            let shamt = bits(ins, 6, 2);
+            match bits(ins, 11, 10) {
+                // RV32C shift amounts with bit 5 set are reserved
+                0b00 | 0b01 if bits(ins, 12, 12) == 1 => return Err(illegal),
+                0b00 => i_type(shamt, rs1_p, 0b101, rs1_p, OP_IMM), // C.SRLI
+                0b01 => i_type(0x400 | shamt, rs1_p, 0b101, rs1_p, OP_IMM), // C.SRAI
+                0b10 => i_type(ci_imm(ins), rs1_p, 0b111, rs1_p, OP_IMM), // C.ANDI
+                _ => {
+                    let (funct7, funct3) = match (bits(ins, 12, 12), bits(ins, 6, 5)) {
+                        (0, 0b00) => (0b0100000, 0b000), // C.SUB
+                        (0, 0b01) => (0b0000000, 0b100), // C.XOR
+                        (0, 0b10) => (0b0000000, 0b110), // C.OR
+                        (0, 0b11) => (0b0000000, 0b111), // C.AND
+                        _ => return Err(illegal),
+                    };
+                    r_type(funct7, rd_p, rs1_p, funct3, rs1_p, OP)
+                }
+            }
+        }
+        (0b01, 0b101) => j_type(cj_offset(ins), 0), // C.J
+        (0b01, 0b110) => b_type(cb_offset(ins), 0, rs1_p, 0b000), // C.BEQZ
+        (0b01, 0b111) => b_type(cb_offset(ins), 0, rs1_p, 0b001), // C.BNEZ
+
+        (0b10, 0b000) => {
+            // C.SLLI
+            if bits(ins, 12, 12) == 1 {
+                return Err(illegal);
+            }
+            i_type(rs2, rd, 0b001, rd, OP_IMM)
+        }
+        (0b10, 0b001) => {
+            // C.FLDSP
+            let imm = bits(ins, 12, 12) << 5 | bits(ins, 6, 5) << 3 | bits(ins, 4, 2) << 6;
+            i_type(imm, 2, 0b011, rd, OP_LOAD_FP)
+        }
+        (0b10, 0b010) | (0b10, 0b011) => {
+            // C.LWSP / C.FLWSP
+            let imm = bits(ins, 12, 12) << 5 | bits(ins, 6, 4) << 2 | bits(ins, 3, 2) << 6;
+            match funct3 {
+                0b010 if rd == 0 => return Err(illegal),
+                0b010 => i_type(imm, 2, 0b010, rd, OP_LOAD),
+                _ => i_type(imm, 2, 0b010, rd, OP_LOAD_FP),
+            }
+        }
+        (0b10, 0b100) => match (bits(ins, 12, 12), rd, rs2) {
+            (0, 0, 0) => return Err(illegal),
+            (0, _, 0) => i_type(0, rd, 0b000, 0, OP_JALR), // C.JR
+            (0, _, _) => r_type(0, rs2, 0, 0b000, rd, OP), // C.MV
+            (_, 0, 0) => 0x00100073,                       // C.EBREAK
+            (_, _, 0) => i_type(0, rd, 0b000, 1, OP_JALR), // C.JALR
+            _ => r_type(0, rs2, rd, 0b000, rd, OP),        // C.ADD
+        },
+        (0b10, 0b101) => {
+            // C.FSDSP
+            let imm = bits(ins, 12, 10) << 3 | bits(ins, 9, 7) << 6;
+            s_type(imm, rs2, 2, 0b011, OP_STORE_FP)
+        }
+        (0b10, 0b110) | (0b10, 0b111) => {
+            // C.SWSP / C.FSWSP
+            let imm = bits(ins, 12, 9) << 2 | bits(ins, 8, 7) << 6;
+            let opcode = if funct3 == 0b110 {
+                OP_STORE
+            } else {
+                OP_STORE_FP
+            };
+            s_type(imm, rs2, 2, 0b010, opcode)
+        }
+        _ => return Err(illegal),
+    };
+    Ok(expanded)
+}
+
+/// Bits `hi..=lo` of `value`, shifted down to bit 0
+fn bits(value: u32, hi: u32, lo: u32) -> u32 {
+    (value >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+/// 6-bit signed immediate of the CI format: imm[5] at bit 12, imm[4:0] at bits 6:2
+fn ci_imm(ins: u32) -> u32 {
+    sign_extend(bits(ins, 12, 12) << 5 | bits(ins, 6, 2), 6)
+}
+
+/// word offset of C.LW / C.SW / C.FLW / C.FSW: uimm[5:3] at bits 12:10, [2] at 6, [6] at 5
+fn cl_w_offset(ins: u32) -> u32 {
+    bits(ins, 12, 10) << 3 | bits(ins, 6, 6) << 2 | bits(ins, 5, 5) << 6
+}
+
+/// double word offset of C.FLD / C.FSD: uimm[5:3] at bits 12:10, [7:6] at 6:5
+fn cl_d_offset(ins: u32) -> u32 {
+    bits(ins, 12, 10) << 3 | bits(ins, 6, 5) << 6
+}
+
+/// jump offset of C.J / C.JAL: imm[11|4|9:8|10|6|7|3:1|5] at bits 12:2
+fn cj_offset(ins: u32) -> u32 {
+    sign_extend(
+        bits(ins, 12, 12) << 11
+            | bits(ins, 11, 11) << 4
+            | bits(ins, 10, 9) << 8
+            | bits(ins, 8, 8) << 10
+            | bits(ins, 7, 7) << 6
+            | bits(ins, 6, 6) << 7
+            | bits(ins, 5, 3) << 1
+            | bits(ins, 2, 2) << 5,
+        12,
+    )
+}
+
+/// branch offset of C.BEQZ / C.BNEZ: imm[8|4:3] at bits 12:10, [7:6|2:1|5] at 6:2
+fn cb_offset(ins: u32) -> u32 {
+    sign_extend(
+        bits(ins, 12, 12) << 8
+            | bits(ins, 11, 10) << 3
+            | bits(ins, 6, 5) << 6
+            | bits(ins, 4, 3) << 1
+            | bits(ins, 2, 2) << 5,
+        9,
+    )
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xFFF) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm & 0xFFF;
+    (imm >> 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1F) << 7 | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    bits(imm, 12, 12) << 31
+        | bits(imm, 10, 5) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | bits(imm, 4, 1) << 8
+        | bits(imm, 11, 11) << 7
+        | OP_BRANCH
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    bits(imm, 20, 20) << 31
+        | bits(imm, 10, 1) << 21
+        | bits(imm, 11, 11) << 20
+        | bits(imm, 19, 12) << 12
+        | rd << 7
+        | OP_JAL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_compressed_instructions() {
+        let cases = [
+            (0x0001, 0x00000013), // c.nop -> addi zero, zero, 0
+            (0x0808, 0x01010513), // c.addi4spn a0, sp, 16 -> addi a0, sp, 16
+            (0x41c8, 0x0045a503), // c.lw a0, 4(a1) -> lw a0, 4(a1)
+            (0xc1c8, 0x00a5a223), // c.sw a0, 4(a1) -> sw a0, 4(a1)
+            (0x557d, 0xfff00513), // c.li a0, -1 -> addi a0, zero, -1
+            (0x1141, 0xff010113), // c.addi sp, -16 -> addi sp, sp, -16
+            (0x7179, 0xfd010113), // c.addi16sp sp, -48 -> addi sp, sp, -48
+            (0x6585, 0x000015b7), // c.lui a1, 1 -> lui a1, 1
+            (0x8505, 0x40155513), // c.srai a0, 1 -> srai a0, a0, 1
+            (0x8d89, 0x40a585b3), // c.sub a1, a0 -> sub a1, a1, a0
+            (0x0506, 0x00151513), // c.slli a0, 1 -> slli a0, a0, 1
+            (0x40b2, 0x00c12083), // c.lwsp ra, 12(sp) -> lw ra, 12(sp)
+            (0xc606, 0x00112623), // c.swsp ra, 12(sp) -> sw ra, 12(sp)
+            (0x8082, 0x00008067), // c.jr ra -> jalr zero, 0(ra)
+            (0x852e, 0x00b00533), // c.mv a0, a1 -> add a0, zero, a1
+            (0x97aa, 0x00a787b3), // c.add a5, a0 -> add a5, a5, a0
+            (0x9002, 0x00100073), // c.ebreak -> ebreak
+            (0xa001, 0x0000006f), // c.j 0 -> jal zero, 0
+            (0x3ffd, 0xfffff0ef), // c.jal -2 -> jal ra, -2
+            (0xdd7d, 0xfe050fe3), // c.beqz a0, -2 -> beq a0, zero, -2
+        ];
+        for (compressed, expanded) in cases {
+            assert_eq!(expand(compressed), Ok(expanded), "0x{:04x}", compressed);
+        }
+    }
+
+    #[test]
+    fn test_reserved_compressed_instructions() {
+        // all zero, c.addi4spn with nzuimm = 0, c.lwsp x0, c.jr x0
+        for compressed in [0x0000, 0x0004, 0x4002, 0x8002] {
+            assert_eq!(
+                expand(compressed),
+                Err(Exception::IllegalInstruction(compressed as u32))
+            );
+        }
+        assert!(is_compressed(0x4501));
+        assert!(!is_compressed(0x00000013));
+    }
+}
@@ -0,0 +1,48 @@
+use crate::clint::{Timebase, DEFAULT_CLINT_BASE};
+use crate::plic::DEFAULT_PLIC_BASE;
+use crate::uart::DEFAULT_UART_BASE;
+
+/// 64 MiB, the buffer is zeroed lazily by the allocator so untouched memory costs nothing
+pub const DEFAULT_RAM_SIZE: usize = 64 * 1024 * 1024;
+/// where RAM starts on most RISC-V boards (and where riscv-tests link)
+pub const DEFAULT_RAM_BASE: u32 = 0x8000_0000;
+
+/// Memory layout of the emulated machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    /// RAM size in bytes
+    pub ram_size: usize,
+    /// guest address of the first RAM byte
+    pub ram_base: u32,
+    /// PC after reset
+    pub reset_vector: u32,
+    /// where to map a UART on the host stdin/stdout, `None` leaves it to the embedder
+    pub uart_base: Option<u32>,
+    /// where to map the CLINT, `None` leaves the machine without timer interrupts
+    pub clint_base: Option<u32>,
+    /// what advances the CLINT mtime
+    pub timebase: Timebase,
+    /// where to map the PLIC, `None` leaves the machine without external interrupts
+    pub plic_base: Option<u32>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            ram_size: DEFAULT_RAM_SIZE,
+            ram_base: DEFAULT_RAM_BASE,
+            reset_vector: DEFAULT_RAM_BASE,
+            uart_base: Some(DEFAULT_UART_BASE),
+            clint_base: Some(DEFAULT_CLINT_BASE),
+            timebase: Timebase::default(),
+            plic_base: Some(DEFAULT_PLIC_BASE),
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Guest address one past the last RAM byte
+    pub fn ram_end(&self) -> u64 {
+        self.ram_base as u64 + self.ram_size as u64
+    }
+}
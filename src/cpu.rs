@@ -1,26 +1,81 @@
-use elf::{endian::AnyEndian, ElfBytes};
-
 use crate::{
-    instruction::{RV5Instruction, RV5Itype, RV5Jtype, RV5Rtype, RV5SBtype, RV5Stype, RVUtype},
-    ram::{RAM, RAM_SIZE},
+    asm::{self, AsmError},
+    bus::Bus,
+    clint::{self, Clint},
+    compressed,
+    config::MachineConfig,
+    csr::{self, CsrFile, Privilege},
+    fpu::{self, Format, RoundingMode},
+    instruction::{
+        RV5Instruction, RV5Itype, RV5Jtype, RV5R4type, RV5Rtype, RV5SBtype, RV5Stype, RVUtype,
+    },
+    linux::LinuxAbi,
+    loader::{ElfError, ElfImage},
+    mmu::{self, Access, Mmu},
+    plic::Plic,
+    ram::{MemoryAccessSize, RAM},
+    symbols::SymbolTable,
+    syscall::SyscallAbi,
+    trace::{Snapshot, Tracer},
+    trap::{Exception, Interrupt, INTERRUPT_BIT},
+    uart::{Uart, DEFAULT_UART_IRQ},
 };
 
 // 32(general purpose) + 1(PC)
 const REGISTER_COUNT: usize = 33;
 const PC_INDEX: usize = 32;
-const INITIAL_PC: usize = 0x80000000;
+const FREGISTER_COUNT: usize = 32;
+/// upper half of a NaN-boxed single precision value
+const NAN_BOX: u64 = 0xFFFF_FFFF_0000_0000;
+
+/// A load or store made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// guest (virtual) address
+    pub addr: u32,
+    pub size: MemoryAccessSize,
+    /// value loaded or stored
+    pub value: u64,
+    pub access: Access,
+}
 
 /// 32-bit RISC-V
 #[derive(Debug)]
 pub struct CPU {
     /// register
     pub reg: [u32; REGISTER_COUNT],
+    /// floating point registers, single precision values are NaN-boxed
+    pub freg: [u64; FREGISTER_COUNT],
     /// clock cycle
     pub clk: u32,
-    /// random access memory
-    pub ram: RAM,
+    /// physical address space, RAM and devices
+    pub bus: Bus,
+    /// memory layout the machine was built with
+    pub config: MachineConfig,
+    /// control and status registers
+    pub csr: CsrFile,
     /// process exit flag
     pub exited: bool,
+    /// exit code passed to the exit syscall
+    pub exit_code: Option<u32>,
+    /// address of the riscv-tests `tohost` word, if the ELF has one
+    pub tohost: Option<u32>,
+    /// symbols of the loaded ELF
+    pub symbols: SymbolTable,
+    /// privilege level the hart runs at, M after reset
+    pub privilege: Privilege,
+    /// Sv32 translation and its TLB
+    pub mmu: Mmu,
+    /// answers ecalls when the guest has no trap handler, only empty while it runs
+    syscall_abi: Option<Box<dyn SyscallAbi>>,
+    /// loads and stores of the last instruction executed
+    memory_accesses: Vec<MemoryAccess>,
+    /// commit log of retired instructions, off unless set
+    tracer: Option<Tracer>,
+    /// raw bits of the instruction being executed
+    ins: u32,
+    /// address of the next instruction, jumps and branches overwrite it
+    next_pc: u32,
 }
 
 impl Default for CPU {
@@ -34,127 +89,744 @@ impl CPU {
         self.exited
     }
 
+    /// Address of the next instruction to execute
+    pub fn pc(&self) -> u32 {
+        self.reg[PC_INDEX]
+    }
+
+    /// Continue execution at `pc`
+    pub fn set_pc(&mut self, pc: u32) {
+        self.reg[PC_INDEX] = pc;
+        self.next_pc = pc;
+    }
+
+    /// Loads and stores made by the last `execute_ins`, at their guest addresses. Syscalls and
+    /// `read_memory`/`write_memory` aren't recorded.
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.memory_accesses
+    }
+
     pub fn new() -> Self {
-        let ram = RAM::new();
+        Self::with_config(MachineConfig::default())
+    }
+
+    /// Build a machine with the given memory layout, panics if RAM doesn't fit in the 32-bit
+    /// address space
+    pub fn with_config(config: MachineConfig) -> Self {
+        assert!(
+            config.ram_end() <= 1 << 32,
+            "RAM at 0x{:08x} with 0x{:x} bytes exceeds the address space",
+            config.ram_base,
+            config.ram_size
+        );
         let mut reg = [0u32; REGISTER_COUNT];
-        reg[PC_INDEX] = INITIAL_PC as u32;
+        reg[PC_INDEX] = config.reset_vector;
+        let mut bus = Bus::new();
+        bus.attach(config.ram_base, Box::new(RAM::new(config.ram_size)));
+        if let Some(uart_base) = config.uart_base {
+            bus.attach_with_irq(uart_base, DEFAULT_UART_IRQ, Box::new(Uart::stdio()));
+        }
+        if let Some(clint_base) = config.clint_base {
+            bus.attach(clint_base, Box::new(Clint::new(config.timebase)));
+        }
+        if let Some(plic_base) = config.plic_base {
+            bus.attach(plic_base, Box::new(Plic::new()));
+        }
         CPU {
             reg,
+            freg: [0; FREGISTER_COUNT],
             clk: 0,
-            ram,
+            bus,
+            config,
+            csr: CsrFile::new(),
             exited: false,
+            exit_code: None,
+            tohost: None,
+            symbols: SymbolTable::new(),
+            privilege: Privilege::Machine,
+            mmu: Mmu::new(),
+            syscall_abi: Some(Box::new(LinuxAbi::new(None))),
+            memory_accesses: Vec::new(),
+            tracer: None,
+            ins: 0,
+            next_pc: config.reset_vector,
         }
     }
 
-    /// Load ELF binary into memory
-    pub fn load_elf(&mut self, binary_data: &[u8]) {
-        let elf = ElfBytes::<AnyEndian>::minimal_parse(binary_data).expect("Failed to parse ELF");
+    /// Replace the syscalls the host answers, Linux without file access by default
+    pub fn set_syscall_abi(&mut self, abi: Box<dyn SyscallAbi>) {
+        self.syscall_abi = Some(abi);
+    }
+
+    /// Log every retired instruction to `tracer`, `None` turns tracing off
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Load every PT_LOAD segment of an ELF executable into RAM and start at its entry point.
+    /// Nothing is written unless all segments fit in RAM.
+    pub fn load_elf(&mut self, binary_data: &[u8]) -> Result<(), ElfError> {
+        let image = ElfImage::parse(binary_data)?;
 
-        if let Ok(Some(shdr)) = elf.section_header_by_name(".text.init") {
-            let p_paddr = shdr.sh_addr;
-            let (data, _) = elf
-                .section_data(&shdr)
-                .expect("Failed to load .text section");
-            let write_addr = p_paddr - INITIAL_PC as u64;
-            assert!(write_addr < RAM_SIZE as u64, "Address out of range");
-            self.ram.write_bytes(write_addr as usize, data);
+        for segment in &image.segments {
+            let size = segment.mem_size.max(segment.data.len() as u32);
+            if !self.bus.is_mapped(segment.addr, size) {
+                return Err(ElfError::SegmentOutOfRange {
+                    addr: segment.addr as u64,
+                    size: size as u64,
+                });
+            }
+        }
+
+        for segment in &image.segments {
+            // .bss: the part of the segment that isn't backed by the file
+            let mut image = segment.data.to_vec();
+            image.resize(segment.mem_size.max(segment.data.len() as u32) as usize, 0);
+            self.bus
+                .load_image(segment.addr, &image)
+                .map_err(|_| ElfError::SegmentOutOfRange {
+                    addr: segment.addr as u64,
+                    size: image.len() as u64,
+                })?;
         }
+
+        self.tohost = image.tohost;
+        self.symbols = image.symbols;
+        self.reg[PC_INDEX] = image.entry;
+        self.next_pc = image.entry;
+
+        let image_end = image
+            .segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .addr
+                    .saturating_add(segment.mem_size.max(segment.data.len() as u32))
+            })
+            .max()
+            .unwrap_or(image.entry);
+        self.with_syscall_abi(|abi, cpu| abi.start(cpu, image_end));
+        Ok(())
     }
 
+    /// Current value of the `tohost` word, riscv-tests write a non zero value there when done.
+    /// The ELF symbol is a physical address, even for tests running with virtual memory.
+    pub fn tohost_value(&mut self) -> Option<u32> {
+        self.tohost
+            .and_then(|addr| self.bus.read(addr, MemoryAccessSize::Word).ok())
+    }
+
+    /// Load a raw binary at the reset vector, panics if it doesn't fit in memory there
     pub fn load_instructions(&mut self, binary_data: &[u8]) {
-        for (i, chunk) in binary_data.chunks(4).enumerate() {
-            println!("Writing instruction at index: {:?}", chunk);
-            self.ram.write_word(
-                i * 4,
-                u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
-            );
-        }
+        self.bus
+            .load_image(self.config.reset_vector, binary_data)
+            .expect("Program doesn't fit in memory at the reset vector");
+    }
+
+    /// Assemble `source` and load it at the reset vector, its labels become the symbols
+    pub fn load_assembly(&mut self, source: &str) -> Result<(), AsmError> {
+        let program = asm::assemble(source, self.config.reset_vector)?;
+        self.load_instructions(&program.image);
+        self.symbols = program.symbols;
+        Ok(())
     }
 
-    /// Note that instruction is a 32-bit value
-    pub fn fetch_ins(&mut self) -> u32 {
+    /// Fetch the instruction at PC, compressed instructions come back in the low 16 bits
+    pub fn fetch_ins(&mut self) -> Result<u32, Exception> {
         let addr = self.reg[PC_INDEX];
-        let ram_addr = addr.wrapping_sub(INITIAL_PC as u32);
-        assert!(ram_addr < RAM_SIZE as u32, "Address out of range");
-        self.ram.read_word(ram_addr as usize)
+        let low = self.fetch_half(addr)?;
+        if compressed::is_compressed(low) {
+            return Ok(low);
+        }
+        // 32-bit instructions only need 2 byte alignment, so fetch them in two halves
+        let high = self.fetch_half(addr.wrapping_add(2))?;
+        Ok(high << 16 | low)
     }
 
-    pub fn execute_ins(&mut self) {
-        let instruction = self.fetch_ins();
+    fn fetch_half(&mut self, addr: u32) -> Result<u32, Exception> {
+        let paddr = self.translate(addr, MemoryAccessSize::HalfWord, Access::Fetch)?;
+        self.bus
+            .read(paddr, MemoryAccessSize::HalfWord)
+            .map_err(|_| Exception::InstructionAccessFault(addr))
+    }
 
-        let decoded_instruction = self.decode_ins(instruction);
-        match decoded_instruction {
-            RV5Instruction::R(rv5_r_type) => self.execute_rtype(rv5_r_type),
-            RV5Instruction::I(rv5_i_type) => self.execute_itype(rv5_i_type),
-            RV5Instruction::S(rv5_s_type) => self.execute_stype(rv5_s_type),
-            RV5Instruction::SB(rv5_sb_type) => self.execute_sbtype(rv5_sb_type),
-            RV5Instruction::U(rv5_u_type) => self.execute_utype(rv5_u_type),
-            RV5Instruction::J(rv5_j_type) => self.execute_jtype(rv5_j_type),
-            RV5Instruction::ECALL => self.handle_ecall(),
-            RV5Instruction::EBREAK => {
-                self.exited = true;
-                println!("Encountered EBREAK ending process.");
+    /// Physical address of the virtual address `addr`, checked against PMP for an access of
+    /// `size` bytes. Loads and stores in M mode use the privilege in MPP while mstatus.MPRV is
+    /// set.
+    fn translate(
+        &mut self,
+        addr: u32,
+        size: MemoryAccessSize,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let privilege = match access {
+            Access::Load | Access::Store
+                if self.privilege == Privilege::Machine && mstatus & csr::MSTATUS_MPRV != 0 =>
+            {
+                Privilege::from_bits((mstatus & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT)
             }
-            RV5Instruction::NOP => {
-                self.exited = true;
-                println!("Encountered NOP or uninitialized memory.");
+            _ => self.privilege,
+        };
+        let paddr = self
+            .mmu
+            .translate(&mut self.bus, &self.csr, privilege, addr, access)?;
+        if !self
+            .csr
+            .pmp
+            .check(paddr, size.byte_size(), access, privilege)
+        {
+            return Err(access.access_fault(addr));
+        }
+        Ok(paddr)
+    }
+
+    /// Execute a single instruction.
+    ///
+    /// Once the guest installed a trap handler (`mtvec` is non zero) exceptions are taken by the
+    /// guest like the privileged spec describes. Otherwise they are returned to the host and
+    /// nothing is committed: the PC still points at the faulting instruction and `clk` is not
+    /// advanced, so the host can inspect the trap, fix things up and resume.
+    ///
+    /// Pending interrupts are taken before the instruction, without executing it.
+    pub fn execute_ins(&mut self) -> Result<(), Exception> {
+        self.memory_accesses.clear();
+        if self.take_interrupt() {
+            return Ok(());
+        }
+        let before = self.tracer.is_some().then(|| Snapshot::take(self));
+        match self.step() {
+            Ok(()) => {
+                if let Some(before) = before {
+                    self.trace(&before);
+                }
+                Ok(())
             }
+            Err(exception) if self.has_trap_handler() => {
+                self.take_trap(exception);
+                self.tick();
+                Ok(())
+            }
+            Err(exception) => Err(exception),
         }
+    }
+
+    /// Hand the instruction that just retired to the tracer, if there is one
+    fn trace(&mut self, before: &Snapshot) {
+        if let Some(mut tracer) = self.tracer.take() {
+            // a trace output that fails doesn't stop the program
+            let _ = tracer.retire(self, before, self.ins);
+            self.tracer = Some(tracer);
+        }
+    }
+
+    /// Advance the clock by one cycle, for the hart and the devices on the bus
+    fn tick(&mut self) {
         self.clk += 1;
-        self.reg[PC_INDEX] = self.reg[PC_INDEX].wrapping_add(4);
+        self.csr.cycle = self.csr.cycle.wrapping_add(1);
+        self.bus.tick(1);
     }
 
-    fn handle_ecall(&mut self) {
-        match self.reg[17] {
-            1 => {
-                // a0 (x10)
-                println!("{}", self.reg[10]);
+    /// Latch the device interrupt lines into mip and enter the handler of the highest priority
+    /// one that is enabled. Interrupts stay pending while there is no guest handler.
+    ///
+    /// Interrupts for a more privileged level are always enabled, for the current level only if
+    /// its xIE bit is set. Delegated interrupts are never taken in M mode.
+    fn take_interrupt(&mut self) -> bool {
+        let lines = self.bus.interrupts();
+        self.csr.set_interrupt_lines(lines);
+        if !self.has_trap_handler() {
+            return false;
+        }
+        let pending = self.csr.enabled_interrupts();
+        let mideleg = self.csr.get(csr::MIDELEG);
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let mut enabled = 0;
+        if self.privilege < Privilege::Machine || mstatus & csr::MSTATUS_MIE != 0 {
+            enabled |= pending & !mideleg;
+        }
+        if self.privilege < Privilege::Supervisor
+            || (self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_SIE != 0)
+        {
+            enabled |= pending & mideleg;
+        }
+        match Interrupt::highest(enabled) {
+            Some(interrupt) => {
+                self.enter_trap(interrupt.code() | INTERRUPT_BIT, 0);
+                true
             }
-            4 => {
-                // a0 (x10)
-                let addr = self.reg[10] as usize;
-                let mut s = String::new();
-                let mut i = addr;
-                loop {
-                    let byte = self.ram.data[i];
-                    if byte == 0 {
-                        break;
-                    }
-                    s.push(byte as char);
-                    i += 1;
-                }
-                print!("{}", s);
+            None => false,
+        }
+    }
+
+    /// Fetch, decode and execute one instruction, committing it only if it didn't raise
+    fn step(&mut self) -> Result<(), Exception> {
+        let instruction = self.fetch_ins()?;
+        self.ins = instruction;
+        let (instruction, len) = if compressed::is_compressed(instruction) {
+            (compressed::expand(instruction as u16)?, 2)
+        } else {
+            (instruction, 4)
+        };
+        self.next_pc = self.reg[PC_INDEX].wrapping_add(len);
+
+        let decoded_instruction = self.decode_ins(instruction)?;
+        match decoded_instruction {
+            RV5Instruction::R(rv5_r_type) => self.execute_rtype(rv5_r_type)?,
+            RV5Instruction::R4(rv5_r4_type) => self.execute_fma(rv5_r4_type)?,
+            RV5Instruction::I(rv5_i_type) => self.execute_itype(rv5_i_type)?,
+            RV5Instruction::S(rv5_s_type) => self.execute_stype(rv5_s_type)?,
+            RV5Instruction::SB(rv5_sb_type) => self.execute_sbtype(rv5_sb_type)?,
+            RV5Instruction::U(rv5_u_type) => self.execute_utype(rv5_u_type)?,
+            RV5Instruction::J(rv5_j_type) => self.execute_jtype(rv5_j_type)?,
+            RV5Instruction::CSR(rv5_i_type) => self.execute_csr(rv5_i_type)?,
+            // guests with their own trap handler get the environment call, others the host shortcut
+            RV5Instruction::ECALL if self.has_trap_handler() => {
+                return Err(Exception::EnvironmentCall(self.privilege))
             }
-            10 => {
-                println!("Program exiting.");
-                self.exited = true;
+            RV5Instruction::ECALL => self.handle_ecall()?,
+            RV5Instruction::EBREAK => return Err(Exception::Breakpoint),
+            RV5Instruction::MRET => self.execute_mret()?,
+            RV5Instruction::SRET => self.execute_sret()?,
+            RV5Instruction::SFENCE(rv5_r_type) => self.execute_sfence_vma(rv5_r_type)?,
+            // mstatus.TW makes WFI illegal outside of M mode
+            RV5Instruction::WFI => {
+                if self.privilege < Privilege::Machine
+                    && self.csr.get(csr::MSTATUS) & csr::MSTATUS_TW != 0
+                {
+                    return Err(self.illegal());
+                }
             }
-            _ => panic!("Unknown syscall number: {}", self.reg[17]),
         }
+        self.tick();
+        self.csr.instret = self.csr.instret.wrapping_add(1);
+        self.reg[PC_INDEX] = self.next_pc;
+        Ok(())
+    }
+
+    /// A guest trap handler is installed once mtvec points somewhere
+    fn has_trap_handler(&self) -> bool {
+        self.csr.get(csr::MTVEC) & !0b11 != 0
+    }
+
+    /// Trap handler address from `tvec` (mtvec or stvec), vectored mode (MODE = 1) only applies
+    /// to interrupts
+    fn trap_vector(&self, tvec: u16, cause: u32, interrupt: bool) -> u32 {
+        let tvec = self.csr.get(tvec);
+        let base = tvec & !0b11;
+        match tvec & 0b11 {
+            1 if interrupt => base.wrapping_add(4 * cause),
+            _ => base,
+        }
+    }
+
+    /// Enter the machine mode trap handler for `exception` raised by the instruction at PC
+    fn take_trap(&mut self, exception: Exception) {
+        let tval = match exception {
+            Exception::Breakpoint => self.reg[PC_INDEX],
+            _ => exception.tval(),
+        };
+        self.enter_trap(exception.code(), tval);
+    }
+
+    /// Save the PC and jump to the trap handler for `cause`. Traps from S and U mode that are
+    /// delegated through medeleg/mideleg go to the supervisor, everything else to M mode.
+    fn enter_trap(&mut self, cause: u32, tval: u32) {
+        let code = cause & !INTERRUPT_BIT;
+        let interrupt = cause & INTERRUPT_BIT != 0;
+        let deleg = match interrupt {
+            true => csr::MIDELEG,
+            false => csr::MEDELEG,
+        };
+        let pc = self.reg[PC_INDEX];
+        let mstatus = self.csr.get(csr::MSTATUS);
+
+        if self.privilege < Privilege::Machine && self.csr.get(deleg) & (1 << code) != 0 {
+            self.csr.write(csr::SEPC, pc);
+            self.csr.write(csr::SCAUSE, cause);
+            self.csr.write(csr::STVAL, tval);
+            // SPIE = SIE, SIE = 0, SPP = previous privilege
+            let spie = match mstatus & csr::MSTATUS_SIE {
+                0 => 0,
+                _ => csr::MSTATUS_SPIE,
+            };
+            let spp = match self.privilege {
+                Privilege::User => 0,
+                _ => csr::MSTATUS_SPP,
+            };
+            self.csr.write(
+                csr::MSTATUS,
+                (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP)) | spie | spp,
+            );
+            self.privilege = Privilege::Supervisor;
+            self.reg[PC_INDEX] = self.trap_vector(csr::STVEC, code, interrupt);
+        } else {
+            self.csr.write(csr::MEPC, pc);
+            self.csr.write(csr::MCAUSE, cause);
+            self.csr.write(csr::MTVAL, tval);
+            // MPIE = MIE, MIE = 0, MPP = previous privilege
+            let mpie = match mstatus & csr::MSTATUS_MIE {
+                0 => 0,
+                _ => csr::MSTATUS_MPIE,
+            };
+            let mpp = (self.privilege as u32) << csr::MSTATUS_MPP_SHIFT;
+            self.csr.write(
+                csr::MSTATUS,
+                (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP)) | mpie | mpp,
+            );
+            self.privilege = Privilege::Machine;
+            self.reg[PC_INDEX] = self.trap_vector(csr::MTVEC, code, interrupt);
+        }
+    }
+
+    /// MRET: return from the machine mode trap handler to mepc, in the privilege level in MPP
+    fn execute_mret(&mut self) -> Result<(), Exception> {
+        if self.privilege < Privilege::Machine {
+            return Err(self.illegal());
+        }
+        // MIE = MPIE, MPIE = 1, MPP = U
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let mie = match mstatus & csr::MSTATUS_MPIE {
+            0 => 0,
+            _ => csr::MSTATUS_MIE,
+        };
+        let privilege =
+            Privilege::from_bits((mstatus & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT);
+        // leaving M mode also stops loads and stores from using the MPP privilege
+        let mprv = match privilege {
+            Privilege::Machine => mstatus & csr::MSTATUS_MPRV,
+            _ => 0,
+        };
+        self.csr.write(
+            csr::MSTATUS,
+            (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP | csr::MSTATUS_MPRV))
+                | mie
+                | csr::MSTATUS_MPIE
+                | mprv,
+        );
+        self.privilege = privilege;
+        self.next_pc = self.csr.get(csr::MEPC);
+        Ok(())
+    }
+
+    /// SRET: return from the supervisor trap handler to sepc, in the privilege level in SPP.
+    /// Illegal in U mode, and in S mode while mstatus.TSR is set.
+    fn execute_sret(&mut self) -> Result<(), Exception> {
+        let mstatus = self.csr.get(csr::MSTATUS);
+        if self.privilege == Privilege::User
+            || (self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TSR != 0)
+        {
+            return Err(self.illegal());
+        }
+        // SIE = SPIE, SPIE = 1, SPP = U
+        let sie = match mstatus & csr::MSTATUS_SPIE {
+            0 => 0,
+            _ => csr::MSTATUS_SIE,
+        };
+        self.privilege = match mstatus & csr::MSTATUS_SPP {
+            0 => Privilege::User,
+            _ => Privilege::Supervisor,
+        };
+        self.csr.write(
+            csr::MSTATUS,
+            (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV))
+                | sie
+                | csr::MSTATUS_SPIE,
+        );
+        self.next_pc = self.csr.get(csr::SEPC);
+        Ok(())
+    }
+
+    /// SFENCE.VMA: flush the TLB after page table updates, illegal in U mode and in S mode while
+    /// mstatus.TVM is set
+    fn execute_sfence_vma(&mut self, instruction: RV5Rtype) -> Result<(), Exception> {
+        if self.privilege == Privilege::User
+            || (self.privilege == Privilege::Supervisor
+                && self.csr.get(csr::MSTATUS) & csr::MSTATUS_TVM != 0)
+        {
+            return Err(self.illegal());
+        }
+        let vaddr = (instruction.rs1 != 0).then(|| self.reg[instruction.rs1 as usize]);
+        let asid = (instruction.rs2 != 0).then(|| self.reg[instruction.rs2 as usize]);
+        self.mmu.flush(vaddr, asid);
+        Ok(())
+    }
+
+    /// Host side syscalls, what they are is up to the syscall ABI
+    fn handle_ecall(&mut self) -> Result<(), Exception> {
+        self.with_syscall_abi(|abi, cpu| abi.syscall(cpu))
+    }
+
+    /// Lend the syscall ABI out together with the CPU it works on
+    fn with_syscall_abi<T>(&mut self, f: impl FnOnce(&mut dyn SyscallAbi, &mut CPU) -> T) -> T {
+        let mut abi = self.syscall_abi.take().expect("syscall ABI called itself");
+        let result = f(abi.as_mut(), self);
+        self.syscall_abi = Some(abi);
+        result
     }
 
     /// Decode the instruction
-    fn decode_ins(&self, instruction: u32) -> RV5Instruction {
+    fn decode_ins(&self, instruction: u32) -> Result<RV5Instruction, Exception> {
         RV5Instruction::new(instruction)
     }
 
-    fn execute_rtype(&mut self, instruction: RV5Rtype) {
+    /// Illegal instruction exception for the instruction being executed
+    fn illegal(&self) -> Exception {
+        Exception::IllegalInstruction(self.ins)
+    }
+
+    /// Write a general purpose register, x0 is hardwired to zero
+    fn write_reg(&mut self, rd: u32, value: u32) {
+        if rd != 0 {
+            self.reg[rd as usize] = value;
+        }
+    }
+
+    /// Read `len` bytes at the guest address `addr`, translated like loads of the hart. Side
+    /// effects are the same too: device registers see a read and the page walk sets PTE A bits.
+    pub fn read_memory(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Exception> {
+        (0..len)
+            .map(|i| {
+                self.read_virtual(addr.wrapping_add(i), MemoryAccessSize::Byte)
+                    .map(|byte| byte as u8)
+            })
+            .collect()
+    }
+
+    /// Write `data` to the guest address `addr`, translated like stores of the hart
+    pub fn write_memory(&mut self, addr: u32, data: &[u8]) -> Result<(), Exception> {
+        for (i, byte) in data.iter().enumerate() {
+            self.write_virtual(
+                addr.wrapping_add(i as u32),
+                MemoryAccessSize::Byte,
+                *byte as u32,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Load for the instruction being executed, recorded in `memory_accesses`
+    fn load(&mut self, addr: u32, size: MemoryAccessSize) -> Result<u32, Exception> {
+        let value = self.read_virtual(addr, size)?;
+        self.log_access(addr, size, value as u64, Access::Load);
+        Ok(value)
+    }
+
+    /// Store for the instruction being executed, recorded in `memory_accesses`
+    fn store(&mut self, addr: u32, size: MemoryAccessSize, value: u32) -> Result<(), Exception> {
+        self.write_virtual(addr, size, value)?;
+        self.log_access(addr, size, value as u64, Access::Store);
+        Ok(())
+    }
+
+    fn log_access(&mut self, addr: u32, size: MemoryAccessSize, value: u64, access: Access) {
+        self.memory_accesses.push(MemoryAccess {
+            addr,
+            size,
+            value,
+            access,
+        });
+    }
+
+    /// Translate an access of `size` bytes at `addr` and check that all of it is on the bus,
+    /// without touching memory, so accesses made of several parts fault before the first part
+    fn probe(
+        &mut self,
+        addr: u32,
+        size: MemoryAccessSize,
+        access: Access,
+    ) -> Result<(), Exception> {
+        if mmu::crosses_page(addr, size) {
+            for i in 0..size.byte_size() {
+                self.probe(addr.wrapping_add(i), MemoryAccessSize::Byte, access)?;
+            }
+            return Ok(());
+        }
+        let paddr = self.translate(addr, size, access)?;
+        match self.bus.is_mapped(paddr, size.byte_size()) {
+            true => Ok(()),
+            false => Err(access.access_fault(addr)),
+        }
+    }
+
+    /// Read `size` bytes from the guest address `addr`, misaligned accesses crossing a page are
+    /// split into bytes
+    fn read_virtual(&mut self, addr: u32, size: MemoryAccessSize) -> Result<u32, Exception> {
+        if mmu::crosses_page(addr, size) {
+            let mut value = 0;
+            for i in 0..size.byte_size() {
+                value |=
+                    self.read_virtual(addr.wrapping_add(i), MemoryAccessSize::Byte)? << (8 * i);
+            }
+            return Ok(value);
+        }
+        let paddr = self.translate(addr, size, Access::Load)?;
+        self.bus
+            .read(paddr, size)
+            .map_err(|_| Exception::LoadAccessFault(addr))
+    }
+
+    /// Write the low `size` bytes of `value` to the guest address `addr`, misaligned accesses
+    /// crossing a page are split into bytes once all of them are known to be writable
+    fn write_virtual(
+        &mut self,
+        addr: u32,
+        size: MemoryAccessSize,
+        value: u32,
+    ) -> Result<(), Exception> {
+        if mmu::crosses_page(addr, size) {
+            self.probe(addr, size, Access::Store)?;
+            for i in 0..size.byte_size() {
+                self.write_virtual(
+                    addr.wrapping_add(i),
+                    MemoryAccessSize::Byte,
+                    value >> (8 * i),
+                )?;
+            }
+            return Ok(());
+        }
+        let paddr = self.translate(addr, size, Access::Store)?;
+        self.bus
+            .write(paddr, size, value)
+            .map_err(|_| Exception::StoreAccessFault(addr))
+    }
+
+    /// Redirect execution to `target`, with RVC instructions only need 2 byte alignment
+    fn jump(&mut self, target: u32) -> Result<(), Exception> {
+        if target & 0b1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+        self.next_pc = target;
+        Ok(())
+    }
+
+    fn execute_rtype(&mut self, instruction: RV5Rtype) -> Result<(), Exception> {
+        match instruction.opcode {
+            0b0101111 => return self.execute_amo(instruction),
+            0b1010011 => return self.execute_fp_op(instruction),
+            _ => {}
+        }
+
         let rs1_val = self.reg[instruction.rs1 as usize];
         let rs2_val = self.reg[instruction.rs2 as usize];
+        let shamt = rs2_val & 0x1F;
 
-        let result = match instruction.funct3 {
-            0b000 => match instruction.funct7 {
-                0b0000000 => rs1_val.wrapping_add(rs2_val), // ADD
-                0b0100000 => rs1_val.wrapping_sub(rs2_val), // SUB
-                _ => panic!("Unknown funct7 for R-type"),
-            },
-            0b111 => rs1_val & rs2_val, // AND
-            0b110 => rs1_val | rs2_val, // OR
-            0b100 => rs1_val ^ rs2_val, // XOR
-            _ => panic!("Unknown funct3 for R-type"),
+        let result = match (instruction.funct3, instruction.funct7) {
+            (0b000, 0b0000000) => rs1_val.wrapping_add(rs2_val), // ADD
+            (0b000, 0b0100000) => rs1_val.wrapping_sub(rs2_val), // SUB
+            (0b001, 0b0000000) => rs1_val << shamt,              // SLL
+            (0b010, 0b0000000) => ((rs1_val as i32) < (rs2_val as i32)) as u32, // SLT
+            (0b011, 0b0000000) => (rs1_val < rs2_val) as u32,    // SLTU
+            (0b100, 0b0000000) => rs1_val ^ rs2_val,             // XOR
+            (0b101, 0b0000000) => rs1_val >> shamt,              // SRL
+            (0b101, 0b0100000) => ((rs1_val as i32) >> shamt) as u32, // SRA
+            (0b110, 0b0000000) => rs1_val | rs2_val,             // OR
+            (0b111, 0b0000000) => rs1_val & rs2_val,             // AND
+            (funct3, 0b0000001) => Self::execute_muldiv(funct3, rs1_val, rs2_val),
+            _ => return Err(self.illegal()),
         };
 
-        self.reg[instruction.rd as usize] = result;
+        self.write_reg(instruction.rd, result);
+        Ok(())
+    }
+
+    /// RV32M multiply / divide, division by zero and overflow don't trap but return the
+    /// results the spec defines
+    fn execute_muldiv(funct3: u32, rs1_val: u32, rs2_val: u32) -> u32 {
+        let (signed1, signed2) = (rs1_val as i32, rs2_val as i32);
+        match funct3 {
+            0b000 => rs1_val.wrapping_mul(rs2_val), // MUL
+            0b001 => ((signed1 as i64 * signed2 as i64) >> 32) as u32, // MULH
+            0b010 => ((signed1 as i64 * rs2_val as i64) >> 32) as u32, // MULHSU
+            0b011 => ((rs1_val as u64 * rs2_val as u64) >> 32) as u32, // MULHU
+            0b100 => match rs2_val {
+                0 => u32::MAX,
+                // i32::MIN / -1 overflows back to i32::MIN
+                _ => signed1.wrapping_div(signed2) as u32,
+            }, // DIV
+            0b101 => rs1_val.checked_div(rs2_val).unwrap_or(u32::MAX), // DIVU
+            0b110 => match rs2_val {
+                0 => rs1_val,
+                _ => signed1.wrapping_rem(signed2) as u32,
+            }, // REM
+            _ => rs1_val.checked_rem(rs2_val).unwrap_or(rs1_val), // REMU
+        }
+    }
+
+    /// RV32A: LR.W / SC.W and the AMO*.W read-modify-write instructions. The aq/rl bits are
+    /// ignored, a single hart executing in order is always sequentially consistent.
+    fn execute_amo(&mut self, instruction: RV5Rtype) -> Result<(), Exception> {
+        let funct5 = instruction.funct7 >> 2;
+        let addr = self.reg[instruction.rs1 as usize];
+        let rs2_val = self.reg[instruction.rs2 as usize];
+        if instruction.funct3 != 0b010 {
+            return Err(self.illegal());
+        }
+
+        match funct5 {
+            0b00010 if instruction.rs2 == 0 => {
+                // LR.W
+                if addr & 0b11 != 0 {
+                    return Err(Exception::LoadAddressMisaligned(addr));
+                }
+                // the reservation is on the physical address, which stores are checked against
+                let paddr = self.translate(addr, MemoryAccessSize::Word, Access::Load)?;
+                let value = self
+                    .bus
+                    .read(paddr, MemoryAccessSize::Word)
+                    .map_err(|_| Exception::LoadAccessFault(addr))?;
+                self.bus.reservation = Some(paddr);
+                self.log_access(addr, MemoryAccessSize::Word, value as u64, Access::Load);
+                self.write_reg(instruction.rd, value);
+                return Ok(());
+            }
+            0b00011 => {
+                // SC.W: rd = 0 on success, 1 if the reservation was lost
+                if addr & 0b11 != 0 {
+                    return Err(Exception::StoreAddressMisaligned(addr));
+                }
+                let paddr = self.translate(addr, MemoryAccessSize::Word, Access::Store)?;
+                let success = self.bus.reservation.take() == Some(paddr);
+                if success {
+                    self.bus
+                        .write(paddr, MemoryAccessSize::Word, rs2_val)
+                        .map_err(|_| Exception::StoreAccessFault(addr))?;
+                    self.log_access(addr, MemoryAccessSize::Word, rs2_val as u64, Access::Store);
+                }
+                self.write_reg(instruction.rd, !success as u32);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if addr & 0b11 != 0 {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        // AMOs need write permission and report faults of their read as store/AMO faults
+        let paddr = self.translate(addr, MemoryAccessSize::Word, Access::Store)?;
+        let old = self
+            .bus
+            .read(paddr, MemoryAccessSize::Word)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        let new = match funct5 {
+            0b00001 => rs2_val,                                 // AMOSWAP.W
+            0b00000 => old.wrapping_add(rs2_val),               // AMOADD.W
+            0b00100 => old ^ rs2_val,                           // AMOXOR.W
+            0b01100 => old & rs2_val,                           // AMOAND.W
+            0b01000 => old | rs2_val,                           // AMOOR.W
+            0b10000 => (old as i32).min(rs2_val as i32) as u32, // AMOMIN.W
+            0b10100 => (old as i32).max(rs2_val as i32) as u32, // AMOMAX.W
+            0b11000 => old.min(rs2_val),                        // AMOMINU.W
+            0b11100 => old.max(rs2_val),                        // AMOMAXU.W
+            _ => return Err(self.illegal()),
+        };
+        self.bus
+            .write(paddr, MemoryAccessSize::Word, new)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        self.log_access(addr, MemoryAccessSize::Word, old as u64, Access::Load);
+        self.log_access(addr, MemoryAccessSize::Word, new as u64, Access::Store);
+        self.write_reg(instruction.rd, old);
+        Ok(())
     }
 
     fn sign_extend(value: u32, bits: u8) -> i32 {
@@ -162,30 +834,298 @@ impl CPU {
         ((value << shift) as i32) >> shift
     }
 
-    fn execute_itype(&mut self, instruction: RV5Itype) {
+    fn execute_itype(&mut self, instruction: RV5Itype) -> Result<(), Exception> {
+        match instruction.opcode {
+            0b0010011 => self.execute_op_imm(instruction),
+            0b0000011 => self.execute_load(instruction),
+            0b0000111 => self.execute_fp_load(instruction),
+            0b1100111 if instruction.funct3 == 0b000 => {
+                // JALR: target is rs1 + imm with the lowest bit cleared
+                let rs1_val = self.reg[instruction.rs1 as usize];
+                let offset = Self::sign_extend(instruction.imm, 12);
+                let target = rs1_val.wrapping_add(offset as u32) & !1;
+                let return_addr = self.next_pc;
+                self.jump(target)?;
+                self.write_reg(instruction.rd, return_addr);
+                Ok(())
+            }
+            // FENCE / FENCE.I: single hart without caches, memory is always coherent
+            0b0001111 if instruction.funct3 <= 0b001 => Ok(()),
+            _ => Err(self.illegal()),
+        }
+    }
+
+    fn execute_op_imm(&mut self, instruction: RV5Itype) -> Result<(), Exception> {
         let rs1_val = self.reg[instruction.rs1 as usize];
         let imm_val = Self::sign_extend(instruction.imm, 12);
+        let shamt = instruction.imm & 0x1F;
+        let funct7 = instruction.imm >> 5;
 
         let result = match instruction.funct3 {
             0b000 => rs1_val.wrapping_add(imm_val as u32), // ADDI (add immediate)
-            0b111 => rs1_val & imm_val as u32,             // ANDI
+            0b010 => ((rs1_val as i32) < imm_val) as u32,  // SLTI
+            0b011 => (rs1_val < imm_val as u32) as u32,    // SLTIU
+            0b100 => rs1_val ^ imm_val as u32,             // XORI
             0b110 => rs1_val | imm_val as u32,             // ORI
-            _ => panic!("Unknown funct3 for I-type"),
+            0b111 => rs1_val & imm_val as u32,             // ANDI
+            0b001 if funct7 == 0b0000000 => rs1_val << shamt, // SLLI
+            0b101 if funct7 == 0b0000000 => rs1_val >> shamt, // SRLI
+            0b101 if funct7 == 0b0100000 => ((rs1_val as i32) >> shamt) as u32, // SRAI
+            _ => return Err(self.illegal()),
         };
 
-        self.reg[instruction.rd as usize] = result;
+        self.write_reg(instruction.rd, result);
+        Ok(())
     }
 
-    fn execute_stype(&mut self, _instruction: RV5Stype) {
-        todo!()
+    fn execute_load(&mut self, instruction: RV5Itype) -> Result<(), Exception> {
+        let offset = Self::sign_extend(instruction.imm, 12);
+        let addr = self.reg[instruction.rs1 as usize].wrapping_add(offset as u32);
+
+        let result = match instruction.funct3 {
+            0b000 => Self::sign_extend(self.load(addr, MemoryAccessSize::Byte)?, 8) as u32, // LB
+            0b001 => Self::sign_extend(self.load(addr, MemoryAccessSize::HalfWord)?, 16) as u32, // LH
+            0b010 => self.load(addr, MemoryAccessSize::Word)?, // LW
+            0b100 => self.load(addr, MemoryAccessSize::Byte)?, // LBU
+            0b101 => self.load(addr, MemoryAccessSize::HalfWord)?, // LHU
+            _ => return Err(self.illegal()),
+        };
+
+        self.write_reg(instruction.rd, result);
+        Ok(())
     }
 
-    fn execute_sbtype(&mut self, _instruction: RV5SBtype) {
-        todo!()
+    fn execute_stype(&mut self, instruction: RV5Stype) -> Result<(), Exception> {
+        if instruction.opcode == 0b0100111 {
+            return self.execute_fp_store(instruction);
+        }
+        let offset = Self::sign_extend(instruction.imm, 12);
+        let addr = self.reg[instruction.rs1 as usize].wrapping_add(offset as u32);
+        let value = self.reg[instruction.rs2 as usize];
+
+        let size = match instruction.funct3 {
+            0b000 => MemoryAccessSize::Byte,     // SB
+            0b001 => MemoryAccessSize::HalfWord, // SH
+            0b010 => MemoryAccessSize::Word,     // SW
+            _ => return Err(self.illegal()),
+        };
+
+        self.store(addr, size, value)
+    }
+
+    /// Rounding mode of an F/D instruction, rm = 0b111 uses frm and reserved modes are illegal
+    fn rounding_mode(&self, rm: u32) -> Result<RoundingMode, Exception> {
+        let rm = if rm == 0b111 { self.csr.frm() } else { rm };
+        RoundingMode::from_bits(rm).ok_or(self.illegal())
     }
 
-    fn execute_utype(&mut self, instruction: RVUtype) {
-        let rd = instruction.rd as usize;
+    /// The fmt field of OP-FP and the fused multiply-add instructions
+    fn fp_format(&self, fmt: u32) -> Result<Format, Exception> {
+        match fmt {
+            0b00 => Ok(fpu::SINGLE),
+            0b01 => Ok(fpu::DOUBLE),
+            _ => Err(self.illegal()),
+        }
+    }
+
+    /// Read a floating point register, single values that aren't NaN-boxed read as the
+    /// canonical NaN
+    fn read_freg(&self, reg: u32, fmt: Format) -> u64 {
+        let value = self.freg[reg as usize];
+        if fmt == fpu::DOUBLE {
+            value
+        } else if value & NAN_BOX == NAN_BOX {
+            value & !NAN_BOX
+        } else {
+            fpu::SINGLE.canonical_nan()
+        }
+    }
+
+    fn write_freg(&mut self, reg: u32, fmt: Format, value: u64) {
+        self.freg[reg as usize] = if fmt == fpu::DOUBLE {
+            value
+        } else {
+            NAN_BOX | value
+        };
+        self.csr.set_fp_dirty();
+    }
+
+    /// FLW / FLD
+    fn execute_fp_load(&mut self, instruction: RV5Itype) -> Result<(), Exception> {
+        if !self.csr.fp_enabled() {
+            return Err(self.illegal());
+        }
+        let offset = Self::sign_extend(instruction.imm, 12);
+        let addr = self.reg[instruction.rs1 as usize].wrapping_add(offset as u32);
+
+        match instruction.funct3 {
+            0b010 => {
+                let value = self.load(addr, MemoryAccessSize::Word)?;
+                self.write_freg(instruction.rd, fpu::SINGLE, value as u64);
+            }
+            0b011 => {
+                // both words have to be readable before either is read, the bus moves at most
+                // a word but the access is logged as one doubleword like spike does
+                self.probe(addr, MemoryAccessSize::Word, Access::Load)?;
+                self.probe(addr.wrapping_add(4), MemoryAccessSize::Word, Access::Load)?;
+                let low = self.read_virtual(addr, MemoryAccessSize::Word)?;
+                let high = self.read_virtual(addr.wrapping_add(4), MemoryAccessSize::Word)?;
+                let value = (high as u64) << 32 | low as u64;
+                self.log_access(addr, MemoryAccessSize::DoubleWord, value, Access::Load);
+                self.write_freg(instruction.rd, fpu::DOUBLE, value);
+            }
+            _ => return Err(self.illegal()),
+        }
+        Ok(())
+    }
+
+    /// FSW / FSD, the register bits are stored as they are without checking the NaN-boxing
+    fn execute_fp_store(&mut self, instruction: RV5Stype) -> Result<(), Exception> {
+        if !self.csr.fp_enabled() {
+            return Err(self.illegal());
+        }
+        let offset = Self::sign_extend(instruction.imm, 12);
+        let addr = self.reg[instruction.rs1 as usize].wrapping_add(offset as u32);
+        let value = self.freg[instruction.rs2 as usize];
+
+        match instruction.funct3 {
+            0b010 => self.store(addr, MemoryAccessSize::Word, value as u32),
+            0b011 => {
+                // a fault on the high word mustn't leave the low one written
+                self.probe(addr, MemoryAccessSize::Word, Access::Store)?;
+                self.probe(addr.wrapping_add(4), MemoryAccessSize::Word, Access::Store)?;
+                self.write_virtual(addr, MemoryAccessSize::Word, value as u32)?;
+                self.write_virtual(
+                    addr.wrapping_add(4),
+                    MemoryAccessSize::Word,
+                    (value >> 32) as u32,
+                )?;
+                self.log_access(addr, MemoryAccessSize::DoubleWord, value, Access::Store);
+                Ok(())
+            }
+            _ => Err(self.illegal()),
+        }
+    }
+
+    /// OP-FP: arithmetic, sign injection, min/max, compares, conversions, classify and moves
+    fn execute_fp_op(&mut self, instruction: RV5Rtype) -> Result<(), Exception> {
+        if !self.csr.fp_enabled() {
+            return Err(self.illegal());
+        }
+        let fmt = self.fp_format(instruction.funct7 & 0b11)?;
+        let funct5 = instruction.funct7 >> 2;
+        let funct3 = instruction.funct3;
+        let a = self.read_freg(instruction.rs1, fmt);
+        let b = self.read_freg(instruction.rs2, fmt);
+
+        // instructions with an integer register result
+        let compare = |(result, flags): (bool, u32)| Some((result as u32, flags));
+        let int_result = match (funct5, funct3, instruction.rs2) {
+            (0b10100, 0b010, _) => compare(fpu::eq(fmt, a, b)), // FEQ
+            (0b10100, 0b001, _) => compare(fpu::lt(fmt, a, b)), // FLT
+            (0b10100, 0b000, _) => compare(fpu::le(fmt, a, b)), // FLE
+            (0b11000, rm, 0b00000 | 0b00001) => {
+                // FCVT.W / FCVT.WU
+                let signed = instruction.rs2 == 0;
+                Some(fpu::to_int(fmt, a, signed, self.rounding_mode(rm)?))
+            }
+            (0b11100, 0b000, 0) if fmt == fpu::SINGLE => {
+                // FMV.X.W moves the raw bits, NaN-boxed or not
+                Some((self.freg[instruction.rs1 as usize] as u32, 0))
+            }
+            (0b11100, 0b001, 0) => Some((fpu::classify(fmt, a), 0)), // FCLASS
+            _ => None,
+        };
+        if let Some((result, flags)) = int_result {
+            self.csr.raise_fflags(flags);
+            self.write_reg(instruction.rd, result);
+            return Ok(());
+        }
+
+        let rs1_val = self.reg[instruction.rs1 as usize];
+        let (result, flags) = match (funct5, instruction.rs2) {
+            (0b00000, _) => fpu::add(fmt, a, b, self.rounding_mode(funct3)?), // FADD
+            (0b00001, _) => fpu::sub(fmt, a, b, self.rounding_mode(funct3)?), // FSUB
+            (0b00010, _) => fpu::mul(fmt, a, b, self.rounding_mode(funct3)?), // FMUL
+            (0b00011, _) => fpu::div(fmt, a, b, self.rounding_mode(funct3)?), // FDIV
+            (0b01011, 0) => fpu::sqrt(fmt, a, self.rounding_mode(funct3)?),   // FSQRT
+            // FSGNJ / FSGNJN / FSGNJX
+            (0b00100, _) if funct3 <= 0b010 => (fpu::sign_inject(fmt, a, b, funct3), 0),
+            // FMIN / FMAX
+            (0b00101, _) if funct3 <= 0b001 => fpu::min_max(fmt, a, b, funct3 == 0b001),
+            (0b01000, 1) if fmt == fpu::SINGLE => {
+                // FCVT.S.D
+                let source = self.read_freg(instruction.rs1, fpu::DOUBLE);
+                let rm = self.rounding_mode(funct3)?;
+                fpu::convert(fpu::DOUBLE, fpu::SINGLE, source, rm)
+            }
+            (0b01000, 0) if fmt == fpu::DOUBLE => {
+                // FCVT.D.S
+                let source = self.read_freg(instruction.rs1, fpu::SINGLE);
+                let rm = self.rounding_mode(funct3)?;
+                fpu::convert(fpu::SINGLE, fpu::DOUBLE, source, rm)
+            }
+            (0b11010, 0b00000 | 0b00001) => {
+                // FCVT.S.W / FCVT.S.WU / FCVT.D.W / FCVT.D.WU
+                let signed = instruction.rs2 == 0;
+                fpu::from_int(fmt, rs1_val, signed, self.rounding_mode(funct3)?)
+            }
+            // FMV.W.X
+            (0b11110, 0) if fmt == fpu::SINGLE && funct3 == 0 => (rs1_val as u64, 0),
+            _ => return Err(self.illegal()),
+        };
+
+        self.csr.raise_fflags(flags);
+        self.write_freg(instruction.rd, fmt, result);
+        Ok(())
+    }
+
+    /// FMADD / FMSUB / FNMSUB / FNMADD, a single rounding of rs1 * rs2 + rs3
+    fn execute_fma(&mut self, instruction: RV5R4type) -> Result<(), Exception> {
+        if !self.csr.fp_enabled() {
+            return Err(self.illegal());
+        }
+        let fmt = self.fp_format(instruction.funct2)?;
+        let rm = self.rounding_mode(instruction.funct3)?;
+        let (negate_product, negate_addend) = match instruction.opcode {
+            0b1000011 => (false, false), // FMADD
+            0b1000111 => (false, true),  // FMSUB
+            0b1001011 => (true, false),  // FNMSUB
+            _ => (true, true),           // FNMADD
+        };
+        let a = self.read_freg(instruction.rs1, fmt);
+        let b = self.read_freg(instruction.rs2, fmt);
+        let c = self.read_freg(instruction.rs3, fmt);
+
+        let (result, flags) = fpu::fma(fmt, a, b, c, negate_product, negate_addend, rm);
+        self.csr.raise_fflags(flags);
+        self.write_freg(instruction.rd, fmt, result);
+        Ok(())
+    }
+
+    fn execute_sbtype(&mut self, instruction: RV5SBtype) -> Result<(), Exception> {
+        let rs1_val = self.reg[instruction.rs1 as usize];
+        let rs2_val = self.reg[instruction.rs2 as usize];
+
+        let taken = match instruction.funct3 {
+            0b000 => rs1_val == rs2_val,                   // BEQ
+            0b001 => rs1_val != rs2_val,                   // BNE
+            0b100 => (rs1_val as i32) < (rs2_val as i32),  // BLT
+            0b101 => (rs1_val as i32) >= (rs2_val as i32), // BGE
+            0b110 => rs1_val < rs2_val,                    // BLTU
+            0b111 => rs1_val >= rs2_val,                   // BGEU
+            _ => return Err(self.illegal()),
+        };
+
+        if taken {
+            let offset = Self::sign_extend(instruction.imm, 13);
+            self.jump(self.reg[PC_INDEX].wrapping_add(offset as u32))?;
+        }
+        Ok(())
+    }
+
+    fn execute_utype(&mut self, instruction: RVUtype) -> Result<(), Exception> {
+        let rd = instruction.rd;
         let opcode = instruction.opcode;
 
         // sign extend
@@ -194,29 +1134,73 @@ impl CPU {
         match opcode {
             0x37 => {
                 // LUI: x[rd] = imm (which is already imm20 << 12 sign-extended)
-                self.reg[rd] = imm_shifted as u32;
+                self.write_reg(rd, imm_shifted as u32);
             }
             0x17 => {
                 // AUIPC: x[rd] = PC + imm_shifted
                 let current_pc = self.reg[PC_INDEX];
-                self.reg[rd] = ((current_pc as i32).wrapping_add(imm_shifted)) as u32;
+                self.write_reg(rd, (current_pc as i32).wrapping_add(imm_shifted) as u32);
             }
-            _ => panic!("Unknown U-type opcode"),
+            _ => return Err(self.illegal()),
         }
+        Ok(())
     }
 
-    fn execute_jtype(&mut self, instruction: RV5Jtype) {
+    fn execute_jtype(&mut self, instruction: RV5Jtype) -> Result<(), Exception> {
         let current_pc = self.reg[PC_INDEX];
-        let offset = Self::sign_extend(instruction.imm, 21) as i32;
+        let offset = Self::sign_extend(instruction.imm, 21);
 
-        // The return address is PC + 4
-        let return_addr = current_pc.wrapping_add(4);
-        if instruction.rd != 0 {
-            self.reg[instruction.rd as usize] = return_addr;
-        }
+        // The return address is the next instruction, PC + 4 (or PC + 2 for C.JAL)
+        let return_addr = self.next_pc;
 
         // Update the PC: PC = PC + offset
-        self.reg[PC_INDEX] = (current_pc as i32).wrapping_add(offset) as u32;
+        self.jump((current_pc as i32).wrapping_add(offset) as u32)?;
+        self.write_reg(instruction.rd, return_addr);
+        Ok(())
+    }
+
+    /// mtime of the CLINT, machines without one count time in cycles
+    fn mtime(&mut self) -> u64 {
+        let Some(base) = self.config.clint_base else {
+            return self.csr.cycle;
+        };
+        let addr = base + clint::MTIME;
+        let low = self.bus.read(addr, MemoryAccessSize::Word).unwrap_or(0);
+        let high = self.bus.read(addr + 4, MemoryAccessSize::Word).unwrap_or(0);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn execute_csr(&mut self, instruction: RV5Itype) -> Result<(), Exception> {
+        let addr = instruction.imm as u16;
+        // CSRR*I use the rs1 field as a 5-bit zero extended immediate
+        let src = match instruction.funct3 & 0b100 {
+            0 => self.reg[instruction.rs1 as usize],
+            _ => instruction.rs1,
+        };
+        // CSRRS/CSRRC with x0 (or uimm 0) only read the CSR
+        let writes = instruction.funct3 & 0b11 == 0b01 || instruction.rs1 != 0;
+
+        if !self.csr.accessible(addr, self.privilege) {
+            return Err(self.illegal());
+        }
+        if addr == csr::TIME || addr == csr::TIMEH {
+            self.csr.time = self.mtime();
+        }
+        let old = self.csr.read(addr).ok_or(self.illegal())?;
+        if writes {
+            if CsrFile::is_read_only(addr) {
+                return Err(self.illegal());
+            }
+            let new = match instruction.funct3 & 0b11 {
+                0b01 => src,       // CSRRW
+                0b10 => old | src, // CSRRS
+                _ => old & !src,   // CSRRC
+            };
+            self.csr.write(addr, new);
+        }
+
+        self.write_reg(instruction.rd, old);
+        Ok(())
     }
 }
 
@@ -236,57 +1220,889 @@ mod tests {
 
     #[test]
     fn test_cpu_fetch_and_execute_instruction() {
-        let binary_data = load_binary("examples/simple/program.bin");
-        println!("Binary data size: {:?}", binary_data.len());
         let mut cpu = CPU::new();
-        cpu.load_instructions(&binary_data);
+        cpu.load_assembly(include_str!("../examples/simple/program.s"))
+            .unwrap();
         //  li
-        cpu.execute_ins();
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
+        cpu.execute_ins().unwrap();
 
         // Execute ADD: x5 = x6 + x7
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], 30); // x5 = 30
 
         // Execute SUB: x5 = x6 - x7
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], -10i32 as u32); // x5 = -10 (unsigned wrap)
 
         // Execute AND: x5 = x6 & x7
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], 10 & 20);
 
         // Execute OR: x5 = x6 | x7
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], 10 | 20);
 
         // Execute XOR: x5 = x6 ^ x7
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], 10 ^ 20);
 
         // Execute ADDI: x5 = x6 + (-5)
-        cpu.execute_ins();
+        cpu.execute_ins().unwrap();
         assert_eq!(cpu.reg[5], 10 - 5); // x5 = 5
     }
 
-    // #[test]
-    // fn test_ecall_handling() {
-    //     let binary_data = load_binary("examples/hello_world/program.bin");
-    //     println!("Binary data size: {:?}", binary_data.len());
-    //     let mut cpu = CPU::new();
-    //     cpu.load_instructions(&binary_data);
-
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-    //     cpu.execute_ins();
-
-    //     assert_eq!(cpu.clk, 10);
-    // }
+    fn load_program(program: &[u32]) -> CPU {
+        let binary_data: Vec<u8> = program.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+        let mut cpu = CPU::new();
+        cpu.load_instructions(&binary_data);
+        cpu
+    }
+
+    #[test]
+    fn test_shift_and_compare_instructions() {
+        let mut cpu = load_program(&[
+            0xff800313, // li t1, -8
+            0x00300393, // li t2, 3
+            0x007312b3, // sll t0, t1, t2
+            0x00735533, // srl a0, t1, t2
+            0x407355b3, // sra a1, t1, t2
+            0x00732633, // slt a2, t1, t2
+            0x007336b3, // sltu a3, t1, t2
+            0x40135713, // srai a4, t1, 1
+            0x00439793, // slli a5, t2, 4
+            0xfff3b813, // sltiu a6, t2, -1
+            0xfff3c893, // xori a7, t2, -1
+        ]);
+        for _ in 0..11 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[5], -64i32 as u32);
+        assert_eq!(cpu.reg[10], 0xFFFF_FFF8 >> 3);
+        assert_eq!(cpu.reg[11], -1i32 as u32);
+        assert_eq!(cpu.reg[12], 1);
+        assert_eq!(cpu.reg[13], 0);
+        assert_eq!(cpu.reg[14], -4i32 as u32);
+        assert_eq!(cpu.reg[15], 48);
+        assert_eq!(cpu.reg[16], 1);
+        assert_eq!(cpu.reg[17], !3);
+    }
+
+    #[test]
+    fn test_load_store_instructions() {
+        let mut cpu = load_program(&[
+            0x800012b7, // lui t0, 0x80001
+            0xffe00313, // li t1, -2
+            0x0062a023, // sw t1, 0(t0)
+            0x007280a3, // sb t2, 1(t0)
+            0x00028503, // lb a0, 0(t0)
+            0x0002c583, // lbu a1, 0(t0)
+            0x00029603, // lh a2, 0(t0)
+            0x0022d683, // lhu a3, 2(t0)
+            0x0002a703, // lw a4, 0(t0)
+            0x00029123, // sh zero, 2(t0)
+            0x0002a783, // lw a5, 0(t0)
+        ]);
+        for _ in 0..11 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[10], -2i32 as u32);
+        assert_eq!(cpu.reg[11], 0xFE);
+        assert_eq!(cpu.reg[12], 0xFE);
+        assert_eq!(cpu.reg[13], 0xFFFF);
+        assert_eq!(cpu.reg[14], 0xFFFF_00FE);
+        assert_eq!(cpu.reg[15], 0xFE);
+    }
+
+    #[test]
+    fn test_branch_and_jump_instructions() {
+        let mut cpu = load_program(&[
+            0x00000293, // li t0, 0
+            0x00500313, // li t1, 5
+            0x00128293, // addi t0, t0, 1
+            0xfe62cee3, // blt t0, t1, -4
+            0x008000ef, // jal ra, 8
+            0x00100513, // li a0, 1
+            0x00000397, // auipc t2, 0
+            0x00c385e7, // jalr a1, 12(t2)
+            0x00100613, // li a2, 1
+            0x00130013, // addi zero, t1, 1
+        ]);
+        for _ in 0..16 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[5], 5);
+        assert_eq!(cpu.reg[1], 0x8000_0014);
+        assert_eq!(cpu.reg[10], 0);
+        assert_eq!(cpu.reg[11], 0x8000_0020);
+        assert_eq!(cpu.reg[12], 0);
+        assert_eq!(cpu.reg[0], 0);
+        assert_eq!(cpu.reg[PC_INDEX], 0x8000_0028);
+    }
+
+    #[test]
+    fn test_exit_syscall_records_exit_code() {
+        let mut cpu = load_program(&[
+            0x00700513, // li a0, 7
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]);
+        while !cpu.is_exited() {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.exit_code, Some(7));
+        assert_eq!(cpu.clk, 3);
+    }
+
+    #[test]
+    fn test_exceptions_are_returned_to_host() {
+        let mut cpu = load_program(&[
+            0x00100293, // li t0, 1
+            0x00002023, // sw zero, 0(zero)
+            0x00002503, // lw a0, 0(zero)
+            0xffffffff, // illegal
+            0x00100073, // ebreak
+        ]);
+        cpu.execute_ins().unwrap();
+
+        // nothing is committed for a faulting instruction, the host skips it and resumes
+        let expected = [
+            Exception::StoreAccessFault(0),
+            Exception::LoadAccessFault(0),
+            Exception::IllegalInstruction(0xffffffff),
+            Exception::Breakpoint,
+        ];
+        for (i, exception) in expected.into_iter().enumerate() {
+            let pc = cpu.config.reset_vector + 4 * (i as u32 + 1);
+            assert_eq!(cpu.execute_ins(), Err(exception));
+            assert_eq!(cpu.pc(), pc);
+            cpu.reg[PC_INDEX] = pc + 4;
+        }
+        assert_eq!(cpu.reg[1], 0);
+        assert_eq!(cpu.clk, 1);
+    }
+
+    #[test]
+    fn test_fetch_out_of_range() {
+        let mut cpu = CPU::new();
+        cpu.reg[PC_INDEX] = 0x1000;
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::InstructionAccessFault(0x1000))
+        );
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let mut cpu = load_program(&[
+            0x05500513, // li a0, 0x55
+            0x340515f3, // csrrw a1, mscratch, a0
+            0x34016673, // csrrsi a2, mscratch, 2
+            0x340536f3, // csrrc a3, mscratch, a0
+            0xf1402773, // csrr a4, mhartid
+            0xc02027f3, // csrr a5, instret
+            0xf1451073, // csrw mhartid, a0
+            0x7ff02073, // csrr zero, 0x7ff
+        ]);
+        for _ in 0..6 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[11], 0);
+        assert_eq!(cpu.reg[12], 0x55);
+        assert_eq!(cpu.reg[13], 0x57);
+        assert_eq!(cpu.csr.read(csr::MSCRATCH), Some(0x02));
+        assert_eq!(cpu.reg[14], 0);
+        assert_eq!(cpu.reg[15], 5);
+
+        // read only and unimplemented CSRs are illegal
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::IllegalInstruction(0xf1451073))
+        );
+        cpu.reg[PC_INDEX] += 4;
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::IllegalInstruction(0x7ff02073))
+        );
+    }
+
+    #[test]
+    fn test_trap_to_mtvec_and_mret() {
+        let mut cpu = load_program(&[
+            0x00000297, // auipc t0, 0
+            0x02028293, // addi t0, t0, 0x20
+            0x30529073, // csrw mtvec, t0
+            0x30046073, // csrsi mstatus, 8
+            0x00000073, // ecall
+            0x00100593, // li a1, 1
+            0x00000013, // nop
+            0x00000013, // nop
+            0x34202573, // csrr a0, mcause
+            0x34102373, // csrr t1, mepc
+            0x00430313, // addi t1, t1, 4
+            0x34131073, // csrw mepc, t1
+            0x30200073, // mret
+        ]);
+        for _ in 0..5 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x8000_0020);
+        assert_eq!(cpu.csr.get(csr::MEPC), 0x8000_0010);
+        let mstatus = cpu.csr.get(csr::MSTATUS);
+        assert_eq!(
+            mstatus & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE),
+            csr::MSTATUS_MPIE
+        );
+
+        for _ in 0..6 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.reg[10], 11);
+        assert_eq!(cpu.reg[11], 1);
+        assert_eq!(cpu.pc(), 0x8000_0018);
+        let mstatus = cpu.csr.get(csr::MSTATUS);
+        assert_eq!(
+            mstatus & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE),
+            csr::MSTATUS_MIE | csr::MSTATUS_MPIE
+        );
+    }
+
+    #[test]
+    fn test_privilege_modes() {
+        let mut program = [0x00000013u32; 17]; // nop
+        program[0] = 0x30200073; // mret
+        program[4] = 0x10200073; // sret
+        program[8] = 0x00000073; // ecall
+        program[16] = 0x30002573; // csrr a0, mstatus
+        let mut cpu = load_program(&program);
+        cpu.csr.write(csr::MTVEC, 0x8000_0100);
+        cpu.csr.write(csr::STVEC, 0x8000_0040);
+        cpu.csr.write(csr::MEDELEG, 1 << 8);
+        cpu.csr.write(csr::MEPC, 0x8000_0010);
+        cpu.csr.write(csr::SEPC, 0x8000_0020);
+        cpu.csr.write(csr::MSTATUS, 1 << csr::MSTATUS_MPP_SHIFT);
+
+        // M -> S -> U
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0010);
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.privilege, Privilege::User);
+        assert_eq!(cpu.pc(), 0x8000_0020);
+
+        // ecall from U is delegated to S
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0040);
+        assert_eq!(cpu.csr.get(csr::SCAUSE), 8);
+        assert_eq!(cpu.csr.get(csr::SEPC), 0x8000_0020);
+        assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_SPP, 0);
+
+        // S can't touch machine CSRs, the illegal instruction isn't delegated
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.pc(), 0x8000_0100);
+        assert_eq!(cpu.csr.get(csr::MCAUSE), 2);
+        assert_eq!(cpu.csr.get(csr::MTVAL), 0x30002573);
+        assert_eq!(cpu.csr.get(csr::MEPC), 0x8000_0040);
+        assert_eq!(
+            cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_MPP,
+            1 << csr::MSTATUS_MPP_SHIFT
+        );
+        assert_eq!(cpu.reg[10], 0);
+    }
+
+    #[test]
+    fn test_virtual_memory() {
+        use crate::mmu::{PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X, SATP_MODE_SV32};
+
+        let mut cpu = load_program(&[
+            0x000012b7, // lui t0, 0x1
+            0x0002a503, // lw a0, 0(t0)
+            0x00a2a223, // sw a0, 4(t0)
+            0x40000337, // lui t1, 0x40000
+            0x00a32423, // sw a0, 8(t1)
+            0x12000073, // sfence.vma
+        ]);
+        let word = MemoryAccessSize::Word;
+        let pte = |paddr: u32, flags: u32| (paddr >> 12) << 10 | flags;
+        // code at 0x0, user data at 0x1000, a megapage at 0x40000000
+        let (root, table) = (0x8001_0000, 0x8001_1000);
+        cpu.bus.write(root, word, pte(table, PTE_V)).unwrap();
+        cpu.bus
+            .write(
+                root + 4 * 0x100,
+                word,
+                pte(0x8040_0000, PTE_V | PTE_R | PTE_W),
+            )
+            .unwrap();
+        cpu.bus
+            .write(table, word, pte(0x8000_0000, PTE_V | PTE_R | PTE_X))
+            .unwrap();
+        cpu.bus
+            .write(
+                table + 4,
+                word,
+                pte(0x8002_0000, PTE_V | PTE_R | PTE_W | PTE_U),
+            )
+            .unwrap();
+        cpu.bus.write(0x8002_0000, word, 42).unwrap();
+
+        cpu.csr.write(csr::SATP, SATP_MODE_SV32 | root >> 12);
+        cpu.csr.write(csr::MTVEC, 0x8000_0100);
+        cpu.privilege = Privilege::Supervisor;
+        cpu.reg[PC_INDEX] = 0;
+
+        // S mode can't read user pages without SUM
+        cpu.execute_ins().unwrap();
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csr.get(csr::MCAUSE), 13);
+        assert_eq!(cpu.csr.get(csr::MTVAL), 0x1000);
+        assert_eq!(cpu.csr.get(csr::MEPC), 0x4);
+
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_SUM);
+        cpu.privilege = Privilege::Supervisor;
+        cpu.reg[PC_INDEX] = 0x4;
+        for _ in 0..5 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x18);
+        assert_eq!(cpu.reg[10], 42);
+        assert_eq!(cpu.bus.read(0x8002_0004, word), Ok(42));
+        assert_eq!(cpu.bus.read(0x8040_0008, word), Ok(42));
+
+        // accessed and dirty bits were set by the walks
+        let flags = |cpu: &mut CPU, addr| cpu.bus.read(addr, word).unwrap() & (PTE_A | PTE_D);
+        assert_eq!(flags(&mut cpu, table), PTE_A);
+        assert_eq!(flags(&mut cpu, table + 4), PTE_A | PTE_D);
+        assert_eq!(flags(&mut cpu, root + 4 * 0x100), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn test_pmp_faults() {
+        use crate::pmp::{PMP_NAPOT, PMP_R, PMP_TOR, PMP_X};
+
+        let mut cpu = load_program(&[
+            0x800012b7, // lui t0, 0x80001
+            0x0002a503, // lw a0, 0(t0)
+            0x00a2a023, // sw a0, 0(t0)
+        ]);
+        // code below 0x80001000, a read only page above it
+        cpu.csr.write(csr::PMPADDR0, 0x8000_1000 >> 2);
+        cpu.csr.write(csr::PMPADDR0 + 1, (0x8000_1000 >> 2) | 0x1FF);
+        cpu.csr.write(
+            csr::PMPCFG0,
+            (PMP_TOR | PMP_R | PMP_X) as u32 | ((PMP_NAPOT | PMP_R) as u32) << 8,
+        );
+        cpu.bus
+            .write(0x8000_1000, MemoryAccessSize::Word, 7)
+            .unwrap();
+        cpu.csr.write(csr::MTVEC, 0x8000_0100);
+        cpu.privilege = Privilege::User;
+
+        for _ in 0..3 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.reg[10], 7);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csr.get(csr::MCAUSE), 7);
+        assert_eq!(cpu.csr.get(csr::MTVAL), 0x8000_1000);
+        assert_eq!(cpu.csr.get(csr::MEPC), 0x8000_0008);
+
+        // M mode isn't restricted by unlocked entries
+        cpu.reg[PC_INDEX] = 0x8000_0008;
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.pc(), 0x8000_000c);
+    }
+
+    #[test]
+    fn test_exception_sets_mtval() {
+        let mut cpu = load_program(&[
+            0x00000297, // auipc t0, 0
+            0x02028293, // addi t0, t0, 0x20
+            0x30529073, // csrw mtvec, t0
+            0xffffffff, // illegal
+        ]);
+        for _ in 0..4 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x8000_0020);
+        assert_eq!(cpu.csr.get(csr::MCAUSE), 2);
+        assert_eq!(cpu.csr.get(csr::MEPC), 0x8000_000c);
+        assert_eq!(cpu.csr.get(csr::MTVAL), 0xffffffff);
+    }
+
+    #[test]
+    fn test_multiply_divide_instructions() {
+        let mut cpu = load_program(&[
+            0xff900293, // li t0, -7
+            0x00200313, // li t1, 2
+            0x800003b7, // lui t2, 0x80000
+            0xfff00e13, // li t3, -1
+            0x02628533, // mul a0, t0, t1
+            0x026295b3, // mulh a1, t0, t1
+            0x0262a633, // mulhsu a2, t0, t1
+            0x0262b6b3, // mulhu a3, t0, t1
+            0x0262c733, // div a4, t0, t1
+            0x0262e7b3, // rem a5, t0, t1
+            0x0202d833, // divu a6, t0, zero
+            0x0202f8b3, // remu a7, t0, zero
+            0x03c3c433, // div s0, t2, t3
+            0x03c3e4b3, // rem s1, t2, t3
+            0x0202c933, // div s2, t0, zero
+            0x0202e9b3, // rem s3, t0, zero
+        ]);
+        for _ in 0..16 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[10], -14i32 as u32);
+        assert_eq!(cpu.reg[11], u32::MAX);
+        assert_eq!(cpu.reg[12], u32::MAX);
+        assert_eq!(cpu.reg[13], 1);
+        assert_eq!(cpu.reg[14], -3i32 as u32);
+        assert_eq!(cpu.reg[15], -1i32 as u32);
+        // division by zero
+        assert_eq!(cpu.reg[16], u32::MAX);
+        assert_eq!(cpu.reg[17], -7i32 as u32);
+        // signed overflow
+        assert_eq!(cpu.reg[8], 0x8000_0000);
+        assert_eq!(cpu.reg[9], 0);
+        assert_eq!(cpu.reg[18], u32::MAX);
+        assert_eq!(cpu.reg[19], -7i32 as u32);
+    }
+
+    #[test]
+    fn test_atomic_instructions() {
+        let mut cpu = load_program(&[
+            0x800012b7, // lui t0, 0x80001
+            0x00500313, // li t1, 5
+            0x0062a023, // sw t1, 0(t0)
+            0x1002a52f, // lr.w a0, (t0)
+            0x00150513, // addi a0, a0, 1
+            0x18a2a5af, // sc.w a1, a0, (t0)
+            0x18a2a62f, // sc.w a2, a0, (t0)
+            0x1002a6af, // lr.w a3, (t0)
+            0x0002a023, // sw zero, 0(t0)
+            0x1862a72f, // sc.w a4, t1, (t0)
+            0xffd00393, // li t2, -3
+            0x0072a7af, // amoadd.w a5, t2, (t0)
+            0xe072a82f, // amomaxu.w a6, t2, (t0)
+            0x8062a8af, // amomin.w a7, t1, (t0)
+            0x0862a42f, // amoswap.w s0, t1, (t0)
+            0x2072a4af, // amoxor.w s1, t2, (t0)
+            0x0002a903, // lw s2, 0(t0)
+            0x00228e13, // addi t3, t0, 2
+            0x406e202f, // amoor.w zero, t1, (t3)
+        ]);
+        for _ in 0..18 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[10], 6);
+        // first SC succeeds, the second one has no reservation left
+        assert_eq!(cpu.reg[11], 0);
+        assert_eq!(cpu.reg[12], 1);
+        assert_eq!(cpu.reg[13], 6);
+        // the plain store in between drops the reservation
+        assert_eq!(cpu.reg[14], 1);
+        assert_eq!(cpu.reg[15], 0);
+        assert_eq!(cpu.reg[16], -3i32 as u32);
+        assert_eq!(cpu.reg[17], -3i32 as u32);
+        assert_eq!(cpu.reg[8], -3i32 as u32);
+        assert_eq!(cpu.reg[9], 5);
+        assert_eq!(cpu.reg[18], 5 ^ -3i32 as u32);
+
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::StoreAddressMisaligned(0x8000_1002))
+        );
+    }
+
+    #[test]
+    fn test_compressed_instructions() {
+        let mut cpu = load_program(&[
+            0x0505_4515, // c.li a0, 5; c.addi a0, 1
+            0x0015_0593, // addi a1, a0, 1
+            0x2011_852e, // c.mv a0, a1; c.jal 4
+            0x9002_0001, // c.nop; c.ebreak
+        ]);
+        for _ in 0..5 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.reg[10], 7);
+        assert_eq!(cpu.reg[11], 7);
+        assert_eq!(cpu.reg[1], 0x8000_000c);
+        assert_eq!(cpu.pc(), 0x8000_000e);
+        assert_eq!(cpu.execute_ins(), Err(Exception::Breakpoint));
+    }
+
+    #[test]
+    fn test_machine_config() {
+        let config = MachineConfig {
+            ram_size: 256 * 1024 * 1024,
+            ram_base: 0x1000_0000,
+            reset_vector: 0x1000_0100,
+            uart_base: None,
+            ..Default::default()
+        };
+        let mut cpu = CPU::with_config(config);
+        cpu.load_instructions(
+            &[
+                0x0FF0_0293u32, // addi t0, zero, 0xff
+                0x2000_0337,    // lui t1, 0x20000
+                0xFE53_2E23,    // sw t0, -4(t1)
+                0x0003_2383,    // lw t2, 0(t1)
+            ]
+            .iter()
+            .flat_map(|ins| ins.to_le_bytes())
+            .collect::<Vec<u8>>(),
+        );
+        assert_eq!(cpu.pc(), 0x1000_0100);
+
+        for _ in 0..3 {
+            cpu.execute_ins().unwrap();
+        }
+        // the last word of RAM is reachable, one past it isn't
+        assert_eq!(cpu.bus.read(0x1FFF_FFFC, MemoryAccessSize::Word), Ok(0xFF));
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::LoadAccessFault(0x2000_0000))
+        );
+    }
+
+    #[test]
+    fn test_ram_filling_address_space() {
+        // 4 GiB, the allocator hands out zeroed pages lazily
+        let mut cpu = CPU::with_config(MachineConfig {
+            ram_base: 0,
+            ram_size: (1u64 << 32) as usize,
+            reset_vector: 0,
+            uart_base: None,
+            clint_base: None,
+            plic_base: None,
+            ..Default::default()
+        });
+        assert_eq!(cpu.bus.read(0xFFFF_FFFC, MemoryAccessSize::Word), Ok(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the address space")]
+    fn test_ram_past_address_space() {
+        CPU::with_config(MachineConfig {
+            ram_base: 0xFFFF_0000,
+            ram_size: 0x2_0000,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_load_elf_segments() {
+        let mut binary_data = load_binary("examples/hello_world/program.elf");
+        // doesn't fit in the default RAM at 0x80000000
+        assert!(matches!(
+            CPU::new().load_elf(&binary_data),
+            Err(ElfError::SegmentOutOfRange { addr: 0x10000, .. })
+        ));
+
+        // grow p_memsz of the PT_LOAD segment to get a .bss
+        binary_data[0x54 + 20..0x54 + 24].copy_from_slice(&0x200u32.to_le_bytes());
+        let mut cpu = CPU::with_config(MachineConfig {
+            ram_size: 0x10000,
+            ram_base: 0x10000,
+            reset_vector: 0x10000,
+            ..Default::default()
+        });
+        cpu.bus.load_image(0x10000, &[0xAA; 0x10000]).unwrap();
+        cpu.load_elf(&binary_data).unwrap();
+
+        assert_eq!(cpu.pc(), 0x10074);
+        let memory = cpu.bus.read_bytes(0x10000, 0x201).unwrap();
+        assert_eq!(&memory[..4], &binary_data[..4]);
+        assert!(memory[0xa4..0x200].iter().all(|&byte| byte == 0));
+        assert_eq!(memory[0x200], 0xAA);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut cpu = load_program(&[
+            0x00000297, // auipc t0, 0
+            0x04028293, // addi t0, t0, 0x40
+            0x30529073, // csrw mtvec, t0
+            0x02004337, // lui t1, 0x2004 (mtimecmp)
+            0x01400393, // li t2, 20
+            0x00732023, // sw t2, 0(t1)
+            0x00032223, // sw zero, 4(t1)
+            0x08000393, // li t2, 0x80 (MTIE)
+            0x30439073, // csrw mie, t2
+            0x30046073, // csrsi mstatus, 8
+            0x10500073, // wfi
+            0xffdff06f, // j -4
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x34202573, // csrr a0, mcause
+            0xc01025f3, // rdtime a1
+        ]);
+        for _ in 0..100 {
+            if cpu.pc() == 0x8000_0040 {
+                break;
+            }
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x8000_0040);
+        assert!(matches!(cpu.csr.get(csr::MEPC), 0x8000_0028 | 0x8000_002c));
+        assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_MIE, 0);
+        assert_ne!(cpu.csr.get(csr::MIP) & csr::MIP_MTIP, 0);
+
+        cpu.execute_ins().unwrap();
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.reg[10], 0x8000_0007);
+        assert!(cpu.reg[11] >= 20);
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        use crate::plic::{CLAIM_COMPLETE, DEFAULT_PLIC_BASE, ENABLE, PRIORITY};
+        use crate::uart::DEFAULT_UART_BASE;
+
+        let mut cpu = CPU::with_config(MachineConfig {
+            uart_base: None,
+            ..Default::default()
+        });
+        let (uart, _) = Uart::new(Box::new(std::io::sink()));
+        cpu.bus
+            .attach_with_irq(DEFAULT_UART_BASE, DEFAULT_UART_IRQ, Box::new(uart));
+        cpu.load_instructions(
+            &[
+                0x0000006fu32, // j 0
+                0x00000013,    // nop
+                0x00000013,    // nop
+                0x00000013,    // nop
+                0x34202573,    // csrr a0, mcause
+            ]
+            .iter()
+            .flat_map(|ins| ins.to_le_bytes())
+            .collect::<Vec<u8>>(),
+        );
+        cpu.csr.write(csr::MTVEC, 0x8000_0010);
+        cpu.csr.write(csr::MIE, csr::MIP_MEIP);
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MIE);
+
+        let word = MemoryAccessSize::Word;
+        let plic = DEFAULT_PLIC_BASE;
+        cpu.bus.write(plic + PRIORITY + 4 * 10, word, 1).unwrap();
+        cpu.bus.write(plic + ENABLE, word, 1 << 10).unwrap();
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.pc(), 0x8000_0000);
+
+        // received data available interrupt, a byte looped back through the UART
+        let byte = MemoryAccessSize::Byte;
+        cpu.bus.write(DEFAULT_UART_BASE + 1, byte, 1).unwrap();
+        cpu.bus.write(DEFAULT_UART_BASE + 4, byte, 0x10).unwrap();
+        cpu.bus.write(DEFAULT_UART_BASE, byte, b'x' as u32).unwrap();
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.pc(), 0x8000_0010);
+        cpu.execute_ins().unwrap();
+        assert_eq!(cpu.reg[10], 0x8000_000b);
+
+        assert_eq!(cpu.bus.read(plic + CLAIM_COMPLETE, word), Ok(10));
+        assert_eq!(cpu.bus.read(DEFAULT_UART_BASE, byte), Ok(b'x' as u32));
+        cpu.bus.write(plic + CLAIM_COMPLETE, word, 10).unwrap();
+        assert_eq!(cpu.bus.interrupts() & csr::MIP_MEIP, 0);
+    }
+
+    #[test]
+    fn test_uart_is_mapped() {
+        let mut cpu = load_program(&[
+            0x1000_02B7, // lui t0, 0x10000
+            0x0410_0313, // addi t1, zero, 'A'
+            0x0062_8023, // sb t1, 0(t0)
+            0x0052_C503, // lbu a0, 5(t0)
+        ]);
+        for _ in 0..4 {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(
+            cpu.reg[10],
+            (crate::uart::LSR_THR_EMPTY | crate::uart::LSR_TRANSMITTER_EMPTY) as u32
+        );
+    }
+
+    #[test]
+    fn test_float_instructions() {
+        let mut cpu = load_program(&[
+            0x800012B7, // lui t0, 0x80001
+            0x40400337, // lui t1, 0x40400 (3.0)
+            0xF00300D3, // fmv.w.x f1, t1
+            0x3F800337, // lui t1, 0x3f800 (1.0)
+            0xF0030153, // fmv.w.x f2, t1
+            0x0020F1D3, // fadd.s f3, f1, f2
+            0x0032A027, // fsw f3, 0(t0)
+            0x18117253, // fdiv.s f4, f2, f1
+            0xC001F553, // fcvt.w.s a0, f3
+            0xA031A5D3, // feq.s a1, f3, f3
+            0x4201F2D3, // fcvt.d.s f5, f3
+            0x0052B427, // fsd f5, 8(t0)
+            0x0082B307, // fld f6, 8(t0)
+            0xE2031653, // fclass.d a2, f6
+            0x1010F3C3, // fmadd.s f7, f1, f1, f2
+            0x001026F3, // csrr a3, fflags
+            0xE0031753, // fclass.s a4, f6
+            0x0020D1D3, // fadd.s f3, f1, f2 with the reserved rounding mode 5
+        ]);
+        for _ in 0..17 {
+            cpu.execute_ins().unwrap();
+        }
+
+        assert_eq!(cpu.freg[3], 0xFFFF_FFFF_4080_0000);
+        assert_eq!(
+            cpu.bus.read(0x8000_1000, MemoryAccessSize::Word),
+            Ok(0x4080_0000)
+        );
+        assert_eq!(
+            cpu.freg[4],
+            0xFFFF_FFFF_0000_0000 | (1.0f32 / 3.0).to_bits() as u64
+        );
+        assert_eq!(cpu.reg[10], 4);
+        assert_eq!(cpu.reg[11], 1);
+        assert_eq!(cpu.freg[6], 4.0f64.to_bits());
+        assert_eq!(cpu.reg[12], 1 << 6);
+        assert_eq!(
+            cpu.freg[7],
+            0xFFFF_FFFF_0000_0000 | 10.0f32.to_bits() as u64
+        );
+        // only the division was inexact
+        assert_eq!(cpu.reg[13], fpu::FLAG_NX);
+        // a double read as single isn't NaN-boxed
+        assert_eq!(cpu.reg[14], 1 << 9);
+        assert_eq!(cpu.csr.get(csr::MSTATUS) & csr::MSTATUS_FS, csr::FS_DIRTY);
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::IllegalInstruction(0x0020D1D3))
+        );
+
+        // with the FPU off every F/D instruction is illegal
+        let mut cpu = load_program(&[0x0020F1D3]); // fadd.s f3, f1, f2
+        cpu.csr.write(csr::MSTATUS, csr::FS_OFF);
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::IllegalInstruction(0x0020F1D3))
+        );
+    }
+
+    #[test]
+    fn test_double_access_past_ram() {
+        let mut cpu = load_program(&[
+            0x840002B7, // lui t0, 0x84000
+            0xFE02BE27, // fsd f0, -4(t0)
+            0xFFC2B087, // fld f1, -4(t0)
+        ]);
+        cpu.freg[0] = 0x1122_3344_5566_7788;
+        cpu.execute_ins().unwrap();
+
+        // the high word is past the end of RAM, the low word stays as it was
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::StoreAccessFault(0x8400_0000))
+        );
+        assert_eq!(cpu.bus.read(0x83FF_FFFC, MemoryAccessSize::Word), Ok(0));
+        assert!(cpu.memory_accesses().is_empty());
+        cpu.set_pc(0x8000_0008);
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::LoadAccessFault(0x8400_0000))
+        );
+        assert!(cpu.memory_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_misaligned_store_past_ram() {
+        let mut cpu = load_program(&[
+            0xFFF00313, // li t1, -1
+            0x840002B7, // lui t0, 0x84000
+            0xFE62AF23, // sw t1, -2(t0)
+        ]);
+        cpu.execute_ins().unwrap();
+        cpu.execute_ins().unwrap();
+
+        // crosses into the unmapped page after RAM, none of the bytes are written
+        assert_eq!(
+            cpu.execute_ins(),
+            Err(Exception::StoreAccessFault(0x8400_0000))
+        );
+        assert_eq!(cpu.bus.read(0x83FF_FFFC, MemoryAccessSize::Word), Ok(0));
+    }
+
+    #[test]
+    fn test_linux_syscalls() {
+        use crate::testutil::SharedBuffer;
+
+        // examples/hello_world writes with Linux syscall 64 and exits with 93
+        let output = SharedBuffer::default();
+        let mut cpu = CPU::with_config(MachineConfig {
+            ram_size: 0x10000,
+            ram_base: 0x10000,
+            reset_vector: 0x10000,
+            ..Default::default()
+        });
+        let input: &[u8] = &[];
+        cpu.set_syscall_abi(Box::new(LinuxAbi::with_io(
+            None,
+            Box::new(input),
+            Box::new(output.clone()),
+        )));
+        cpu.load_elf(&load_binary("examples/hello_world/program.elf"))
+            .unwrap();
+        // initial stack at the top of RAM
+        assert_eq!(cpu.reg[2], 0x1FFE0);
+        while !cpu.is_exited() {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(output.0.borrow().as_slice(), b"Hello, World!");
+        // the program exits with whatever write returned
+        assert_eq!(cpu.exit_code, Some(13));
+    }
+
+    #[test]
+    fn test_assembled_program() {
+        let mut cpu = CPU::new();
+        cpu.load_assembly(
+            "
+            _start:
+                la    t0, values
+                li    t1, 4
+                li    a0, 0
+            loop:
+                lw    t2, 0(t0)
+                add   a0, a0, t2
+                addi  t0, t0, 4
+                addi  t1, t1, -1
+                bnez  t1, loop
+                call  double
+                li    a7, 93
+                ecall
+            double:
+                slli  a0, a0, 1
+                ret
+            .data
+            values: .word 1, 2, 3, 0x100
+            ",
+        )
+        .unwrap();
+        while !cpu.is_exited() {
+            cpu.execute_ins().unwrap();
+        }
+        assert_eq!(cpu.exit_code, Some(2 * (1 + 2 + 3 + 0x100)));
+        assert_eq!(cpu.symbols.describe(0x8000003C).as_deref(), Some("values"));
+    }
 }
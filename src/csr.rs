@@ -0,0 +1,560 @@
+//! Control and status registers (Zicsr) of a hart with machine, supervisor and user mode
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::pmp::Pmp;
+
+// floating point control and status
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+// supervisor trap setup
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+
+// supervisor trap handling
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// supervisor protection and translation
+pub const SATP: u16 = 0x180;
+
+// machine information registers
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSTATUSH: u16 = 0x310;
+
+// machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// machine memory protection
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPCFG3: u16 = 0x3A3;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const PMPADDR15: u16 = 0x3BF;
+
+// machine counters
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+// user counters, read only shadows of the machine counters
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+// mstatus fields
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_FS: u32 = 0b11 << 13;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+pub const MSTATUS_SD: u32 = 1 << 31;
+
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+/// the part of mstatus visible through sstatus
+const SSTATUS_MASK: u32 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_SD;
+
+// mstatus.FS states
+pub const FS_OFF: u32 = 0;
+pub const FS_INITIAL: u32 = 1 << 13;
+pub const FS_DIRTY: u32 = 0b11 << 13;
+
+// mip / mie fields
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+/// interrupts that can be delegated to supervisor mode, software may also set them in mip
+const SUPERVISOR_INTERRUPTS: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const ALL_INTERRUPTS: u32 = SUPERVISOR_INTERRUPTS | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+/// exceptions that can be delegated: all but the reserved codes and ecall from M mode
+const DELEGABLE_EXCEPTIONS: u32 = 0xB3FF;
+
+/// Privilege level the hart runs at, ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Privilege encoded in the MPP/SPP fields and CSR addresses, the reserved 0b10 reads as M
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Privilege::User => write!(f, "U"),
+            Privilege::Supervisor => write!(f, "S"),
+            Privilege::Machine => write!(f, "M"),
+        }
+    }
+}
+
+/// Assembler name of a CSR this hart implements
+pub fn name(addr: u16) -> Option<String> {
+    let name = match addr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MSTATUSH => "mstatush",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        PMPCFG0..=PMPCFG3 => return Some(format!("pmpcfg{}", addr - PMPCFG0)),
+        PMPADDR0..=PMPADDR15 => return Some(format!("pmpaddr{}", addr - PMPADDR0)),
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MCYCLEH => "mcycleh",
+        MINSTRETH => "minstreth",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        CYCLEH => "cycleh",
+        TIMEH => "timeh",
+        INSTRETH => "instreth",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Address of the CSR called `name`, the inverse of [`name`]
+pub fn address(name: &str) -> Option<u16> {
+    // built once, the assembler looks up every CSR operand
+    static ADDRESSES: OnceLock<HashMap<String, u16>> = OnceLock::new();
+    ADDRESSES
+        .get_or_init(|| {
+            (0..0x1000)
+                .filter_map(|addr| Some((self::name(addr)?, addr)))
+                .collect()
+        })
+        .get(name)
+        .copied()
+}
+
+/// misa: MXL = 1 (32-bit) and the implemented extensions, one bit per letter
+const MISA_VALUE: u32 = (1 << 30)
+    | ext('I')
+    | ext('M')
+    | ext('A')
+    | ext('F')
+    | ext('D')
+    | ext('C')
+    | ext('S')
+    | ext('U');
+
+const fn ext(letter: char) -> u32 {
+    1 << (letter as u32 - 'A' as u32)
+}
+
+const CSR_COUNT: usize = 4096;
+
+#[derive(Debug)]
+pub struct CsrFile {
+    /// plain storage for the read/write registers, indexed by CSR address
+    csrs: Vec<u32>,
+    /// 64-bit mcycle counter
+    pub cycle: u64,
+    /// 64-bit minstret counter
+    pub instret: u64,
+    /// mtime shadowed by the time CSR, refreshed by the hart before it is read
+    pub time: u64,
+    /// mip bits driven by interrupt lines, on top of the ones software set
+    interrupt_lines: u32,
+    /// physical memory protection entries behind pmpcfg* and pmpaddr*
+    pub pmp: Pmp,
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        let mut csrs = vec![0; CSR_COUNT];
+        // the FPU starts enabled so programs without a runtime that sets FS still work
+        csrs[MSTATUS as usize] = FS_INITIAL;
+        Self {
+            csrs,
+            cycle: 0,
+            instret: 0,
+            time: 0,
+            interrupt_lines: 0,
+            pmp: Pmp::new(),
+        }
+    }
+
+    /// CSRs with address bits [11:10] set to 0b11 can't be written
+    pub fn is_read_only(addr: u16) -> bool {
+        (addr >> 10) & 0b11 == 0b11
+    }
+
+    /// Whether code running at `privilege` may access the CSR at all. The lowest privilege
+    /// allowed is encoded in address bits [9:8], counters also depend on the counter enables.
+    pub fn accessible(&self, addr: u16, privilege: Privilege) -> bool {
+        if privilege < Privilege::from_bits((addr >> 8) as u32) {
+            return false;
+        }
+        match addr {
+            SATP => {
+                privilege != Privilege::Supervisor || self.csrs[MSTATUS as usize] & MSTATUS_TVM == 0
+            }
+            CYCLE..=INSTRET | CYCLEH..=INSTRETH => {
+                let bit = 1 << (addr & 0x1F);
+                match privilege {
+                    Privilege::Machine => true,
+                    Privilege::Supervisor => self.csrs[MCOUNTEREN as usize] & bit != 0,
+                    Privilege::User => {
+                        self.csrs[MCOUNTEREN as usize] & self.csrs[SCOUNTEREN as usize] & bit != 0
+                    }
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Read a CSR, `None` if it isn't implemented
+    pub fn read(&self, addr: u16) -> Option<u32> {
+        let value = match addr {
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            MISA => MISA_VALUE,
+            MSTATUS => {
+                let mstatus = self.csrs[MSTATUS as usize];
+                if mstatus & MSTATUS_FS == FS_DIRTY {
+                    mstatus | MSTATUS_SD
+                } else {
+                    mstatus
+                }
+            }
+            SSTATUS => self.read(MSTATUS)? & SSTATUS_MASK,
+            MSTATUSH => 0,
+            MIP => self.csrs[MIP as usize] | self.interrupt_lines,
+            SIE => self.csrs[MIE as usize] & self.csrs[MIDELEG as usize],
+            SIP => self.read(MIP)? & self.csrs[MIDELEG as usize],
+            MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN | MSCRATCH | MEPC | MCAUSE | MTVAL
+            | STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE | STVAL | SATP => {
+                self.csrs[addr as usize]
+            }
+            PMPCFG0..=PMPCFG3 => self.pmp.read_cfg((addr - PMPCFG0) as usize),
+            PMPADDR0..=PMPADDR15 => self.pmp.read_addr((addr - PMPADDR0) as usize),
+            MCYCLE | CYCLE => self.cycle as u32,
+            MCYCLEH | CYCLEH => (self.cycle >> 32) as u32,
+            MINSTRET | INSTRET => self.instret as u32,
+            MINSTRETH | INSTRETH => (self.instret >> 32) as u32,
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            // the floating point CSRs don't exist while the FPU is off
+            FFLAGS | FRM | FCSR if !self.fp_enabled() => return None,
+            FFLAGS => self.csrs[FCSR as usize] & 0x1F,
+            FRM => self.csrs[FCSR as usize] >> 5,
+            FCSR => self.csrs[FCSR as usize],
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Value of a CSR the hart maintains itself (trap entry/return), unimplemented ones read as 0
+    pub fn get(&self, addr: u16) -> u32 {
+        self.read(addr).unwrap_or(0)
+    }
+
+    /// Write a CSR, WARL fields keep their legal values and read only bits are ignored
+    pub fn write(&mut self, addr: u16, value: u32) {
+        match addr {
+            MSTATUS => {
+                let mut value = value & MSTATUS_WRITABLE;
+                // MPP is WARL, the reserved privilege 0b10 keeps the old value
+                if (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 0b10 {
+                    value = (value & !MSTATUS_MPP) | (self.csrs[MSTATUS as usize] & MSTATUS_MPP);
+                }
+                self.csrs[addr as usize] = value;
+            }
+            SSTATUS => {
+                let mstatus = self.csrs[MSTATUS as usize];
+                self.csrs[MSTATUS as usize] =
+                    (mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK & MSTATUS_WRITABLE);
+            }
+            MEDELEG => self.csrs[addr as usize] = value & DELEGABLE_EXCEPTIONS,
+            MIDELEG => self.csrs[addr as usize] = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.csrs[addr as usize] = value & ALL_INTERRUPTS,
+            MIP => self.csrs[addr as usize] = value & SUPERVISOR_INTERRUPTS,
+            // supervisor software can only see and change the delegated interrupts
+            SIE => {
+                let mideleg = self.csrs[MIDELEG as usize];
+                let mie = self.csrs[MIE as usize];
+                self.csrs[MIE as usize] = (mie & !mideleg) | (value & mideleg);
+            }
+            SIP => {
+                let writable = self.csrs[MIDELEG as usize] & MIP_SSIP;
+                let mip = self.csrs[MIP as usize];
+                self.csrs[MIP as usize] = (mip & !writable) | (value & writable);
+            }
+            // direct (0) and vectored (1) modes only
+            MTVEC | STVEC => self.csrs[addr as usize] = value & !0b10,
+            // IALIGN is 16 with the C extension
+            MEPC | SEPC => self.csrs[addr as usize] = value & !0b1,
+            MCOUNTEREN | SCOUNTEREN => self.csrs[addr as usize] = value & 0b111,
+            MSCRATCH | MCAUSE | MTVAL | SSCRATCH | SCAUSE | STVAL | SATP => {
+                self.csrs[addr as usize] = value
+            }
+            PMPCFG0..=PMPCFG3 => self.pmp.write_cfg((addr - PMPCFG0) as usize, value),
+            PMPADDR0..=PMPADDR15 => self.pmp.write_addr((addr - PMPADDR0) as usize, value),
+            MCYCLE => self.cycle = (self.cycle & !0xFFFF_FFFF) | value as u64,
+            MCYCLEH => self.cycle = (self.cycle & 0xFFFF_FFFF) | (value as u64) << 32,
+            MINSTRET => self.instret = (self.instret & !0xFFFF_FFFF) | value as u64,
+            MINSTRETH => self.instret = (self.instret & 0xFFFF_FFFF) | (value as u64) << 32,
+            FFLAGS => self.set_fcsr((self.csrs[FCSR as usize] & !0x1F) | (value & 0x1F)),
+            FRM => self.set_fcsr((self.csrs[FCSR as usize] & 0x1F) | (value & 0b111) << 5),
+            FCSR => self.set_fcsr(value & 0xFF),
+            // misa is fixed
+            _ => {}
+        }
+    }
+
+    /// Levels of the interrupt lines into mip, software can't clear the bits they drive
+    pub fn set_interrupt_lines(&mut self, lines: u32) {
+        self.interrupt_lines = lines & ALL_INTERRUPTS;
+    }
+
+    /// Interrupts that are both pending and enabled
+    pub fn enabled_interrupts(&self) -> u32 {
+        self.get(MIP) & self.csrs[MIE as usize]
+    }
+
+    /// Floating point instructions and CSRs are illegal while mstatus.FS is off
+    pub fn fp_enabled(&self) -> bool {
+        self.csrs[MSTATUS as usize] & MSTATUS_FS != FS_OFF
+    }
+
+    /// Any write to the floating point state marks it dirty
+    pub fn set_fp_dirty(&mut self) {
+        self.csrs[MSTATUS as usize] |= FS_DIRTY;
+    }
+
+    /// Dynamic rounding mode from fcsr.frm
+    pub fn frm(&self) -> u32 {
+        self.csrs[FCSR as usize] >> 5
+    }
+
+    /// Accumulate exception flags raised by a floating point instruction
+    pub fn raise_fflags(&mut self, flags: u32) {
+        if flags != 0 {
+            self.set_fcsr(self.csrs[FCSR as usize] | flags);
+        }
+    }
+
+    fn set_fcsr(&mut self, value: u32) {
+        self.csrs[FCSR as usize] = value;
+        self.set_fp_dirty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mstatus_warl_fields() {
+        let mut csr = CsrFile::new();
+        csr.write(MSTATUS, 0xFFFF_FFFF);
+        assert_eq!(csr.read(MSTATUS), Some(MSTATUS_WRITABLE | MSTATUS_SD));
+        csr.write(MSTATUS, 0);
+        assert_eq!(csr.read(MSTATUS), Some(0));
+
+        // MPP can't hold the reserved privilege
+        csr.write(MSTATUS, 1 << MSTATUS_MPP_SHIFT);
+        csr.write(MSTATUS, 0b10 << MSTATUS_MPP_SHIFT);
+        assert_eq!(csr.read(MSTATUS), Some(1 << MSTATUS_MPP_SHIFT));
+    }
+
+    #[test]
+    fn test_supervisor_views() {
+        let mut csr = CsrFile::new();
+        csr.write(SSTATUS, 0xFFFF_FFFF);
+        assert_eq!(csr.read(MSTATUS), Some(SSTATUS_MASK));
+        assert_eq!(csr.get(MSTATUS) & (MSTATUS_MIE | MSTATUS_MPP), 0);
+
+        // sie and sip only show the delegated interrupts
+        csr.write(MIE, 0xFFFF_FFFF);
+        csr.write(MIDELEG, 0xFFFF_FFFF);
+        assert_eq!(csr.read(MIDELEG), Some(SUPERVISOR_INTERRUPTS));
+        csr.write(MIDELEG, MIP_STIP);
+        csr.write(SIE, 0);
+        assert_eq!(csr.read(MIE), Some(ALL_INTERRUPTS & !MIP_STIP));
+        assert_eq!(csr.read(SIE), Some(0));
+
+        csr.set_interrupt_lines(MIP_MTIP | MIP_STIP);
+        csr.write(SIP, 0xFFFF_FFFF);
+        assert_eq!(csr.read(SIP), Some(MIP_STIP));
+        assert_eq!(csr.read(MIP), Some(MIP_MTIP | MIP_STIP));
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut csr = CsrFile::new();
+        assert!(csr.accessible(MSTATUS, Privilege::Machine));
+        assert!(!csr.accessible(MSTATUS, Privilege::Supervisor));
+        assert!(csr.accessible(SSTATUS, Privilege::Supervisor));
+        assert!(!csr.accessible(SSTATUS, Privilege::User));
+        assert!(csr.accessible(FCSR, Privilege::User));
+
+        assert!(csr.accessible(SATP, Privilege::Supervisor));
+        csr.write(MSTATUS, MSTATUS_TVM);
+        assert!(!csr.accessible(SATP, Privilege::Supervisor));
+        assert!(csr.accessible(SATP, Privilege::Machine));
+
+        // user counters need both counter enables
+        assert!(!csr.accessible(TIME, Privilege::Supervisor));
+        csr.write(MCOUNTEREN, 0b010);
+        assert!(csr.accessible(TIME, Privilege::Supervisor));
+        assert!(!csr.accessible(TIME, Privilege::User));
+        csr.write(SCOUNTEREN, 0b010);
+        assert!(csr.accessible(TIME, Privilege::User));
+        assert!(!csr.accessible(CYCLE, Privilege::User));
+    }
+
+    #[test]
+    fn test_counters_are_64_bit() {
+        let mut csr = CsrFile::new();
+        csr.cycle = 0x1_0000_0002;
+        assert_eq!(csr.read(CYCLE), Some(2));
+        assert_eq!(csr.read(CYCLEH), Some(1));
+
+        csr.write(MINSTRETH, 3);
+        csr.write(MINSTRET, 4);
+        assert_eq!(csr.instret, 0x3_0000_0004);
+    }
+
+    #[test]
+    fn test_fcsr_fields() {
+        let mut csr = CsrFile::new();
+        csr.write(MSTATUS, 0);
+        assert_eq!(csr.read(FCSR), None);
+
+        csr.write(MSTATUS, FS_INITIAL);
+        csr.write(FRM, 0b011);
+        csr.raise_fflags(0b10001);
+        assert_eq!(csr.read(FCSR), Some(0b011_10001));
+        assert_eq!(csr.read(FFLAGS), Some(0b10001));
+        csr.write(FFLAGS, 0);
+        assert_eq!(csr.read(FCSR), Some(0b011_00000));
+        assert_eq!(csr.get(MSTATUS) & MSTATUS_SD, MSTATUS_SD);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(name(MSCRATCH).as_deref(), Some("mscratch"));
+        assert_eq!(address("mscratch"), Some(MSCRATCH));
+        assert_eq!(address("pmpaddr15"), Some(PMPADDR15));
+        assert_eq!(name(0x7FF), None);
+        assert_eq!(address("mscratchh"), None);
+    }
+
+    #[test]
+    fn test_unimplemented_csr() {
+        let csr = CsrFile::new();
+        assert_eq!(csr.read(0x7FF), None);
+        assert_eq!(
+            csr.read(MISA),
+            Some(
+                (1 << 30)
+                    | (1 << 20)
+                    | (1 << 18)
+                    | (1 << 12)
+                    | (1 << 8)
+                    | (1 << 5)
+                    | (1 << 3)
+                    | (1 << 2)
+                    | 1
+            )
+        );
+        assert!(CsrFile::is_read_only(MHARTID));
+        assert!(!CsrFile::is_read_only(MSCRATCH));
+    }
+}
@@ -0,0 +1,466 @@
+//! Interactive debugger behind `rv32i_run --debug`, a prompt that drives the CPU one
+//! `execute_ins` at a time. `help` at the prompt lists the commands.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::compressed;
+use crate::cpu::CPU;
+use crate::disasm;
+use crate::instruction::REGISTER_NAMES;
+use crate::mmu::Access;
+
+const HELP: &str = "\
+step [n]              execute n instructions, 1 by default
+continue              run until a breakpoint, watchpoint, exception or exit
+break [addr|symbol]   set a breakpoint, without an argument list them
+delete addr|symbol    remove the breakpoint or watchpoint there
+watch addr|symbol [n] stop after stores to n bytes there, 4 by default
+regs                  print the registers
+x addr|symbol [n]     dump n bytes of memory as hex, 64 by default
+disas [addr|symbol]   instructions around the pc or an address
+clk                   clock cycles so far
+quit";
+
+/// instructions `disas` shows before the address
+const DISAS_BEFORE: usize = 4;
+const DISAS_COUNT: usize = 9;
+/// furthest into a symbol `disas` decodes from its start to find the instructions before
+const DISAS_WALK: u32 = 0x1000;
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    /// start and length of watched memory
+    watchpoints: Vec<(u32, u32)>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read commands one line at a time with `read_line` until `quit` or the end of the input.
+    /// Nothing of the input is held between commands, so a guest reading the same terminal
+    /// (`|line| io::stdin().read_line(line)`) gets it while it runs.
+    pub fn run(
+        &mut self,
+        cpu: &mut CPU,
+        mut read_line: impl FnMut(&mut String) -> io::Result<usize>,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        self.show_pc(cpu, &mut output)?;
+        loop {
+            write!(output, "(rv32) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, args)) = words.split_first() else {
+                continue;
+            };
+            if !self.command(cpu, command, args, &mut output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run one command, `false` once the user quits
+    fn command(
+        &mut self,
+        cpu: &mut CPU,
+        command: &str,
+        args: &[&str],
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        let addr = args.first().map(|arg| parse_addr(cpu, arg));
+        match command {
+            "s" | "step" => {
+                let steps = match args.first() {
+                    Some(arg) => parse_number(arg),
+                    None => Some(1),
+                };
+                match steps {
+                    Some(steps) => self.resume(cpu, Some(steps), output)?,
+                    None => writeln!(output, "step takes a number")?,
+                }
+            }
+            "c" | "continue" => self.resume(cpu, None, output)?,
+            "b" | "break" => match addr {
+                Some(Some(addr)) => {
+                    self.breakpoints.insert(addr);
+                    writeln!(output, "breakpoint at {}", cpu.symbols.location(addr))?;
+                }
+                Some(None) => writeln!(output, "unknown address {}", args[0])?,
+                None => {
+                    for &addr in &self.breakpoints {
+                        writeln!(output, "breakpoint at {}", cpu.symbols.location(addr))?;
+                    }
+                    for &(addr, len) in &self.watchpoints {
+                        writeln!(
+                            output,
+                            "watchpoint on {} bytes at {}",
+                            len,
+                            cpu.symbols.location(addr)
+                        )?;
+                    }
+                }
+            },
+            "d" | "delete" => match addr {
+                Some(Some(addr)) => {
+                    let breakpoint = self.breakpoints.remove(&addr);
+                    let watchpoints = self.watchpoints.len();
+                    self.watchpoints.retain(|&(start, _)| start != addr);
+                    if !breakpoint && watchpoints == self.watchpoints.len() {
+                        writeln!(output, "nothing set at 0x{:08x}", addr)?;
+                    }
+                }
+                _ => writeln!(output, "delete takes an address or symbol")?,
+            },
+            "w" | "watch" => match addr {
+                Some(Some(addr)) => {
+                    let len = args.get(1).map_or(Some(4), |arg| parse_number(arg));
+                    match len {
+                        Some(len) => {
+                            self.watchpoints.push((addr, len as u32));
+                            writeln!(
+                                output,
+                                "watchpoint on {} bytes at {}",
+                                len,
+                                cpu.symbols.location(addr)
+                            )?;
+                        }
+                        None => writeln!(output, "watch takes a length in bytes")?,
+                    }
+                }
+                _ => writeln!(output, "watch takes an address or symbol")?,
+            },
+            "r" | "regs" => {
+                for (row, names) in REGISTER_NAMES.chunks(4).enumerate() {
+                    let line: Vec<String> = names
+                        .iter()
+                        .enumerate()
+                        .map(|(i, name)| format!("{:<4} 0x{:08x}", name, cpu.reg[4 * row + i]))
+                        .collect();
+                    writeln!(output, "{}", line.join("  "))?;
+                }
+                writeln!(output, "{:<4} {}", "pc", cpu.symbols.location(cpu.pc()))?;
+            }
+            "x" => match addr {
+                Some(Some(addr)) => {
+                    let len = args.get(1).map_or(Some(64), |arg| parse_number(arg));
+                    match len {
+                        Some(len) => dump(cpu, addr, len as u32, output)?,
+                        None => writeln!(output, "x takes a length in bytes")?,
+                    }
+                }
+                _ => writeln!(output, "x takes an address or symbol")?,
+            },
+            "disas" => match addr {
+                Some(Some(addr)) => disassemble(cpu, addr, output)?,
+                Some(None) => writeln!(output, "unknown address {}", args[0])?,
+                None => disassemble(cpu, cpu.pc(), output)?,
+            },
+            "clk" => writeln!(output, "{}", cpu.clk)?,
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(output, "unknown command {}, try help", command)?,
+        }
+        Ok(true)
+    }
+
+    /// Execute `steps` instructions, or until something stops the program. A breakpoint at
+    /// the pc it resumes from doesn't count.
+    fn resume(
+        &mut self,
+        cpu: &mut CPU,
+        steps: Option<u64>,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut executed = 0;
+        loop {
+            if cpu.is_exited() {
+                return match cpu.exit_code {
+                    Some(code) => writeln!(output, "program exited with code {}", code),
+                    None => writeln!(output, "program exited"),
+                };
+            }
+            if executed > 0 && self.breakpoints.contains(&cpu.pc()) {
+                writeln!(output, "breakpoint")?;
+                break;
+            }
+            if steps == Some(executed) {
+                break;
+            }
+            if let Err(exception) = cpu.execute_ins() {
+                writeln!(
+                    output,
+                    "{} at {}",
+                    exception,
+                    cpu.symbols.location(cpu.pc())
+                )?;
+                return Ok(());
+            }
+            executed += 1;
+
+            let hit = cpu.memory_accesses().iter().find(|access| {
+                access.access == Access::Store
+                    && self.watchpoints.iter().any(|&(addr, len)| {
+                        (access.addr as u64) < addr as u64 + len as u64
+                            && (addr as u64) < access.addr as u64 + access.size.byte_size() as u64
+                    })
+            });
+            if let Some(access) = hit {
+                writeln!(
+                    output,
+                    "watchpoint: stored 0x{:x} at 0x{:08x}",
+                    access.value, access.addr
+                )?;
+                break;
+            }
+        }
+        self.show_pc(cpu, output)
+    }
+
+    fn show_pc(&self, cpu: &mut CPU, output: &mut impl Write) -> io::Result<()> {
+        let (line, _) = instruction_line(cpu, cpu.pc());
+        writeln!(output, "{}", line)
+    }
+}
+
+/// Hex or decimal
+fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// A number or a symbol of the loaded ELF
+fn parse_addr(cpu: &CPU, arg: &str) -> Option<u32> {
+    parse_number(arg)
+        .and_then(|addr| u32::try_from(addr).ok())
+        .or_else(|| cpu.symbols.lookup(arg))
+}
+
+/// The instruction at `addr` with its disassembly, and its length
+fn instruction_line(cpu: &mut CPU, addr: u32) -> (String, u32) {
+    let Ok(low) = cpu.read_memory(addr, 2) else {
+        return (format!("{}: <unreadable>", cpu.symbols.location(addr)), 4);
+    };
+    let low = u16::from_le_bytes([low[0], low[1]]) as u32;
+    if compressed::is_compressed(low) {
+        let text = disasm::disassemble(low, addr);
+        return (
+            format!("{}: {:04x}      {}", cpu.symbols.location(addr), low, text),
+            2,
+        );
+    }
+    match cpu.read_memory(addr.wrapping_add(2), 2) {
+        Ok(high) => {
+            let ins = (u16::from_le_bytes([high[0], high[1]]) as u32) << 16 | low;
+            let text = disasm::disassemble(ins, addr);
+            (
+                format!("{}: {:08x}  {}", cpu.symbols.location(addr), ins, text),
+                4,
+            )
+        }
+        Err(_) => (format!("{}: <unreadable>", cpu.symbols.location(addr)), 4),
+    }
+}
+
+/// A few instructions before `addr` and more after it, the pc marked with `=>`
+fn disassemble(cpu: &mut CPU, addr: u32, output: &mut impl Write) -> io::Result<()> {
+    let mut addr = listing_start(cpu, addr);
+    for _ in 0..DISAS_COUNT {
+        let (line, len) = instruction_line(cpu, addr);
+        let marker = if addr == cpu.pc() { "=>" } else { "  " };
+        writeln!(output, "{} {}", marker, line)?;
+        addr = addr.wrapping_add(len);
+    }
+    Ok(())
+}
+
+/// Where the listing around `addr` starts. With RVC the instructions before `addr` can't be
+/// counted back, so they are decoded forward from the start of the symbol `addr` is in. Without
+/// a symbol, or if that doesn't line up with `addr`, the listing starts at `addr`.
+fn listing_start(cpu: &mut CPU, addr: u32) -> u32 {
+    let Some(mut at) = cpu
+        .symbols
+        .resolve(addr)
+        .filter(|&(_, offset)| offset <= DISAS_WALK)
+        .map(|(symbol, _)| symbol.addr)
+    else {
+        return addr;
+    };
+    let mut starts = Vec::new();
+    while at < addr {
+        starts.push(at);
+        at = at.saturating_add(instruction_line(cpu, at).1);
+    }
+    match at == addr {
+        true => starts[starts.len().saturating_sub(DISAS_BEFORE)..]
+            .first()
+            .copied()
+            .unwrap_or(addr),
+        false => addr,
+    }
+}
+
+/// 16 bytes per line, with the printable ones on the right
+fn dump(cpu: &mut CPU, addr: u32, len: u32, output: &mut impl Write) -> io::Result<()> {
+    for line in (0..len).step_by(16) {
+        let start = addr.wrapping_add(line);
+        let bytes = match cpu.read_memory(start, (len - line).min(16)) {
+            Ok(bytes) => bytes,
+            Err(exception) => return writeln!(output, "{}", exception),
+        };
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x20..0x7F => byte as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(output, "0x{:08x}: {:<47}  |{}|", start, hex.join(" "), text)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linux::LinuxAbi;
+    use crate::testutil::SharedBuffer;
+    use std::cell::RefCell;
+    use std::io::{BufRead, Read};
+    use std::rc::Rc;
+
+    /// Input the prompt and the guest share like a terminal, borrowed for each read only
+    #[derive(Clone)]
+    struct SharedInput(Rc<RefCell<&'static [u8]>>);
+
+    impl Read for SharedInput {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+
+    #[test]
+    fn test_debug_commands() {
+        let mut cpu = CPU::new();
+        cpu.load_assembly(
+            "
+                li    a0, 5
+                li    a1, 7
+                lui   t0, 0x80001
+                sw    a1, 0(t0)
+                li    a7, 93
+                ecall
+            ",
+        )
+        .unwrap();
+
+        let input = "step 2\nregs\nbreak 0x80000014\nwatch 0x80001000\n\
+                     continue\nx 0x80001000 4\ncontinue\nclk\ncontinue\nquit\nstep\n";
+        let mut output = Vec::new();
+        let mut input = input.as_bytes();
+        Debugger::new()
+            .run(&mut cpu, |line| input.read_line(line), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("0x80000000: 00500513  li      a0,5\n"));
+        assert!(
+            output.contains("s0   0x00000000  s1   0x00000000  a0   0x00000005  a1   0x00000007\n")
+        );
+        assert!(output.contains("pc   0x80000008\n"));
+        assert!(output.contains("watchpoint on 4 bytes at 0x80001000\n"));
+        assert!(output.contains(
+            "watchpoint: stored 0x7 at 0x80001000\n0x80000010: 05d00893  li      a7,93\n"
+        ));
+        assert!(output.contains("0x80001000: 07 00 00 00"));
+        assert!(output.contains("breakpoint\n0x80000014: 00000073  ecall\n(rv32) 5\n"));
+        assert!(output.contains("program exited with code 5"));
+        // nothing runs after quit
+        assert_eq!(cpu.clk, 6);
+    }
+
+    #[test]
+    fn test_disas_compressed_code() {
+        let mut cpu = CPU::new();
+        cpu.load_assembly(
+            "
+            main:
+                li    a0, 1
+                .half 0x4515     # c.li a0, 5
+                li    a1, 2
+                .half 0x0001     # c.nop
+                li    a7, 93
+                ecall
+            ",
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        let mut input = "step 3\ndisas\nquit\n".as_bytes();
+        Debugger::new()
+            .run(&mut cpu, |line| input.read_line(line), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        // the listing starts at main, not 16 bytes before the pc
+        let listing = [
+            "   0x80000000 <main>: 00100513  li      a0,1",
+            "   0x80000004 <main+0x4>: 4515      li      a0,5",
+            "   0x80000006 <main+0x6>: 00200593  li      a1,2",
+            "=> 0x8000000a <main+0xa>: 0001      nop",
+        ]
+        .join("\n");
+        assert!(output.contains(&listing));
+    }
+
+    #[test]
+    fn test_guest_reads_while_debugging() {
+        let mut cpu = CPU::new();
+        cpu.load_assembly(
+            "
+                li    a0, 0
+                la    a1, buffer
+                li    a2, 6
+                li    a7, 63        # read
+                ecall
+                li    a0, 1
+                li    a7, 64        # write
+                ecall
+                li    a0, 0
+                li    a7, 93
+                ecall
+            .data
+            buffer: .zero 16
+            ",
+        )
+        .unwrap();
+        let input = SharedInput(Rc::new(RefCell::new(b"continue\nguest\nquit\n")));
+        let output = SharedBuffer::default();
+        cpu.set_syscall_abi(Box::new(LinuxAbi::with_io(
+            None,
+            Box::new(input.clone()),
+            Box::new(output.clone()),
+        )));
+
+        let mut prompt = Vec::new();
+        Debugger::new()
+            .run(
+                &mut cpu,
+                |line| input.0.borrow_mut().read_line(line),
+                &mut prompt,
+            )
+            .unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"guest\n");
+        let prompt = String::from_utf8(prompt).unwrap();
+        assert!(prompt.ends_with("program exited with code 0\n(rv32) "));
+    }
+}
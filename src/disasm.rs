@@ -0,0 +1,521 @@
+//! Disassembler producing objdump style text with ABI register names, e.g. `addi    a0,a0,-1`,
+//! `lw      a5,8(sp)` or `beq     a0,a1,80000010`. Pseudo-instructions stand in for the
+//! instructions they alias, like objdump prints them by default.
+
+use std::fmt;
+
+use crate::compressed;
+use crate::csr;
+use crate::instruction::{
+    RV5Instruction, RV5Itype, RV5Jtype, RV5R4type, RV5Rtype, RV5SBtype, RV5Stype, RVUtype,
+    REGISTER_NAMES,
+};
+
+/// ABI names of f0 to f31
+pub const FREGISTER_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// static rounding modes by their rm encoding, 7 is dynamic and not printed
+const ROUNDING_MODES: [&str; 5] = ["rne", "rtz", "rdn", "rup", "rmm"];
+
+/// Disassemble the raw instruction at `pc`, compressed ones print as the instruction they expand
+/// to. Anything that doesn't decode comes out as `.4byte`/`.2byte` data.
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    if compressed::is_compressed(instruction) {
+        let half = instruction as u16;
+        return compressed::expand(half)
+            .and_then(RV5Instruction::new)
+            .ok()
+            .and_then(|decoded| text(&decoded, Some(pc)))
+            .unwrap_or_else(|| format!(".2byte 0x{:04x}", half));
+    }
+    RV5Instruction::new(instruction)
+        .ok()
+        .and_then(|decoded| text(&decoded, Some(pc)))
+        .unwrap_or_else(|| format!(".4byte 0x{:08x}", instruction))
+}
+
+impl RV5Instruction {
+    /// Text of the instruction at `pc`, branch and jump targets as absolute addresses
+    pub fn disassemble(&self, pc: u32) -> String {
+        text(self, Some(pc)).unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+/// Without a pc, branch and jump targets are relative to the instruction: `j       .+16`
+impl fmt::Display for RV5Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match text(self, None) {
+            Some(text) => f.write_str(&text),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+/// `None` for field combinations that aren't an instruction
+fn text(instruction: &RV5Instruction, pc: Option<u32>) -> Option<String> {
+    match instruction {
+        RV5Instruction::R(r) => match r.opcode {
+            0b0101111 => amo(r),
+            0b1010011 => fp_op(r),
+            _ => rtype(r),
+        },
+        RV5Instruction::R4(r4) => fma(r4),
+        RV5Instruction::I(i) => itype(i),
+        RV5Instruction::S(s) => stype(s),
+        RV5Instruction::SB(sb) => branch(sb, pc),
+        RV5Instruction::U(u) => utype(u),
+        RV5Instruction::J(j) => jal(j, pc),
+        RV5Instruction::CSR(i) => csr_op(i),
+        RV5Instruction::ECALL => Some("ecall".to_string()),
+        RV5Instruction::EBREAK => Some("ebreak".to_string()),
+        RV5Instruction::MRET => Some("mret".to_string()),
+        RV5Instruction::SRET => Some("sret".to_string()),
+        RV5Instruction::WFI => Some("wfi".to_string()),
+        RV5Instruction::SFENCE(r) => Some(match (r.rs1, r.rs2) {
+            (0, 0) => "sfence.vma".to_string(),
+            (rs1, 0) => op("sfence.vma", x(rs1)),
+            (rs1, rs2) => op("sfence.vma", format!("{},{}", x(rs1), x(rs2))),
+        }),
+    }
+}
+
+/// Mnemonic padded to the operand column, as objdump's tab lines up
+fn op(mnemonic: &str, operands: impl AsRef<str>) -> String {
+    format!("{:<7} {}", mnemonic, operands.as_ref())
+}
+
+fn x(reg: u32) -> &'static str {
+    REGISTER_NAMES[reg as usize]
+}
+
+fn f(reg: u32) -> &'static str {
+    FREGISTER_NAMES[reg as usize]
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// Absolute target with a pc, `.+offset` without
+fn target(pc: Option<u32>, offset: i32) -> String {
+    match pc {
+        Some(pc) => format!("{:x}", pc.wrapping_add(offset as u32)),
+        None if offset >= 0 => format!(".+{}", offset),
+        None => format!(".{}", offset),
+    }
+}
+
+/// Static rounding mode operand, empty for the dynamic one
+fn rounding_mode(rm: u32) -> Option<String> {
+    match rm {
+        7 => Some(String::new()),
+        _ => ROUNDING_MODES
+            .get(rm as usize)
+            .map(|mode| format!(",{}", mode)),
+    }
+}
+
+/// `s` or `d` from the fmt field
+fn fp_format(fmt: u32) -> Option<&'static str> {
+    match fmt {
+        0 => Some("s"),
+        1 => Some("d"),
+        _ => None,
+    }
+}
+
+fn rtype(r: &RV5Rtype) -> Option<String> {
+    let (rd, rs1, rs2) = (x(r.rd), x(r.rs1), x(r.rs2));
+    let name = match (r.funct7, r.funct3) {
+        (0b0000000, 0b000) => "add",
+        (0b0100000, 0b000) if r.rs1 == 0 => return Some(op("neg", format!("{},{}", rd, rs2))),
+        (0b0100000, 0b000) => "sub",
+        (0b0000000, 0b001) => "sll",
+        (0b0000000, 0b010) if r.rs2 == 0 => return Some(op("sltz", format!("{},{}", rd, rs1))),
+        (0b0000000, 0b010) if r.rs1 == 0 => return Some(op("sgtz", format!("{},{}", rd, rs2))),
+        (0b0000000, 0b010) => "slt",
+        (0b0000000, 0b011) if r.rs1 == 0 => return Some(op("snez", format!("{},{}", rd, rs2))),
+        (0b0000000, 0b011) => "sltu",
+        (0b0000000, 0b100) => "xor",
+        (0b0000000, 0b101) => "srl",
+        (0b0100000, 0b101) => "sra",
+        (0b0000000, 0b110) => "or",
+        (0b0000000, 0b111) => "and",
+        (0b0000001, 0b000) => "mul",
+        (0b0000001, 0b001) => "mulh",
+        (0b0000001, 0b010) => "mulhsu",
+        (0b0000001, 0b011) => "mulhu",
+        (0b0000001, 0b100) => "div",
+        (0b0000001, 0b101) => "divu",
+        (0b0000001, 0b110) => "rem",
+        (0b0000001, 0b111) => "remu",
+        _ => return None,
+    };
+    Some(op(name, format!("{},{},{}", rd, rs1, rs2)))
+}
+
+fn amo(r: &RV5Rtype) -> Option<String> {
+    if r.funct3 != 0b010 {
+        return None;
+    }
+    let name = match r.funct7 >> 2 {
+        0b00010 if r.rs2 == 0 => "lr.w",
+        0b00011 => "sc.w",
+        0b00001 => "amoswap.w",
+        0b00000 => "amoadd.w",
+        0b00100 => "amoxor.w",
+        0b01100 => "amoand.w",
+        0b01000 => "amoor.w",
+        0b10000 => "amomin.w",
+        0b10100 => "amomax.w",
+        0b11000 => "amominu.w",
+        0b11100 => "amomaxu.w",
+        _ => return None,
+    };
+    let ordering = match r.funct7 & 0b11 {
+        0b10 => ".aq",
+        0b01 => ".rl",
+        0b11 => ".aqrl",
+        _ => "",
+    };
+    let name = format!("{}{}", name, ordering);
+    Some(match name.starts_with("lr") {
+        true => op(&name, format!("{},({})", x(r.rd), x(r.rs1))),
+        false => op(&name, format!("{},{},({})", x(r.rd), x(r.rs2), x(r.rs1))),
+    })
+}
+
+fn fp_op(r: &RV5Rtype) -> Option<String> {
+    let fmt = fp_format(r.funct7 & 0b11)?;
+    let (funct5, funct3) = (r.funct7 >> 2, r.funct3);
+    let (rd, rs1, rs2) = (r.rd, r.rs1, r.rs2);
+    let text = match (funct5, funct3, rs2) {
+        (0b00000..=0b00011, rm, _) => {
+            let name = ["fadd", "fsub", "fmul", "fdiv"][funct5 as usize];
+            op(
+                &format!("{}.{}", name, fmt),
+                format!("{},{},{}{}", f(rd), f(rs1), f(rs2), rounding_mode(rm)?),
+            )
+        }
+        (0b01011, rm, 0) => op(
+            &format!("fsqrt.{}", fmt),
+            format!("{},{}{}", f(rd), f(rs1), rounding_mode(rm)?),
+        ),
+        (0b00100, 0b000..=0b010, _) => {
+            // sign injection from the register itself is a move, negate or absolute value
+            let (name, alias) =
+                [("fsgnj", "fmv"), ("fsgnjn", "fneg"), ("fsgnjx", "fabs")][funct3 as usize];
+            match rs1 == rs2 {
+                true => op(
+                    &format!("{}.{}", alias, fmt),
+                    format!("{},{}", f(rd), f(rs1)),
+                ),
+                false => op(
+                    &format!("{}.{}", name, fmt),
+                    format!("{},{},{}", f(rd), f(rs1), f(rs2)),
+                ),
+            }
+        }
+        (0b00101, 0b000 | 0b001, _) => op(
+            &format!("{}.{}", ["fmin", "fmax"][funct3 as usize], fmt),
+            format!("{},{},{}", f(rd), f(rs1), f(rs2)),
+        ),
+        (0b01000, rm, 1) if fmt == "s" => op(
+            "fcvt.s.d",
+            format!("{},{}{}", f(rd), f(rs1), rounding_mode(rm)?),
+        ),
+        (0b01000, rm, 0) if fmt == "d" => op(
+            "fcvt.d.s",
+            format!("{},{}{}", f(rd), f(rs1), rounding_mode(rm)?),
+        ),
+        (0b10100, 0b000..=0b010, _) => op(
+            &format!("{}.{}", ["fle", "flt", "feq"][funct3 as usize], fmt),
+            format!("{},{},{}", x(rd), f(rs1), f(rs2)),
+        ),
+        (0b11000, rm, 0 | 1) => op(
+            &format!("fcvt.{}.{}", ["w", "wu"][rs2 as usize], fmt),
+            format!("{},{}{}", x(rd), f(rs1), rounding_mode(rm)?),
+        ),
+        (0b11010, rm, 0 | 1) => op(
+            &format!("fcvt.{}.{}", fmt, ["w", "wu"][rs2 as usize]),
+            format!("{},{}{}", f(rd), x(rs1), rounding_mode(rm)?),
+        ),
+        (0b11100, 0b000, 0) if fmt == "s" => op("fmv.x.w", format!("{},{}", x(rd), f(rs1))),
+        (0b11100, 0b001, 0) => op(&format!("fclass.{}", fmt), format!("{},{}", x(rd), f(rs1))),
+        (0b11110, 0b000, 0) if fmt == "s" => op("fmv.w.x", format!("{},{}", f(rd), x(rs1))),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn fma(r4: &RV5R4type) -> Option<String> {
+    let name = match r4.opcode {
+        0b1000011 => "fmadd",
+        0b1000111 => "fmsub",
+        0b1001011 => "fnmsub",
+        _ => "fnmadd",
+    };
+    Some(op(
+        &format!("{}.{}", name, fp_format(r4.funct2)?),
+        format!(
+            "{},{},{},{}{}",
+            f(r4.rd),
+            f(r4.rs1),
+            f(r4.rs2),
+            f(r4.rs3),
+            rounding_mode(r4.funct3)?
+        ),
+    ))
+}
+
+/// `pred`/`succ` of a fence, device input and output then memory reads and writes
+fn fence_set(bits: u32) -> String {
+    "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| bits & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn itype(i: &RV5Itype) -> Option<String> {
+    let imm = sign_extend(i.imm, 12);
+    let (rd, rs1) = (x(i.rd), x(i.rs1));
+    let text = match (i.opcode, i.funct3) {
+        (0b0000011, funct3) => {
+            let name = match funct3 {
+                0b000 => "lb",
+                0b001 => "lh",
+                0b010 => "lw",
+                0b100 => "lbu",
+                0b101 => "lhu",
+                _ => return None,
+            };
+            op(name, format!("{},{}({})", rd, imm, rs1))
+        }
+        (0b0000111, 0b010 | 0b011) => op(
+            ["flw", "fld"][i.funct3 as usize - 0b010],
+            format!("{},{}({})", f(i.rd), imm, rs1),
+        ),
+        (0b0010011, 0b000) => match (i.rd, i.rs1, imm) {
+            (0, 0, 0) => "nop".to_string(),
+            (_, 0, _) => op("li", format!("{},{}", rd, imm)),
+            (_, _, 0) => op("mv", format!("{},{}", rd, rs1)),
+            _ => op("addi", format!("{},{},{}", rd, rs1, imm)),
+        },
+        (0b0010011, 0b001) if i.imm >> 5 == 0 => {
+            op("slli", format!("{},{},0x{:x}", rd, rs1, i.imm))
+        }
+        (0b0010011, 0b101) => {
+            let name = match i.imm >> 5 {
+                0b0000000 => "srli",
+                0b0100000 => "srai",
+                _ => return None,
+            };
+            op(name, format!("{},{},0x{:x}", rd, rs1, i.imm & 0x1F))
+        }
+        (0b0010011, 0b011) if imm == 1 => op("seqz", format!("{},{}", rd, rs1)),
+        (0b0010011, 0b100) if imm == -1 => op("not", format!("{},{}", rd, rs1)),
+        (0b0010011, funct3) => {
+            let name = match funct3 {
+                0b010 => "slti",
+                0b011 => "sltiu",
+                0b100 => "xori",
+                0b110 => "ori",
+                0b111 => "andi",
+                _ => return None,
+            };
+            op(name, format!("{},{},{}", rd, rs1, imm))
+        }
+        (0b1100111, 0b000) => match (i.rd, i.rs1, imm) {
+            (0, 1, 0) => "ret".to_string(),
+            (0, _, 0) => op("jr", rs1),
+            (1, _, 0) => op("jalr", rs1),
+            _ => op("jalr", format!("{},{}({})", rd, imm, rs1)),
+        },
+        (0b0001111, 0b000) => {
+            let (pred, succ) = ((i.imm >> 4) & 0xF, i.imm & 0xF);
+            match (pred, succ) {
+                (0xF, 0xF) => "fence".to_string(),
+                _ => op("fence", format!("{},{}", fence_set(pred), fence_set(succ))),
+            }
+        }
+        (0b0001111, 0b001) => "fence.i".to_string(),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn stype(s: &RV5Stype) -> Option<String> {
+    let imm = sign_extend(s.imm, 12);
+    let base = x(s.rs1);
+    let text = match (s.opcode, s.funct3) {
+        (0b0100011, 0b000..=0b010) => op(
+            ["sb", "sh", "sw"][s.funct3 as usize],
+            format!("{},{}({})", x(s.rs2), imm, base),
+        ),
+        (0b0100111, 0b010 | 0b011) => op(
+            ["fsw", "fsd"][s.funct3 as usize - 0b010],
+            format!("{},{}({})", f(s.rs2), imm, base),
+        ),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn branch(sb: &RV5SBtype, pc: Option<u32>) -> Option<String> {
+    let target = target(pc, sign_extend(sb.imm, 13));
+    let (rs1, rs2) = (x(sb.rs1), x(sb.rs2));
+    // comparisons against zero
+    let alias = match (sb.funct3, sb.rs1, sb.rs2) {
+        (0b000, _, 0) => Some(("beqz", rs1)),
+        (0b001, _, 0) => Some(("bnez", rs1)),
+        (0b100, _, 0) => Some(("bltz", rs1)),
+        (0b101, _, 0) => Some(("bgez", rs1)),
+        (0b100, 0, _) => Some(("bgtz", rs2)),
+        (0b101, 0, _) => Some(("blez", rs2)),
+        _ => None,
+    };
+    if let Some((name, reg)) = alias {
+        return Some(op(name, format!("{},{}", reg, target)));
+    }
+    let name = match sb.funct3 {
+        0b000 => "beq",
+        0b001 => "bne",
+        0b100 => "blt",
+        0b101 => "bge",
+        0b110 => "bltu",
+        0b111 => "bgeu",
+        _ => return None,
+    };
+    Some(op(name, format!("{},{},{}", rs1, rs2, target)))
+}
+
+fn utype(u: &RVUtype) -> Option<String> {
+    let name = match u.opcode {
+        0b0110111 => "lui",
+        _ => "auipc",
+    };
+    Some(op(name, format!("{},0x{:x}", x(u.rd), u.imm20)))
+}
+
+fn jal(j: &RV5Jtype, pc: Option<u32>) -> Option<String> {
+    let target = target(pc, sign_extend(j.imm, 21));
+    Some(match j.rd {
+        0 => op("j", target),
+        1 => op("jal", target),
+        rd => op("jal", format!("{},{}", x(rd), target)),
+    })
+}
+
+fn csr_op(i: &RV5Itype) -> Option<String> {
+    let csr = csr::name(i.imm as u16).unwrap_or_else(|| format!("0x{:x}", i.imm));
+    let immediate = i.funct3 & 0b100 != 0;
+    let source = match immediate {
+        true => i.rs1.to_string(),
+        false => x(i.rs1).to_string(),
+    };
+    let name = match i.funct3 & 0b011 {
+        0b01 => "csrrw",
+        0b10 => "csrrs",
+        0b11 => "csrrc",
+        _ => return None,
+    };
+    // reads without a write, and writes dropping the old value
+    if name == "csrrs" && !immediate && i.rs1 == 0 {
+        return Some(op("csrr", format!("{},{}", x(i.rd), csr)));
+    }
+    if i.rd == 0 {
+        let alias = format!("csr{}{}", &name[4..], if immediate { "i" } else { "" });
+        return Some(op(&alias, format!("{},{}", csr, source)));
+    }
+    let name = format!("{}{}", name, if immediate { "i" } else { "" });
+    Some(op(&name, format!("{},{},{}", x(i.rd), csr, source)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_instructions() {
+        let pc = 0x8000_0000;
+        let cases = [
+            (0x00000013, "nop"),
+            (0x00500513, "li      a0,5"),
+            (0xfff00513, "li      a0,-1"),
+            (0x00058513, "mv      a0,a1"),
+            (0xfff50513, "addi    a0,a0,-1"),
+            (0x00c5f533, "and     a0,a1,a2"),
+            (0x40b00533, "neg     a0,a1"),
+            (0x0005a533, "sltz    a0,a1"),
+            (0x00b02533, "sgtz    a0,a1"),
+            (0x02c5c533, "div     a0,a1,a2"),
+            (0x00451513, "slli    a0,a0,0x4"),
+            (0x41f55513, "srai    a0,a0,0x1f"),
+            (0xfff54513, "not     a0,a0"),
+            (0x00812783, "lw      a5,8(sp)"),
+            (0xfea42e23, "sw      a0,-4(s0)"),
+            (0x800012b7, "lui     t0,0x80001"),
+            (0x00000517, "auipc   a0,0x0"),
+            (0x00b50863, "beq     a0,a1,80000010"),
+            (0xfe051ee3, "bnez    a0,7ffffffc"),
+            (0x0100006f, "j       80000010"),
+            (0xff9ff0ef, "jal     7ffffff8"),
+            (0x00008067, "ret"),
+            (0x00050067, "jr      a0"),
+            (0x008500e7, "jalr    ra,8(a0)"),
+            (0x0ff0000f, "fence"),
+            (0x0220000f, "fence   r,r"),
+            (0x00000073, "ecall"),
+            (0x30200073, "mret"),
+            (0x12000073, "sfence.vma"),
+        ];
+        for (instruction, text) in cases {
+            assert_eq!(disassemble(instruction, pc), text, "{:08x}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let cases = [
+            (0x34051573, "csrrw   a0,mscratch,a0"),
+            (0x30002573, "csrr    a0,mstatus"),
+            (0x30529073, "csrw    mtvec,t0"),
+            (0x3004f073, "csrci   mstatus,9"),
+            (0x7c002573, "csrr    a0,0x7c0"),
+            (0x100525af, "lr.w    a1,(a0)"),
+            (0x0cc5a52f, "amoswap.w.aq a0,a2,(a1)"),
+            (0x203170c3, "fmadd.s ft1,ft2,ft3,ft4"),
+            (0x00b57553, "fadd.s  fa0,fa0,fa1"),
+            (0xc0051553, "fcvt.w.s a0,fa0,rtz"),
+            (0x22a50553, "fmv.d   fa0,fa0"),
+            (0xa2b52553, "feq.d   a0,fa0,fa1"),
+            (0x00852507, "flw     fa0,8(a0)"),
+            (0x00a53427, "fsd     fa0,8(a0)"),
+            (0x4515, "li      a0,5"),
+            (0x8082, "ret"),
+            (0x0000, ".2byte 0x0000"),
+            (0xffffffff, ".4byte 0xffffffff"),
+        ];
+        for (instruction, text) in cases {
+            assert_eq!(disassemble(instruction, 0), text, "{:08x}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_display_without_pc() {
+        let branch = RV5Instruction::new(0xfe051ee3).unwrap();
+        assert_eq!(branch.to_string(), "bnez    a0,.-4");
+        assert_eq!(branch.disassemble(0x100), "bnez    a0,fc");
+        assert_eq!(
+            RV5Instruction::new(0x0100006f).unwrap().to_string(),
+            "j       .+16"
+        );
+        assert!(format!("{:?}", branch).starts_with("SB(RV5SBtype"));
+    }
+}
@@ -0,0 +1,873 @@
+//! IEEE 754 arithmetic for the F and D extensions.
+//!
+//! Host floats only round to nearest even and don't report exception flags, so every operation
+//! is done on the unpacked significand in a `u128`, exactly or with a sticky bit, and rounded
+//! once at the end with the requested rounding mode. Values are passed around as raw bits,
+//! single precision in the low 32 bits of a `u64`. Each operation returns the result together
+//! with the fflags it raised.
+
+// fflags bits
+pub const FLAG_NV: u32 = 1 << 4;
+pub const FLAG_DZ: u32 = 1 << 3;
+pub const FLAG_OF: u32 = 1 << 2;
+pub const FLAG_UF: u32 = 1 << 1;
+pub const FLAG_NX: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// round to nearest, ties to even
+    RNE,
+    /// round towards zero
+    RTZ,
+    /// round down (towards -inf)
+    RDN,
+    /// round up (towards +inf)
+    RUP,
+    /// round to nearest, ties to max magnitude
+    RMM,
+}
+
+impl RoundingMode {
+    /// Rounding mode encoded in the rm field / frm CSR, 5 and 6 are reserved and 7 (dynamic)
+    /// has to be resolved against frm by the caller
+    pub fn from_bits(rm: u32) -> Option<Self> {
+        match rm {
+            0 => Some(RoundingMode::RNE),
+            1 => Some(RoundingMode::RTZ),
+            2 => Some(RoundingMode::RDN),
+            3 => Some(RoundingMode::RUP),
+            4 => Some(RoundingMode::RMM),
+            _ => None,
+        }
+    }
+}
+
+/// Binary interchange format: single or double precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const SINGLE: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+pub const DOUBLE: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+/// Unpacked operand, finite values are `sig * 2^exp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    NaN { signaling: bool },
+    Inf { sign: bool },
+    Zero { sign: bool },
+    Finite { sign: bool, exp: i32, sig: u128 },
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    /// precision in bits, including the implicit one
+    fn precision(self) -> u32 {
+        self.frac_bits + 1
+    }
+
+    fn exp_max(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.exp_max() << self.frac_bits) | 1 << (self.frac_bits - 1)
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.pack_sign(sign) | self.exp_max() << self.frac_bits
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.pack_sign(sign) | (self.infinity(false) - 1)
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.pack_sign(sign)
+    }
+
+    fn pack_sign(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    fn sign(self, bits: u64) -> bool {
+        bits & self.sign_bit() != 0
+    }
+
+    fn biased_exp(self, bits: u64) -> u64 {
+        (bits >> self.frac_bits) & self.exp_max()
+    }
+
+    fn fraction(self, bits: u64) -> u64 {
+        bits & ((1 << self.frac_bits) - 1)
+    }
+
+    pub fn is_nan(self, bits: u64) -> bool {
+        self.biased_exp(bits) == self.exp_max() && self.fraction(bits) != 0
+    }
+
+    pub fn is_signaling_nan(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & (1 << (self.frac_bits - 1)) == 0
+    }
+
+    fn unpack(self, bits: u64) -> Value {
+        let sign = self.sign(bits);
+        let exp = self.biased_exp(bits);
+        let frac = self.fraction(bits);
+        if exp == self.exp_max() {
+            if frac == 0 {
+                Value::Inf { sign }
+            } else {
+                Value::NaN {
+                    signaling: self.is_signaling_nan(bits),
+                }
+            }
+        } else if exp == 0 {
+            if frac == 0 {
+                Value::Zero { sign }
+            } else {
+                Value::Finite {
+                    sign,
+                    exp: 1 - self.bias() - self.frac_bits as i32,
+                    sig: frac as u128,
+                }
+            }
+        } else {
+            Value::Finite {
+                sign,
+                exp: exp as i32 - self.bias() - self.frac_bits as i32,
+                sig: (frac | 1 << self.frac_bits) as u128,
+            }
+        }
+    }
+
+    /// Round `sig * 2^exp` to this format, `sig` must be non zero
+    fn round_pack(self, sign: bool, exp: i32, sig: u128, rm: RoundingMode) -> (u64, u32) {
+        let p = self.precision() as i32;
+        let emin = 1 - self.bias();
+        let emax = self.bias();
+        // exponent of the leading one
+        let e = exp + msb(sig) as i32;
+
+        // subnormals keep the quantum of the smallest normal exponent
+        let quantum = e.max(emin) - (p - 1);
+        let (mut kept, inexact) = round_at(sign, exp, sig, quantum, rm);
+        let mut quantum = quantum;
+        if kept >> p != 0 {
+            kept >>= 1;
+            quantum += 1;
+        }
+
+        let mut flags = 0;
+        if inexact {
+            flags |= FLAG_NX;
+            // tininess is detected after rounding, as if the exponent range was unbounded
+            if e < emin {
+                let (unbounded, _) = round_at(sign, exp, sig, e - (p - 1), rm);
+                let carried = unbounded >> p != 0;
+                if !(carried && e + 1 >= emin) {
+                    flags |= FLAG_UF;
+                }
+            }
+        }
+
+        if quantum + (p - 1) > emax {
+            let to_inf = match rm {
+                RoundingMode::RNE | RoundingMode::RMM => true,
+                RoundingMode::RTZ => false,
+                RoundingMode::RDN => sign,
+                RoundingMode::RUP => !sign,
+            };
+            let bits = if to_inf {
+                self.infinity(sign)
+            } else {
+                self.max_finite(sign)
+            };
+            return (bits, FLAG_OF | FLAG_NX);
+        }
+
+        let kept = kept as u64;
+        let bits = if kept >> (p - 1) == 0 {
+            // subnormal (or rounded down to zero)
+            self.pack_sign(sign) | kept
+        } else {
+            let biased = (quantum + (p - 1) + self.bias()) as u64;
+            self.pack_sign(sign) | biased << self.frac_bits | self.fraction(kept)
+        };
+        (bits, flags)
+    }
+
+    /// Canonical NaN result, invalid if any operand was a signaling NaN
+    fn propagate_nan(self, operands: &[u64]) -> (u64, u32) {
+        let flags = if operands.iter().any(|&x| self.is_signaling_nan(x)) {
+            FLAG_NV
+        } else {
+            0
+        };
+        (self.canonical_nan(), flags)
+    }
+}
+
+/// Index of the most significant set bit
+fn msb(x: u128) -> u32 {
+    127 - x.leading_zeros()
+}
+
+/// Shift right, OR-ing every bit shifted out into the lowest bit
+fn shift_right_jam(x: u128, n: u32) -> u128 {
+    if n == 0 {
+        x
+    } else if n >= 128 {
+        (x != 0) as u128
+    } else {
+        (x >> n) | ((x & ((1 << n) - 1)) != 0) as u128
+    }
+}
+
+/// Round `sig * 2^exp` to an integer multiple of `2^quantum`, returns the multiple and
+/// whether it was inexact
+fn round_at(sign: bool, exp: i32, sig: u128, quantum: i32, rm: RoundingMode) -> (u128, bool) {
+    let shift = quantum - exp;
+    if shift <= 0 {
+        return (sig << (-shift) as u32, false);
+    }
+    // keep two extra bits: the round bit and a sticky bit
+    let extended = if shift >= 2 {
+        shift_right_jam(sig, shift as u32 - 2)
+    } else {
+        sig << (2 - shift) as u32
+    };
+    let kept = extended >> 2;
+    let rest = extended & 0b11;
+    let round_up = match rm {
+        RoundingMode::RNE => rest > 0b10 || (rest == 0b10 && kept & 1 == 1),
+        RoundingMode::RMM => rest >= 0b10,
+        RoundingMode::RTZ => false,
+        RoundingMode::RDN => sign && rest != 0,
+        RoundingMode::RUP => !sign && rest != 0,
+    };
+    (kept + round_up as u128, rest != 0)
+}
+
+/// Exact sum of two finite values, the smaller one is jammed into a sticky bit when it falls
+/// far enough below the larger one that it can only affect rounding
+fn add_finite(a: (bool, i32, u128), b: (bool, i32, u128)) -> (bool, i32, u128) {
+    let (large, small) = if a.1 + msb(a.2) as i32 >= b.1 + msb(b.2) as i32 {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    // leading one of the larger operand at bit 124 leaves room for the carry
+    let left = 124 - msb(large.2);
+    let exp = large.1 - left as i32;
+    let large_sig = large.2 << left;
+    let small_shift = small.1 - exp;
+    let small_sig = if small_shift >= 0 {
+        small.2 << small_shift as u32
+    } else {
+        shift_right_jam(small.2, (-small_shift) as u32)
+    };
+
+    if large.0 == small.0 {
+        (large.0, exp, large_sig + small_sig)
+    } else if large_sig >= small_sig {
+        (large.0, exp, large_sig - small_sig)
+    } else {
+        (small.0, exp, small_sig - large_sig)
+    }
+}
+
+/// Sign of an exact zero sum of operands with different signs
+fn zero_sum_sign(rm: RoundingMode) -> bool {
+    rm == RoundingMode::RDN
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+    match (fmt.unpack(a), fmt.unpack(b)) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => fmt.propagate_nan(&[a, b]),
+        (Value::Inf { sign: sa }, Value::Inf { sign: sb }) if sa != sb => {
+            (fmt.canonical_nan(), FLAG_NV)
+        }
+        (Value::Inf { sign }, _) | (_, Value::Inf { sign }) => (fmt.infinity(sign), 0),
+        (Value::Zero { sign: sa }, Value::Zero { sign: sb }) => {
+            let sign = if sa == sb { sa } else { zero_sum_sign(rm) };
+            (fmt.zero(sign), 0)
+        }
+        (Value::Zero { .. }, Value::Finite { .. }) => (b, 0),
+        (Value::Finite { .. }, Value::Zero { .. }) => (a, 0),
+        (
+            Value::Finite {
+                sign: sa,
+                exp: ea,
+                sig: ma,
+            },
+            Value::Finite {
+                sign: sb,
+                exp: eb,
+                sig: mb,
+            },
+        ) => {
+            let (sign, exp, sig) = add_finite((sa, ea, ma), (sb, eb, mb));
+            if sig == 0 {
+                (fmt.zero(zero_sum_sign(rm)), 0)
+            } else {
+                fmt.round_pack(sign, exp, sig, rm)
+            }
+        }
+    }
+}
+
+pub fn sub(fmt: Format, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+    // negating a NaN doesn't matter, NaN results are canonical anyway
+    add(fmt, a, b ^ fmt.sign_bit(), rm)
+}
+
+pub fn mul(fmt: Format, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+    let sign = fmt.sign(a) != fmt.sign(b);
+    match (fmt.unpack(a), fmt.unpack(b)) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => fmt.propagate_nan(&[a, b]),
+        (Value::Inf { .. }, Value::Zero { .. }) | (Value::Zero { .. }, Value::Inf { .. }) => {
+            (fmt.canonical_nan(), FLAG_NV)
+        }
+        (Value::Inf { .. }, _) | (_, Value::Inf { .. }) => (fmt.infinity(sign), 0),
+        (Value::Zero { .. }, _) | (_, Value::Zero { .. }) => (fmt.zero(sign), 0),
+        (
+            Value::Finite {
+                exp: ea, sig: ma, ..
+            },
+            Value::Finite {
+                exp: eb, sig: mb, ..
+            },
+        ) => fmt.round_pack(sign, ea + eb, ma * mb, rm),
+    }
+}
+
+pub fn div(fmt: Format, a: u64, b: u64, rm: RoundingMode) -> (u64, u32) {
+    let sign = fmt.sign(a) != fmt.sign(b);
+    match (fmt.unpack(a), fmt.unpack(b)) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => fmt.propagate_nan(&[a, b]),
+        (Value::Inf { .. }, Value::Inf { .. }) | (Value::Zero { .. }, Value::Zero { .. }) => {
+            (fmt.canonical_nan(), FLAG_NV)
+        }
+        (Value::Inf { .. }, _) => (fmt.infinity(sign), 0),
+        (_, Value::Inf { .. }) | (Value::Zero { .. }, _) => (fmt.zero(sign), 0),
+        (Value::Finite { .. }, Value::Zero { .. }) => (fmt.infinity(sign), FLAG_DZ),
+        (
+            Value::Finite {
+                exp: ea, sig: ma, ..
+            },
+            Value::Finite {
+                exp: eb, sig: mb, ..
+            },
+        ) => {
+            // widen the dividend so the quotient has far more bits than the precision
+            let shift = 126 - msb(ma);
+            let dividend = ma << shift;
+            let quotient = dividend / mb;
+            let sticky = !dividend.is_multiple_of(mb) as u128;
+            fmt.round_pack(sign, ea - eb - shift as i32 - 1, quotient << 1 | sticky, rm)
+        }
+    }
+}
+
+pub fn sqrt(fmt: Format, a: u64, rm: RoundingMode) -> (u64, u32) {
+    match fmt.unpack(a) {
+        Value::NaN { .. } => fmt.propagate_nan(&[a]),
+        Value::Zero { .. } => (a, 0),
+        Value::Inf { sign: false } => (a, 0),
+        Value::Inf { sign: true } | Value::Finite { sign: true, .. } => {
+            (fmt.canonical_nan(), FLAG_NV)
+        }
+        Value::Finite { exp, sig, .. } => {
+            // make the exponent even and the radicand as wide as possible
+            let (mut exp, mut sig) = (exp, sig);
+            if exp & 1 != 0 {
+                exp -= 1;
+                sig <<= 1;
+            }
+            let shift = (126 - msb(sig)) & !1;
+            let radicand = sig << shift;
+            let (root, remainder) = isqrt(radicand);
+            let sticky = (remainder != 0) as u128;
+            let exp = (exp - shift as i32) / 2 - 1;
+            fmt.round_pack(false, exp, root << 1 | sticky, rm)
+        }
+    }
+}
+
+/// Integer square root, returns the root and the remainder
+fn isqrt(x: u128) -> (u128, u128) {
+    let mut remainder = x;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > x {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, remainder)
+}
+
+/// Fused `(a * b) + c` with a single rounding, the FMSUB/FNMSUB/FNMADD variants negate the
+/// product and/or the addend
+pub fn fma(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    c: u64,
+    negate_product: bool,
+    negate_addend: bool,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let product_sign = (fmt.sign(a) != fmt.sign(b)) != negate_product;
+    let c = if negate_addend { c ^ fmt.sign_bit() } else { c };
+    let (va, vb, vc) = (fmt.unpack(a), fmt.unpack(b), fmt.unpack(c));
+
+    // inf * 0 is invalid even when the addend is a quiet NaN
+    let invalid_product = matches!(
+        (va, vb),
+        (Value::Inf { .. }, Value::Zero { .. }) | (Value::Zero { .. }, Value::Inf { .. })
+    );
+    if invalid_product {
+        return (fmt.canonical_nan(), FLAG_NV);
+    }
+    if [va, vb, vc].iter().any(|v| matches!(v, Value::NaN { .. })) {
+        return fmt.propagate_nan(&[a, b, c]);
+    }
+
+    let product_inf = matches!(va, Value::Inf { .. }) || matches!(vb, Value::Inf { .. });
+    match vc {
+        Value::Inf { sign } if product_inf && sign != product_sign => {
+            return (fmt.canonical_nan(), FLAG_NV);
+        }
+        Value::Inf { sign } => return (fmt.infinity(sign), 0),
+        _ if product_inf => return (fmt.infinity(product_sign), 0),
+        _ => {}
+    }
+
+    let product = match (va, vb) {
+        (
+            Value::Finite {
+                exp: ea, sig: ma, ..
+            },
+            Value::Finite {
+                exp: eb, sig: mb, ..
+            },
+        ) => Some((product_sign, ea + eb, ma * mb)),
+        _ => None,
+    };
+    match (product, vc) {
+        (None, Value::Zero { sign }) => {
+            let sign = if sign == product_sign {
+                sign
+            } else {
+                zero_sum_sign(rm)
+            };
+            (fmt.zero(sign), 0)
+        }
+        (None, _) => (c, 0),
+        (Some((sign, exp, sig)), Value::Zero { .. }) => fmt.round_pack(sign, exp, sig, rm),
+        (Some(product), Value::Finite { sign, exp, sig }) => {
+            let (sign, exp, sig) = add_finite(product, (sign, exp, sig));
+            if sig == 0 {
+                (fmt.zero(zero_sum_sign(rm)), 0)
+            } else {
+                fmt.round_pack(sign, exp, sig, rm)
+            }
+        }
+        (Some(_), _) => unreachable!("NaN and infinite addends are handled above"),
+    }
+}
+
+/// FMIN/FMAX: a single NaN operand is ignored, -0 is smaller than +0
+pub fn min_max(fmt: Format, a: u64, b: u64, max: bool) -> (u64, u32) {
+    let flags = if fmt.is_signaling_nan(a) || fmt.is_signaling_nan(b) {
+        FLAG_NV
+    } else {
+        0
+    };
+    let result = match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ => {
+            let a_less = less_than(fmt, a, b) || (fmt.sign(a) && !fmt.sign(b));
+            if a_less != max {
+                a
+            } else {
+                b
+            }
+        }
+    };
+    (result, flags)
+}
+
+/// Ordered comparison of two non NaN values, +0 and -0 compare equal
+fn less_than(fmt: Format, a: u64, b: u64) -> bool {
+    let (sa, sb) = (fmt.sign(a), fmt.sign(b));
+    let magnitude = !fmt.sign_bit() & ((fmt.sign_bit() << 1) - 1);
+    let (ma, mb) = (a & magnitude, b & magnitude);
+    if ma == 0 && mb == 0 {
+        return false;
+    }
+    match (sa, sb) {
+        (false, false) => ma < mb,
+        (true, true) => ma > mb,
+        (true, false) => true,
+        (false, true) => false,
+    }
+}
+
+/// FEQ is a quiet comparison, only signaling NaNs raise invalid
+pub fn eq(fmt: Format, a: u64, b: u64) -> (bool, u32) {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        let signaling = fmt.is_signaling_nan(a) || fmt.is_signaling_nan(b);
+        return (false, if signaling { FLAG_NV } else { 0 });
+    }
+    let both_zero = (a | b) & !fmt.sign_bit() & ((fmt.sign_bit() << 1) - 1) == 0;
+    (a == b || both_zero, 0)
+}
+
+/// FLT is a signaling comparison, any NaN raises invalid
+pub fn lt(fmt: Format, a: u64, b: u64) -> (bool, u32) {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        return (false, FLAG_NV);
+    }
+    (less_than(fmt, a, b), 0)
+}
+
+/// FLE is a signaling comparison, any NaN raises invalid
+pub fn le(fmt: Format, a: u64, b: u64) -> (bool, u32) {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        return (false, FLAG_NV);
+    }
+    (!less_than(fmt, b, a), 0)
+}
+
+/// FCLASS: one hot mask of the operand class
+pub fn classify(fmt: Format, a: u64) -> u32 {
+    let sign = fmt.sign(a);
+    let subnormal = fmt.biased_exp(a) == 0 && fmt.fraction(a) != 0;
+    match fmt.unpack(a) {
+        Value::Inf { sign: true } => 1 << 0,
+        Value::Finite { .. } if sign && !subnormal => 1 << 1,
+        Value::Finite { .. } if sign => 1 << 2,
+        Value::Zero { sign: true } => 1 << 3,
+        Value::Zero { sign: false } => 1 << 4,
+        Value::Finite { .. } if subnormal => 1 << 5,
+        Value::Finite { .. } => 1 << 6,
+        Value::Inf { sign: false } => 1 << 7,
+        Value::NaN { signaling: true } => 1 << 8,
+        Value::NaN { signaling: false } => 1 << 9,
+    }
+}
+
+/// FSGNJ / FSGNJN / FSGNJX: magnitude of `a` with a sign built from both operands
+pub fn sign_inject(fmt: Format, a: u64, b: u64, funct3: u32) -> u64 {
+    let sign_bit = fmt.sign_bit();
+    let sign = match funct3 {
+        0b000 => b & sign_bit,
+        0b001 => !b & sign_bit,
+        _ => (a ^ b) & sign_bit,
+    };
+    (a & !sign_bit) | sign
+}
+
+/// FCVT.W[U].fmt: out of range values and NaN saturate and raise invalid
+pub fn to_int(fmt: Format, a: u64, signed: bool, rm: RoundingMode) -> (u32, u32) {
+    let (min, max): (i64, i64) = if signed {
+        (i32::MIN as i64, i32::MAX as i64)
+    } else {
+        (0, u32::MAX as i64)
+    };
+    let (sign, exp, sig) = match fmt.unpack(a) {
+        Value::NaN { .. } => return (max as u32, FLAG_NV),
+        Value::Inf { sign } => return ((if sign { min } else { max }) as u32, FLAG_NV),
+        Value::Zero { .. } => return (0, 0),
+        Value::Finite { sign, exp, sig } => (sign, exp, sig),
+    };
+
+    // anything at or above 2^64 is out of range no matter how it rounds
+    if exp + msb(sig) as i32 >= 64 {
+        return ((if sign { min } else { max }) as u32, FLAG_NV);
+    }
+    let (magnitude, inexact) = round_at(sign, exp, sig, 0, rm);
+    let value = if sign {
+        -(magnitude as i128)
+    } else {
+        magnitude as i128
+    };
+    if value < min as i128 || value > max as i128 {
+        return ((if sign { min } else { max }) as u32, FLAG_NV);
+    }
+    (value as i64 as u32, if inexact { FLAG_NX } else { 0 })
+}
+
+/// FCVT.fmt.W[U]
+pub fn from_int(fmt: Format, value: u32, signed: bool, rm: RoundingMode) -> (u64, u32) {
+    let (sign, magnitude) = if signed && (value as i32) < 0 {
+        (true, (value as i32).unsigned_abs())
+    } else {
+        (false, value)
+    };
+    if magnitude == 0 {
+        return (fmt.zero(false), 0);
+    }
+    fmt.round_pack(sign, 0, magnitude as u128, rm)
+}
+
+/// FCVT.S.D / FCVT.D.S
+pub fn convert(from: Format, to: Format, a: u64, rm: RoundingMode) -> (u64, u32) {
+    match from.unpack(a) {
+        Value::NaN { .. } => {
+            let (_, flags) = from.propagate_nan(&[a]);
+            (to.canonical_nan(), flags)
+        }
+        Value::Inf { sign } => (to.infinity(sign), 0),
+        Value::Zero { sign } => (to.zero(sign), 0),
+        Value::Finite { sign, exp, sig } => to.round_pack(sign, exp, sig, rm),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::RNE;
+
+    fn f32_bits(x: f32) -> u64 {
+        x.to_bits() as u64
+    }
+
+    /// xorshift, plenty for spreading bit patterns over every class of value
+    fn random_bits(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        // every other value gets an exponent near 1.0 or the subnormal range so that
+        // cancellation and underflow come up too
+        match *state % 4 {
+            0 => *state & 0x800F_FFFF_FFFF_FFFF | 0x3FF0_0000_0000_0000,
+            1 => *state & 0x800F_FFFF_8000_FFFF | 0x0010_0000_3F80_0000,
+            _ => *state,
+        }
+    }
+
+    #[test]
+    fn test_round_to_nearest_matches_host() {
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        for _ in 0..50_000 {
+            let (a, b, c) = (
+                random_bits(&mut state),
+                random_bits(&mut state),
+                random_bits(&mut state),
+            );
+            let (fa, fb, fc) = (f64::from_bits(a), f64::from_bits(b), f64::from_bits(c));
+            let checks = [
+                (add(DOUBLE, a, b, RNE).0, fa + fb),
+                (mul(DOUBLE, a, b, RNE).0, fa * fb),
+                (div(DOUBLE, a, b, RNE).0, fa / fb),
+                (sqrt(DOUBLE, a, RNE).0, fa.sqrt()),
+                (
+                    fma(DOUBLE, a, b, c, false, false, RNE).0,
+                    fa.mul_add(fb, fc),
+                ),
+            ];
+            for (ours, host) in checks {
+                if host.is_nan() {
+                    assert_eq!(ours, DOUBLE.canonical_nan());
+                } else {
+                    assert_eq!(ours, host.to_bits(), "{:e} {:e} {:e}", fa, fb, fc);
+                }
+            }
+
+            let (sa, sb, sc) = (a as u32, b as u32, c as u32);
+            let (fa, fb, fc) = (f32::from_bits(sa), f32::from_bits(sb), f32::from_bits(sc));
+            let (sa, sb, sc) = (sa as u64, sb as u64, sc as u64);
+            let checks = [
+                (add(SINGLE, sa, sb, RNE).0, fa + fb),
+                (mul(SINGLE, sa, sb, RNE).0, fa * fb),
+                (div(SINGLE, sa, sb, RNE).0, fa / fb),
+                (sqrt(SINGLE, sa, RNE).0, fa.sqrt()),
+                (
+                    fma(SINGLE, sa, sb, sc, false, false, RNE).0,
+                    fa.mul_add(fb, fc),
+                ),
+                (convert(DOUBLE, SINGLE, a, RNE).0, f64::from_bits(a) as f32),
+            ];
+            for (ours, host) in checks {
+                if host.is_nan() {
+                    assert_eq!(ours, SINGLE.canonical_nan());
+                } else {
+                    assert_eq!(ours, f32_bits(host), "{:e} {:e} {:e}", fa, fb, fc);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_directed_rounding() {
+        let one = f32_bits(1.0);
+        let tiny = f32_bits(2f32.powi(-30));
+        let next_up = f32_bits(1.0 + f32::EPSILON);
+        assert_eq!(add(SINGLE, one, tiny, RNE), (one, FLAG_NX));
+        assert_eq!(
+            add(SINGLE, one, tiny, RoundingMode::RUP),
+            (next_up, FLAG_NX)
+        );
+        assert_eq!(add(SINGLE, one, tiny, RoundingMode::RTZ), (one, FLAG_NX));
+
+        let minus_one = f32_bits(-1.0);
+        let next_down = f32_bits(-1.0 - f32::EPSILON);
+        assert_eq!(
+            sub(SINGLE, minus_one, tiny, RoundingMode::RDN),
+            (next_down, FLAG_NX)
+        );
+
+        // x - x is -0 only when rounding down
+        assert_eq!(sub(SINGLE, one, one, RNE), (0, 0));
+        assert_eq!(
+            sub(SINGLE, one, one, RoundingMode::RDN),
+            (f32_bits(-0.0), 0)
+        );
+
+        // 2.5 rounds to even or away from zero
+        let x = f32_bits(2.5);
+        assert_eq!(to_int(SINGLE, x, true, RNE), (2, FLAG_NX));
+        assert_eq!(to_int(SINGLE, x, true, RoundingMode::RMM), (3, FLAG_NX));
+        assert_eq!(to_int(SINGLE, x, true, RoundingMode::RDN), (2, FLAG_NX));
+    }
+
+    #[test]
+    fn test_exception_flags() {
+        let max = f32_bits(f32::MAX);
+        assert_eq!(
+            mul(SINGLE, max, f32_bits(2.0), RNE),
+            (f32_bits(f32::INFINITY), FLAG_OF | FLAG_NX)
+        );
+        assert_eq!(
+            mul(SINGLE, max, f32_bits(2.0), RoundingMode::RTZ),
+            (max, FLAG_OF | FLAG_NX)
+        );
+        assert_eq!(
+            div(SINGLE, f32_bits(1.0), 0, RNE),
+            (f32_bits(f32::INFINITY), FLAG_DZ)
+        );
+        assert_eq!(
+            sqrt(SINGLE, f32_bits(-1.0), RNE),
+            (SINGLE.canonical_nan(), FLAG_NV)
+        );
+        let min_normal = f32_bits(f32::MIN_POSITIVE);
+        assert_eq!(
+            mul(SINGLE, min_normal, f32_bits(0.75), RNE),
+            (f32_bits(f32::MIN_POSITIVE * 0.75), 0)
+        );
+        assert_eq!(
+            div(SINGLE, f32_bits(f32::MIN_POSITIVE), f32_bits(3.0), RNE).1,
+            FLAG_UF | FLAG_NX
+        );
+        let snan = 0x7f80_0001;
+        assert_eq!(
+            add(SINGLE, snan, f32_bits(1.0), RNE),
+            (SINGLE.canonical_nan(), FLAG_NV)
+        );
+        assert_eq!(
+            fma(
+                SINGLE,
+                f32_bits(f32::INFINITY),
+                0,
+                SINGLE.canonical_nan(),
+                false,
+                false,
+                RNE
+            ),
+            (SINGLE.canonical_nan(), FLAG_NV)
+        );
+    }
+
+    #[test]
+    fn test_integer_conversions() {
+        assert_eq!(to_int(SINGLE, f32_bits(-1.5), false, RNE), (0, FLAG_NV));
+        assert_eq!(
+            to_int(SINGLE, f32_bits(3e9), true, RNE),
+            (i32::MAX as u32, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(SINGLE, f32_bits(3e9), false, RNE),
+            (3_000_000_000, 0)
+        );
+        assert_eq!(
+            to_int(SINGLE, SINGLE.canonical_nan(), true, RNE),
+            (i32::MAX as u32, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(DOUBLE, (-2147483648.0f64).to_bits(), true, RNE),
+            (i32::MIN as u32, 0)
+        );
+        assert_eq!(
+            from_int(SINGLE, 0xFFFF_FFFF, false, RNE),
+            (f32_bits(4294967296.0), FLAG_NX)
+        );
+        assert_eq!(
+            from_int(SINGLE, 0xFFFF_FFFF, false, RoundingMode::RTZ),
+            (f32_bits(4294967040.0), FLAG_NX)
+        );
+        assert_eq!(
+            from_int(DOUBLE, -7i32 as u32, true, RNE),
+            ((-7.0f64).to_bits(), 0)
+        );
+    }
+
+    #[test]
+    fn test_min_max_compare_classify() {
+        let qnan = SINGLE.canonical_nan();
+        let (one, zero, neg_zero) = (f32_bits(1.0), f32_bits(0.0), f32_bits(-0.0));
+        assert_eq!(min_max(SINGLE, qnan, one, false), (one, 0));
+        assert_eq!(min_max(SINGLE, qnan, qnan, true), (qnan, 0));
+        assert_eq!(min_max(SINGLE, zero, neg_zero, false), (neg_zero, 0));
+        assert_eq!(min_max(SINGLE, neg_zero, zero, true), (zero, 0));
+
+        assert_eq!(eq(SINGLE, zero, neg_zero), (true, 0));
+        assert_eq!(eq(SINGLE, qnan, qnan), (false, 0));
+        assert_eq!(lt(SINGLE, qnan, one), (false, FLAG_NV));
+        assert_eq!(le(SINGLE, neg_zero, zero), (true, 0));
+        assert_eq!(lt(SINGLE, f32_bits(-2.0), f32_bits(-1.0)), (true, 0));
+
+        assert_eq!(classify(SINGLE, f32_bits(f32::NEG_INFINITY)), 1 << 0);
+        assert_eq!(classify(SINGLE, f32_bits(-1.0)), 1 << 1);
+        assert_eq!(classify(SINGLE, neg_zero), 1 << 3);
+        assert_eq!(classify(SINGLE, 1), 1 << 5);
+        assert_eq!(classify(SINGLE, 0x7f80_0001), 1 << 8);
+        assert_eq!(classify(SINGLE, qnan), 1 << 9);
+
+        assert_eq!(sign_inject(SINGLE, one, neg_zero, 0b000), f32_bits(-1.0));
+        assert_eq!(sign_inject(SINGLE, one, neg_zero, 0b001), one);
+        assert_eq!(sign_inject(SINGLE, f32_bits(-1.0), neg_zero, 0b010), one);
+    }
+}
@@ -0,0 +1,638 @@
+//! GDB remote serial protocol stub, so `riscv64-unknown-elf-gdb` can `target remote` a program
+//! running in the emulator. Covers x0-x31 and pc, memory, stepping, breakpoints and watchpoints.
+//!
+//! Breakpoints live on the host side instead of being patched into guest memory, so they work in
+//! ROM too. Watchpoints are checked against the loads and stores each instruction made.
+//!
+//! Memory reads from gdb go through the MMU and the bus like loads of the hart: reading a device
+//! register has the same side effects (a read of the UART receive register pops a byte) and the
+//! page walk sets the accessed bit of the PTEs it uses.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::cpu::CPU;
+use crate::instruction::REGISTER_NAMES;
+use crate::mmu::Access;
+use crate::trap::Exception;
+
+/// x0-x31 and pc
+const REGISTERS: usize = 33;
+/// instructions between checks for a ^C from gdb while continuing
+const POLL_INTERVAL: u32 = 0x1000;
+const INTERRUPT: u8 = 0x03;
+/// largest packet gdb may send, advertised in the qSupported reply
+const PACKET_SIZE: usize = 0x4000;
+/// most bytes an m or M packet can move, at two hex digits a byte
+const MAX_MEMORY_LEN: u32 = (PACKET_SIZE / 2) as u32;
+
+// gdb's own signal numbers, used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+/// A stream gdb is connected through
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchpoint {
+    addr: u32,
+    len: u32,
+    kind: WatchKind,
+}
+
+/// Why the target stopped
+#[derive(Debug)]
+enum Stop {
+    Step,
+    Breakpoint,
+    Watch(WatchKind, u32),
+    Interrupted,
+    Exception(Exception),
+    Exited(u32),
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Step => format!("S{:02x}", SIGTRAP),
+            Stop::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            Stop::Interrupted => format!("S{:02x}", SIGINT),
+            Stop::Exception(exception) => {
+                let signal = match exception {
+                    Exception::IllegalInstruction(_) => SIGILL,
+                    Exception::Breakpoint | Exception::EnvironmentCall(_) => SIGTRAP,
+                    Exception::InstructionAddressMisaligned(_)
+                    | Exception::LoadAddressMisaligned(_)
+                    | Exception::StoreAddressMisaligned(_) => SIGBUS,
+                    _ => SIGSEGV,
+                };
+                format!("S{:02x}", signal)
+            }
+            Stop::Exited(code) => format!("W{:02x}", code & 0xFF),
+        }
+    }
+}
+
+pub struct GdbStub<C: Connection> {
+    connection: C,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    /// a byte `interrupted` read that wasn't a ^C, the start of the next packet
+    pending: Option<u8>,
+}
+
+impl GdbStub<TcpStream> {
+    /// Wait for gdb to connect on `addr`, e.g. `localhost:1234`
+    pub fn accept_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl GdbStub<UnixStream> {
+    /// Wait for gdb to connect to a Unix socket created at `path`
+    pub fn accept_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Serve gdb until it detaches, kills the program or hangs up. The CPU only runs while gdb
+    /// asks it to.
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(cpu, &packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Next `$packet#checksum`, acknowledged. `None` once gdb hung up. Packets longer than
+    /// `PACKET_SIZE` are refused, the rest of them is skipped up to the next `$`.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        'packet: loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                // acks, and ^C while nothing is running
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(_) if data.len() == PACKET_SIZE => {
+                        self.connection.write_all(b"-")?;
+                        continue 'packet;
+                    }
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.connection.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(Self::checksum(&data));
+            if !valid {
+                self.connection.write_all(b"-")?;
+                continue;
+            }
+            self.connection.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.take() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        match self.connection.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::Interrupted => self.read_byte(),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", reply, Self::checksum(reply.as_bytes()));
+        self.connection.write_all(packet.as_bytes())?;
+        self.connection.flush()
+    }
+
+    /// Whether gdb sent a ^C, without waiting for it. Anything else is kept for `read_packet`.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.pending.is_some() {
+            return Ok(false);
+        }
+        self.connection.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.connection.read(&mut byte);
+        self.connection.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(true),
+            Ok(_) if byte[0] == INTERRUPT => Ok(true),
+            Ok(_) => {
+                self.pending = Some(byte[0]);
+                Ok(false)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reply to a packet, `None` ends the session
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at_checked(1).unwrap_or((packet, ""));
+        let reply = match command {
+            "?" => Stop::Step.reply(),
+            "g" => (0..REGISTERS)
+                .map(|reg| hex(&read_register(cpu, reg).to_le_bytes()))
+                .collect(),
+            "G" => match unhex(args) {
+                Some(bytes) if bytes.len() == 4 * REGISTERS => {
+                    for (reg, value) in bytes.chunks_exact(4).enumerate() {
+                        write_register(cpu, reg, u32::from_le_bytes(value.try_into().unwrap()));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < REGISTERS => hex(&read_register(cpu, reg).to_le_bytes()),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let value = unhex(value)?;
+                    (reg < REGISTERS && value.len() == 4)
+                        .then(|| (reg, u32::from_le_bytes(value.try_into().unwrap())))
+                });
+                match register {
+                    Some((reg, value)) => {
+                        write_register(cpu, reg, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) if len <= MAX_MEMORY_LEN => match cpu.read_memory(addr, len) {
+                    Ok(bytes) => hex(&bytes),
+                    Err(_) => "E14".to_string(),
+                },
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let data = unhex(data)?;
+                    (len <= MAX_MEMORY_LEN && data.len() == len as usize).then_some((addr, data))
+                });
+                match write {
+                    Some((addr, data)) => match cpu.write_memory(addr, &data) {
+                        Ok(()) => "OK".to_string(),
+                        Err(_) => "E14".to_string(),
+                    },
+                    None => "E01".to_string(),
+                }
+            }
+            // resuming at another address isn't supported, gdb writes pc instead
+            "s" => self.resume(cpu, true)?.reply(),
+            "c" => self.resume(cpu, false)?.reply(),
+            "Z" | "z" => self.set_point(command == "Z", args),
+            "D" => {
+                self.send("OK")?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+            // a single thread, whatever gdb selects
+            "H" => "OK".to_string(),
+            _ => self.query(packet),
+        };
+        Ok(Some(reply))
+    }
+
+    /// General queries, unknown ones get the empty "not supported" reply
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(range) else {
+                return "E01".to_string();
+            };
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    /// `Z`/`z` type,addr,kind: 0 and 1 are breakpoints, 2 to 4 write, read and access watchpoints
+    fn set_point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.splitn(3, ',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (u32::from_str_radix(addr, 16), u32::from_str_radix(len, 16))
+        else {
+            return "E01".to_string();
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                match insert {
+                    true => self.breakpoints.insert(addr),
+                    false => self.breakpoints.remove(&addr),
+                };
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.push(Watchpoint {
+                addr,
+                len,
+                kind: watch,
+            });
+        } else {
+            self.watchpoints
+                .retain(|w| !(w.addr == addr && w.len == len && w.kind == watch));
+        }
+        "OK".to_string()
+    }
+
+    /// Run one instruction, or until something stops the program. A breakpoint at the pc it
+    /// resumes from doesn't count, or it would never get past it.
+    fn resume(&mut self, cpu: &mut CPU, step: bool) -> io::Result<Stop> {
+        let mut executed = 0u32;
+        loop {
+            if cpu.is_exited() {
+                return Ok(Stop::Exited(cpu.exit_code.unwrap_or(0)));
+            }
+            if executed > 0 && self.breakpoints.contains(&cpu.pc()) {
+                return Ok(Stop::Breakpoint);
+            }
+            if let Err(exception) = cpu.execute_ins() {
+                return Ok(Stop::Exception(exception));
+            }
+            executed = executed.wrapping_add(1);
+            if let Some(stop) = self.watchpoint_hit(cpu) {
+                return Ok(stop);
+            }
+            if cpu.is_exited() {
+                return Ok(Stop::Exited(cpu.exit_code.unwrap_or(0)));
+            }
+            if step {
+                return Ok(Stop::Step);
+            }
+            if executed.is_multiple_of(POLL_INTERVAL) && self.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    fn watchpoint_hit(&self, cpu: &CPU) -> Option<Stop> {
+        cpu.memory_accesses().iter().find_map(|access| {
+            let (start, end) = (
+                access.addr as u64,
+                access.addr as u64 + access.size.byte_size() as u64,
+            );
+            self.watchpoints.iter().find_map(|watch| {
+                let overlaps =
+                    start < watch.addr as u64 + watch.len as u64 && (watch.addr as u64) < end;
+                let matches = match watch.kind {
+                    WatchKind::Write => access.access == Access::Store,
+                    WatchKind::Read => access.access == Access::Load,
+                    WatchKind::Access => true,
+                };
+                (overlaps && matches).then_some(Stop::Watch(watch.kind, watch.addr))
+            })
+        })
+    }
+}
+
+fn read_register(cpu: &CPU, reg: usize) -> u32 {
+    match reg {
+        32 => cpu.pc(),
+        _ => cpu.reg[reg],
+    }
+}
+
+/// Writes to x0 are dropped
+fn write_register(cpu: &mut CPU, reg: usize, value: u32) {
+    match reg {
+        0 => {}
+        32 => cpu.set_pc(value),
+        _ => cpu.reg[reg] = value,
+    }
+}
+
+/// `addr,len` in hex
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((
+        u32::from_str_radix(addr, 16).ok()?,
+        u32::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, byte| {
+        let _ = write!(s, "{:02x}", byte);
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Register layout gdb reads with `g`, the standard RISC-V core feature for rv32
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv32</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (regnum, name) in REGISTER_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "s0" => "data_ptr",
+            _ => "int",
+        };
+        let _ = writeln!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, regnum
+        );
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"32\"/>\n");
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Minimal gdb: sends packets and returns the replies
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, packet: &str) -> String {
+            let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.0, "${}#{:02x}", packet, checksum).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            // skip the ack, then read up to the checksum
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            self.0.read_exact(&mut [0; 2]).unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    /// gdb side that was written in advance, the replies are collected
+    struct Script {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_packet_framing() {
+        // a packet that is too long is refused, the stub picks up again at the next one
+        let oversized = format!("$m{}#00$?#3f", "0".repeat(PACKET_SIZE));
+        let mut stub = GdbStub::new(Script {
+            input: io::Cursor::new(oversized.into_bytes()),
+            output: Vec::new(),
+        });
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some("?"));
+        assert_eq!(stub.connection.output, b"-+");
+
+        // the start of a packet seen while checking for ^C isn't lost
+        let mut stub = GdbStub::new(Script {
+            input: io::Cursor::new(b"$?#3f".to_vec()),
+            output: Vec::new(),
+        });
+        assert!(!stub.interrupted().unwrap());
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some("?"));
+    }
+
+    #[test]
+    fn test_debug_session() {
+        let program: [u32; 6] = [
+            0x00500513, // li a0, 5
+            0x00700593, // li a1, 7
+            0x800012b7, // lui t0, 0x80001
+            0x00b2a023, // sw a1, 0(t0)
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPU::new();
+        let binary: Vec<u8> = program.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+        cpu.load_instructions(&binary);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gdb = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut gdb = Client(stream);
+            assert!(gdb
+                .request("qSupported:swbreak+")
+                .contains("qXfer:features:read+"));
+            let xml = gdb.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains("<reg name=\"a0\" bitsize=\"32\" type=\"int\" regnum=\"10\"/>"));
+            assert_eq!(gdb.request("?"), "S05");
+
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("pa"), "05000000");
+            assert_eq!(gdb.request("Z0,80000014,4"), "OK");
+            assert_eq!(gdb.request("Z2,80001000,4"), "OK");
+            assert_eq!(gdb.request("c"), "T05watch:80001000;");
+            // stopped after the store
+            let registers = gdb.request("g");
+            assert_eq!(&registers[32 * 8..], "10000080");
+            assert_eq!(gdb.request("m80001000,4"), "07000000");
+            assert_eq!(gdb.request("M80001000,4:2a000000"), "OK");
+            assert_eq!(gdb.request("m80001000,4"), "2a000000");
+            // more than fits in a packet
+            assert_eq!(gdb.request("m80001000,2001"), "E01");
+            assert_eq!(gdb.request("m80001000,ffffffff"), "E01");
+
+            assert_eq!(gdb.request("Pa=09000000"), "OK");
+            assert_eq!(gdb.request("c"), "T05swbreak:;");
+            assert_eq!(gdb.request("p20"), "14000080");
+            assert_eq!(gdb.request("c"), "W09");
+            write!(gdb.0, "$k#6b").unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        GdbStub::new(stream).run(&mut cpu).unwrap();
+        gdb.join().unwrap();
+        assert_eq!(cpu.exit_code, Some(9));
+        assert_eq!(cpu.bus.read_bytes(0x8000_1000, 4).unwrap(), [42, 0, 0, 0]);
+    }
+}
@@ -1,15 +1,34 @@
 //! ideally i wanted to have bit representation not bytes - should i use zig/c
 
+use crate::trap::Exception;
+
+/// ABI names of x0 to x31, as in the README register table
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+#[derive(Debug)]
 pub enum RV5Instruction {
     R(RV5Rtype),
+    /// FMADD / FMSUB / FNMSUB / FNMADD
+    R4(RV5R4type),
     I(RV5Itype),
     S(RV5Stype),
     SB(RV5SBtype),
     J(RV5Jtype),
     U(RVUtype),
+    /// Zicsr instructions, `imm` holds the CSR address and `rs1` the source register or uimm
+    CSR(RV5Itype),
     ECALL,
     EBREAK,
-    NOP,
+    MRET,
+    SRET,
+    /// `rs1` holds the virtual address and `rs2` the ASID to flush, x0 means all of them
+    SFENCE(RV5Rtype),
+    /// wait for interrupt, a hint that is fine to execute as a nop
+    WFI,
 }
 
 // | funct7  | rs2   | rs1   | funct3 | rd    | opcode |
@@ -25,6 +44,20 @@ pub struct RV5Rtype {
     pub opcode: u32,
 }
 
+// | rs3   | funct2 | rs2   | rs1   | funct3 | rd    | opcode |
+// |:-----:|:------:|:-----:|:-----:|:------:|:-----:|:------:|
+// | 5 bits| 2 bits | 5 bits| 5 bits| 3 bits | 5 bits| 7 bits |
+#[derive(Debug)]
+pub struct RV5R4type {
+    pub rs3: u32,
+    pub funct2: u32,
+    pub rs2: u32,
+    pub rs1: u32,
+    pub funct3: u32,
+    pub rd: u32,
+    pub opcode: u32,
+}
+
 // | imm[11:0]  | rs1   | funct3 | rd    | opcode |
 // |:----------:|:-----:|:------:|:-----:|:------:|
 // | 12 bits    | 5 bits| 3 bits | 5 bits| 7 bits |
@@ -52,6 +85,7 @@ pub struct RV5Stype {
 // | imm[12,10:5] | rs2   | rs1   | funct3 | imm[4:1,11] | opcode |
 // |:------------:|:-----:|:-----:|:------:|:-----------:|:------:|
 // | 7 bits       | 5 bits| 5 bits| 3 bits | 5 bits      | 7 bits |
+#[derive(Debug)]
 pub struct RV5SBtype {
     pub imm: u32,
     pub rs2: u32,
@@ -63,6 +97,7 @@ pub struct RV5SBtype {
 // |imm[20]|  imm[10:1]  |imm[11]| imm[19:12] | rd    | opcode |
 // |:-----:|:-----------:|:-----:|:----------:|:-----:|:------:|
 // |1 bits |   10 bits   | 1 bits| 8 bits     | 5 bits| 7 bits |
+#[derive(Debug)]
 pub struct RV5Jtype {
     pub imm: u32,
     pub rd: u32,
@@ -72,6 +107,7 @@ pub struct RV5Jtype {
 // | imm[31:12]     | rd    | opcode |
 // |:--------------:|:-----:|:------:|
 // |   20 bits      | 5 bits| 7 bits |
+#[derive(Debug)]
 pub struct RVUtype {
     pub imm20: u32,
     pub rd: u32,
@@ -79,18 +115,15 @@ pub struct RVUtype {
 }
 
 impl RV5Instruction {
-    pub fn new(instruction: u32) -> Self {
+    /// Decode a 32-bit instruction, unknown opcodes are reported as illegal instructions
+    pub fn new(instruction: u32) -> Result<Self, Exception> {
         if instruction == 0x00000073 {
-            println!("ecall");
-            return Self::ECALL;
-        } else if instruction == 0x00000000 {
-            println!("Encountered NOP or uninitialized memory.");
-            return Self::NOP;
+            return Ok(Self::ECALL);
         }
         let opcode = instruction & 0x7F; // bits 6-0
                                          // println!("ins: 0b{:07b} ", opcode);
-        match opcode {
-            0b0110011 => {
+        let decoded = match opcode {
+            0b0110011 | 0b0101111 | 0b1010011 => {
                 let funct7 = (instruction >> 25) & 0x7F; // bits 31-25
                 let rs2 = (instruction >> 20) & 0x1F; // bits 24-20
                 let rs1 = (instruction >> 15) & 0x1F; // bits 19-15
@@ -105,7 +138,18 @@ impl RV5Instruction {
                     opcode,
                 })
             }
-            0b0000011 | 0b0010011 | 0b1100111 => {
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => {
+                RV5Instruction::R4(RV5R4type {
+                    rs3: (instruction >> 27) & 0x1F,   // bits 31-27
+                    funct2: (instruction >> 25) & 0x3, // bits 26-25
+                    rs2: (instruction >> 20) & 0x1F,   // bits 24-20
+                    rs1: (instruction >> 15) & 0x1F,   // bits 19-15
+                    funct3: (instruction >> 12) & 0x7, // bits 14-12
+                    rd: (instruction >> 7) & 0x1F,     // bits 11-7
+                    opcode,
+                })
+            }
+            0b0000011 | 0b0010011 | 0b1100111 | 0b0001111 | 0b0000111 => {
                 let imm = (instruction >> 20) & 0xFFF; // bits 31-20
                 let rs1 = (instruction >> 15) & 0x1F; // bits 19-15
                 let funct3 = (instruction >> 12) & 0x7; // bits 14-12
@@ -118,7 +162,7 @@ impl RV5Instruction {
                     opcode,
                 })
             }
-            0b0100011 => {
+            0b0100011 | 0b0100111 => {
                 let imm_11_5 = (instruction >> 25) & 0x7F; // bits 31-25
                 let rs2 = (instruction >> 20) & 0x1F; // bits 24-20
                 let rs1 = (instruction >> 15) & 0x1F; // bits 19-15
@@ -154,7 +198,7 @@ impl RV5Instruction {
                 })
             }
             0b0010111 | 0b0110111 => {
-                let imm20 = (instruction >> 12) & 0x09; // bit 31-12
+                let imm20 = (instruction >> 12) & 0xFFFFF; // bit 31-12
                 let rd = (instruction >> 7) & 0x1F; // bits 11-7
                 let opcode = instruction & 0x7F; // bits 6-0
                 RV5Instruction::U(RVUtype { imm20, rd, opcode })
@@ -170,8 +214,90 @@ impl RV5Instruction {
 
                 RV5Instruction::J(RV5Jtype { imm, rd, opcode })
             }
-            0b1110011 => RV5Instruction::EBREAK,
-            _ => panic!("Unknown instruction"),
+            0b1110011 => {
+                let funct3 = (instruction >> 12) & 0x7; // bits 14-12
+                match funct3 {
+                    0b000 if instruction == 0x00100073 => RV5Instruction::EBREAK,
+                    0b000 if instruction == 0x30200073 => RV5Instruction::MRET,
+                    0b000 if instruction == 0x10200073 => RV5Instruction::SRET,
+                    0b000 if instruction == 0x10500073 => RV5Instruction::WFI,
+                    0b000 if instruction >> 25 == 0b0001001 && (instruction >> 7) & 0x1F == 0 => {
+                        RV5Instruction::SFENCE(RV5Rtype {
+                            funct7: instruction >> 25,
+                            rs2: (instruction >> 20) & 0x1F, // bits 24-20
+                            rs1: (instruction >> 15) & 0x1F, // bits 19-15
+                            funct3,
+                            rd: 0,
+                            opcode,
+                        })
+                    }
+                    0b001 | 0b010 | 0b011 | 0b101 | 0b110 | 0b111 => {
+                        RV5Instruction::CSR(RV5Itype {
+                            imm: (instruction >> 20) & 0xFFF, // bits 31-20
+                            rs1: (instruction >> 15) & 0x1F,  // bits 19-15
+                            funct3,
+                            rd: (instruction >> 7) & 0x1F, // bits 11-7
+                            opcode,
+                        })
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instruction)),
+                }
+            }
+            _ => return Err(Exception::IllegalInstruction(instruction)),
+        };
+        Ok(decoded)
+    }
+
+    /// Encode back into the 32-bit instruction `new` decodes, the inverse of `new`
+    pub fn encode(&self) -> u32 {
+        match self {
+            RV5Instruction::R(r) | RV5Instruction::SFENCE(r) => {
+                r.funct7 << 25 | r.rs2 << 20 | r.rs1 << 15 | r.funct3 << 12 | r.rd << 7 | r.opcode
+            }
+            RV5Instruction::R4(r4) => {
+                r4.rs3 << 27
+                    | r4.funct2 << 25
+                    | r4.rs2 << 20
+                    | r4.rs1 << 15
+                    | r4.funct3 << 12
+                    | r4.rd << 7
+                    | r4.opcode
+            }
+            RV5Instruction::I(i) | RV5Instruction::CSR(i) => {
+                (i.imm & 0xFFF) << 20 | i.rs1 << 15 | i.funct3 << 12 | i.rd << 7 | i.opcode
+            }
+            RV5Instruction::S(s) => {
+                (s.imm >> 5 & 0x7F) << 25
+                    | s.rs2 << 20
+                    | s.rs1 << 15
+                    | s.funct3 << 12
+                    | (s.imm & 0x1F) << 7
+                    | s.opcode
+            }
+            RV5Instruction::SB(sb) => {
+                (sb.imm >> 12 & 0x1) << 31
+                    | (sb.imm >> 5 & 0x3F) << 25
+                    | sb.rs2 << 20
+                    | sb.rs1 << 15
+                    | sb.funct3 << 12
+                    | (sb.imm >> 1 & 0xF) << 8
+                    | (sb.imm >> 11 & 0x1) << 7
+                    | sb.opcode
+            }
+            RV5Instruction::J(j) => {
+                (j.imm >> 20 & 0x1) << 31
+                    | (j.imm >> 1 & 0x3FF) << 21
+                    | (j.imm >> 11 & 0x1) << 20
+                    | (j.imm >> 12 & 0xFF) << 12
+                    | j.rd << 7
+                    | j.opcode
+            }
+            RV5Instruction::U(u) => (u.imm20 & 0xFFFFF) << 12 | u.rd << 7 | u.opcode,
+            RV5Instruction::ECALL => 0x00000073,
+            RV5Instruction::EBREAK => 0x00100073,
+            RV5Instruction::MRET => 0x30200073,
+            RV5Instruction::SRET => 0x10200073,
+            RV5Instruction::WFI => 0x10500073,
         }
     }
 }
@@ -182,7 +308,7 @@ mod tests {
     #[test]
     fn test_rv5_instruction_r() {
         let instruction = 0x15A04B3; // add x9, x20, x21
-        let rv5_instruction = RV5Instruction::new(instruction);
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
 
         match rv5_instruction {
             RV5Instruction::R(rv5_r_type) => {
@@ -200,7 +326,7 @@ mod tests {
     #[test]
     fn test_rv5_instruction_i() {
         let instruction = 0x3E813083; // ld x1, 1000(x2)
-        let rv5_instruction = RV5Instruction::new(instruction);
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
         match rv5_instruction {
             RV5Instruction::I(rv5_i_type) => {
                 assert_eq!(rv5_i_type.imm, 0b001111101000);
@@ -216,7 +342,7 @@ mod tests {
     #[test]
     fn test_rv5_instruction_s() {
         let instruction = 0x3E113423; // sd x1, 1000(x2)
-        let rv5_instruction = RV5Instruction::new(instruction);
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
         match rv5_instruction {
             RV5Instruction::S(rv5_s_type) => {
                 assert_eq!(rv5_s_type.imm, 0b001111101000);
@@ -232,7 +358,7 @@ mod tests {
     #[test]
     fn test_rv5_instruction_sb() {
         let instruction = 0x7CB51863; // bne x10, x11, 2000
-        let rv5_instruction = RV5Instruction::new(instruction);
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
         match rv5_instruction {
             RV5Instruction::SB(rv5_sb_type) => {
                 assert_eq!(rv5_sb_type.imm, 0b0011111010000);
@@ -244,4 +370,85 @@ mod tests {
             _ => panic!("Expected RV5SBtype"),
         }
     }
+
+    #[test]
+    fn test_rv5_instruction_u() {
+        let instruction = 0x800012B7; // lui x5, 0x80001
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
+        match rv5_instruction {
+            RV5Instruction::U(rv5_u_type) => {
+                assert_eq!(rv5_u_type.imm20, 0x80001);
+                assert_eq!(rv5_u_type.rd, 0b00101);
+                assert_eq!(rv5_u_type.opcode, 0b0110111);
+            }
+            _ => panic!("Expected RVUtype"),
+        }
+    }
+
+    #[test]
+    fn test_rv5_instruction_unknown_opcode() {
+        let instruction = 0xFFFFFFFF;
+        assert_eq!(
+            RV5Instruction::new(instruction).err(),
+            Some(Exception::IllegalInstruction(0xFFFFFFFF))
+        );
+    }
+
+    #[test]
+    fn test_rv5_instruction_r4() {
+        let instruction = 0x203170C3; // fmadd.s f1, f2, f3, f4
+        match RV5Instruction::new(instruction).unwrap() {
+            RV5Instruction::R4(rv5_r4_type) => {
+                assert_eq!(rv5_r4_type.rs3, 4);
+                assert_eq!(rv5_r4_type.funct2, 0);
+                assert_eq!(rv5_r4_type.rs2, 3);
+                assert_eq!(rv5_r4_type.rs1, 2);
+                assert_eq!(rv5_r4_type.funct3, 0b111);
+                assert_eq!(rv5_r4_type.rd, 1);
+            }
+            _ => panic!("Expected R4"),
+        }
+    }
+
+    #[test]
+    fn test_rv5_instruction_csr() {
+        let instruction = 0x34051573; // csrrw x10, mscratch, x10
+        let rv5_instruction = RV5Instruction::new(instruction).unwrap();
+        match rv5_instruction {
+            RV5Instruction::CSR(rv5_i_type) => {
+                assert_eq!(rv5_i_type.imm, 0x340);
+                assert_eq!(rv5_i_type.rs1, 0b01010);
+                assert_eq!(rv5_i_type.funct3, 0b001);
+                assert_eq!(rv5_i_type.rd, 0b01010);
+            }
+            _ => panic!("Expected CSR"),
+        }
+        assert!(matches!(
+            RV5Instruction::new(0x00100073),
+            Ok(RV5Instruction::EBREAK)
+        ));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let instructions = [
+            0x015A04B3, // add x9, x20, x21
+            0xfff50513, // addi a0, a0, -1
+            0xfea42e23, // sw a0, -4(s0)
+            0x7CB51863, // bne x10, x11, 2000
+            0xfe051ee3, // bnez a0, .-4
+            0xff9ff0ef, // jal .-8
+            0x800012B7, // lui x5, 0x80001
+            0x203170C3, // fmadd.s f1, f2, f3, f4
+            0x34051573, // csrrw x10, mscratch, x10
+            0x0cc5a52f, // amoswap.w.aq a0, a2, (a1)
+            0x12b50073, // sfence.vma a0, a1
+            0x00000073, // ecall
+            0x30200073, // mret
+        ];
+        for instruction in instructions {
+            let decoded = RV5Instruction::new(instruction).unwrap();
+            assert_eq!(decoded.encode(), instruction, "{:?}", decoded);
+        }
+    }
 }
@@ -1,3 +1,26 @@
+pub mod asm;
+pub mod bus;
+pub mod clint;
+pub mod compressed;
+pub mod config;
 pub mod cpu;
+pub mod csr;
+pub mod debugger;
+pub mod disasm;
+pub mod fpu;
+pub mod gdbstub;
 pub mod instruction;
+pub mod linux;
+pub mod loader;
+pub mod mmu;
+pub mod plic;
+pub mod pmp;
 pub mod ram;
+pub mod symbols;
+pub mod syscall;
+pub mod trace;
+pub mod trap;
+pub mod uart;
+
+#[cfg(test)]
+mod testutil;
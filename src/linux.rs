@@ -0,0 +1,586 @@
+//! Linux rv32 user-mode syscalls, the subset newlib and picolibc programs need: console and file
+//! I/O, brk for malloc, exit and the clocks. Errors come back as -errno in a0.
+//!
+//! Guest paths are resolved under a host directory that acts as the guest's `/`, `..` can't climb
+//! out of it and neither can symlinks pointing elsewhere. Without a root every open fails with
+//! EACCES, only the standard streams are there.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cpu::CPU;
+use crate::syscall::SyscallAbi;
+use crate::trap::Exception;
+
+// syscall numbers, from the asm-generic table rv32 uses
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_CLOCK_GETTIME: u32 = 113;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_BRK: u32 = 214;
+/// clock_gettime with a 64-bit tv_sec, what rv32 libcs actually call
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+// errno values
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const EMFILE: i32 = 24;
+const ESPIPE: i32 = 29;
+const ENAMETOOLONG: i32 = 36;
+const ENOSYS: i32 = 38;
+const EOVERFLOW: i32 = 75;
+
+// openat
+const AT_FDCWD: i32 = -100;
+const O_ACCMODE: u32 = 0b11;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+// st_mode file types
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const CLOCK_REALTIME: u32 = 0;
+const SEEK_SET: u32 = 0;
+const SEEK_CUR: u32 = 1;
+const SEEK_END: u32 = 2;
+
+const MAX_FILES: usize = 64;
+const PATH_MAX: usize = 4096;
+/// most bytes a single read or write moves, the guest handles the short count
+const MAX_TRANSFER: u32 = 1 << 20;
+const PAGE_SIZE: u32 = 4096;
+/// `struct stat` as riscv-pk fills it in and newlib's libgloss reads it
+const STAT_SIZE: usize = 128;
+
+/// syscall result or errno
+type SyscallResult = Result<u32, i32>;
+
+enum Descriptor {
+    Input(Box<dyn Read>),
+    Output(Box<dyn Write>),
+    File(File),
+}
+
+pub struct LinuxAbi {
+    /// host directory that is `/` for the guest
+    root: Option<PathBuf>,
+    /// open files, indexed by fd
+    files: Vec<Option<Descriptor>>,
+    /// heap bounds, the heap starts on the page after the loaded image
+    brk_start: u32,
+    brk: u32,
+    /// origin of CLOCK_MONOTONIC
+    started: Instant,
+}
+
+impl LinuxAbi {
+    /// Syscalls on the host stdin, stdout and stderr, with files under `root`
+    pub fn new(root: Option<PathBuf>) -> Self {
+        Self::with_io(root, Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    /// Syscalls with fd 0 reading `input` and fd 1 writing `output`, fd 2 stays the host stderr
+    pub fn with_io(root: Option<PathBuf>, input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self {
+            root,
+            files: vec![
+                Some(Descriptor::Input(input)),
+                Some(Descriptor::Output(output)),
+                Some(Descriptor::Output(Box::new(io::stderr()))),
+            ],
+            brk_start: 0,
+            brk: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn file(&mut self, fd: u32) -> Result<&mut Descriptor, i32> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+
+    /// Host path of a guest path, `..` stops at the guest's `/`
+    fn resolve(&self, path: &str) -> Result<PathBuf, i32> {
+        let root = self.root.as_ref().ok_or(EACCES)?;
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::ParentDir => {
+                    relative.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        let host_path = root.join(relative);
+
+        // symlinks are followed by the host, so check where they really lead and open that
+        // path. A file that doesn't exist yet is checked through its directory, and a dangling
+        // symlink in its place is refused since creating it would write wherever it points.
+        let root = root.canonicalize().map_err(errno)?;
+        let real_path = match host_path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => {
+                let name = host_path.file_name().ok_or(ENOENT)?;
+                let parent = host_path.parent().ok_or(ENOENT)?;
+                let path = parent.canonicalize().map_err(errno)?.join(name);
+                if path
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.is_symlink())
+                {
+                    return Err(EACCES);
+                }
+                path
+            }
+        };
+        if !real_path.starts_with(&root) {
+            return Err(EACCES);
+        }
+        Ok(real_path)
+    }
+
+    fn read(&mut self, cpu: &mut CPU, fd: u32, buf: u32, count: u32) -> SyscallResult {
+        let mut data = vec![0; count.min(MAX_TRANSFER) as usize];
+        let len = match self.file(fd)? {
+            Descriptor::Input(input) => input.read(&mut data),
+            Descriptor::File(file) => file.read(&mut data),
+            Descriptor::Output(_) => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        cpu.write_memory(buf, &data[..len]).map_err(|_| EFAULT)?;
+        Ok(len as u32)
+    }
+
+    fn write(&mut self, cpu: &mut CPU, fd: u32, buf: u32, count: u32) -> SyscallResult {
+        let data = cpu
+            .read_memory(buf, count.min(MAX_TRANSFER))
+            .map_err(|_| EFAULT)?;
+        let len = match self.file(fd)? {
+            Descriptor::Output(output) => output
+                .write_all(&data)
+                .and_then(|()| output.flush())
+                .map(|()| data.len()),
+            Descriptor::File(file) => file.write(&data),
+            Descriptor::Input(_) => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        Ok(len as u32)
+    }
+
+    /// The guest's working directory is `/`, so only absolute paths may come with a directory
+    /// fd. File permissions for new files come from the host umask.
+    fn openat(&mut self, cpu: &mut CPU, dirfd: i32, path: u32, flags: u32) -> SyscallResult {
+        let path = read_path(cpu, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let host_path = self.resolve(&path)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            match flags & O_EXCL {
+                0 => options.create(true),
+                _ => options.create_new(true),
+            };
+        }
+        let file = options.open(host_path).map_err(errno)?;
+
+        // lowest free descriptor, like the kernel
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(EMFILE),
+        };
+        self.files[fd] = Some(Descriptor::File(file));
+        Ok(fd as u32)
+    }
+
+    fn close(&mut self, fd: u32) -> SyscallResult {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .map(|_| 0)
+            .ok_or(EBADF)
+    }
+
+    /// The three argument lseek newlib calls, offsets are 32 bits
+    fn lseek(&mut self, fd: u32, offset: i32, whence: u32) -> SyscallResult {
+        let Descriptor::File(file) = self.file(fd)? else {
+            return Err(ESPIPE);
+        };
+        let position = match whence {
+            SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+            SEEK_CUR => SeekFrom::Current(offset as i64),
+            SEEK_END => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        let position = file.seek(position).map_err(errno)?;
+        u32::try_from(position)
+            .ok()
+            .filter(|&position| position <= i32::MAX as u32)
+            .ok_or(EOVERFLOW)
+    }
+
+    fn fstat(&mut self, cpu: &mut CPU, fd: u32, buf: u32) -> SyscallResult {
+        let (mode, size, mtime) = match self.file(fd)? {
+            Descriptor::File(file) => {
+                let metadata = file.metadata().map_err(errno)?;
+                let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+                    (true, _) => S_IFDIR | 0o755,
+                    (false, true) => S_IFREG | 0o444,
+                    (false, false) => S_IFREG | 0o644,
+                };
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                (mode, metadata.len(), mtime)
+            }
+            _ => (S_IFCHR | 0o620, 0, Duration::ZERO),
+        };
+
+        // 64-bit st_dev and st_ino at 0 and 8, then 32-bit st_mode, st_nlink, st_uid and st_gid,
+        // 64-bit st_rdev and padding, st_size at 48, 32-bit st_blksize at 56, st_blocks at 64
+        // and 64-bit second/nanosecond pairs for the three times from 72 on
+        let mut stat = [0u8; STAT_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(16, &mode.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(48, &size.to_le_bytes());
+        put(56, &PAGE_SIZE.to_le_bytes());
+        put(64, &size.div_ceil(512).to_le_bytes());
+        for offset in [72, 88, 104] {
+            put(offset, &mtime.as_secs().to_le_bytes());
+            put(offset + 8, &(mtime.subsec_nanos() as u64).to_le_bytes());
+        }
+        cpu.write_memory(buf, &stat).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    /// Move the end of the heap. Out of range or unbacked requests leave it where it is, which
+    /// is how the guest learns it failed.
+    fn brk(&mut self, cpu: &mut CPU, addr: u32) -> u32 {
+        if addr < self.brk_start || !cpu.bus.is_mapped(self.brk_start, addr - self.brk_start) {
+            return self.brk;
+        }
+        if addr > self.brk {
+            // memory given back and taken again reads as zero, like fresh pages
+            let zeros = vec![0; (addr - self.brk) as usize];
+            if cpu.bus.load_image(self.brk, &zeros).is_err() {
+                return self.brk;
+            }
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    fn clock(&self, clock: u32) -> Duration {
+        match clock {
+            CLOCK_REALTIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            _ => self.started.elapsed(),
+        }
+    }
+
+    /// `struct timespec` with a 32 or 64-bit tv_sec, tv_nsec is as wide
+    fn clock_gettime(&self, cpu: &mut CPU, clock: u32, tp: u32, time64: bool) -> SyscallResult {
+        let time = self.clock(clock);
+        let timespec = match time64 {
+            true => [
+                time.as_secs().to_le_bytes(),
+                (time.subsec_nanos() as u64).to_le_bytes(),
+            ]
+            .concat(),
+            false => [
+                (time.as_secs() as u32).to_le_bytes(),
+                time.subsec_nanos().to_le_bytes(),
+            ]
+            .concat(),
+        };
+        cpu.write_memory(tp, &timespec).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    /// 32-bit `struct timeval`, the timezone argument is ignored
+    fn gettimeofday(&self, cpu: &mut CPU, tv: u32) -> SyscallResult {
+        if tv != 0 {
+            let time = self.clock(CLOCK_REALTIME);
+            let timeval = [
+                (time.as_secs() as u32).to_le_bytes(),
+                time.subsec_micros().to_le_bytes(),
+            ]
+            .concat();
+            cpu.write_memory(tv, &timeval).map_err(|_| EFAULT)?;
+        }
+        Ok(0)
+    }
+}
+
+impl SyscallAbi for LinuxAbi {
+    /// Put the heap after the image and give the program an initial stack at the top of RAM:
+    /// argc 0, empty argv and envp and an empty auxiliary vector
+    fn start(&mut self, cpu: &mut CPU, image_end: u32) {
+        self.brk_start = image_end
+            .checked_next_multiple_of(PAGE_SIZE)
+            .unwrap_or(image_end);
+        self.brk = self.brk_start;
+
+        let sp = ((cpu.config.ram_end() & !0xF) - 32) as u32;
+        if cpu.bus.load_image(sp, &[0; 32]).is_ok() {
+            cpu.reg[2] = sp;
+        }
+    }
+
+    /// Unknown numbers fail with ENOSYS rather than stopping the program
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<(), Exception> {
+        let [a0, a1, a2] = [cpu.reg[10], cpu.reg[11], cpu.reg[12]];
+        let result = match cpu.reg[17] {
+            SYS_READ => self.read(cpu, a0, a1, a2),
+            SYS_WRITE => self.write(cpu, a0, a1, a2),
+            SYS_OPENAT => self.openat(cpu, a0 as i32, a1, a2),
+            SYS_CLOSE => self.close(a0),
+            SYS_LSEEK => self.lseek(a0, a1 as i32, a2),
+            SYS_FSTAT => self.fstat(cpu, a0, a1),
+            SYS_BRK => Ok(self.brk(cpu, a0)),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                cpu.exit_code = Some(a0);
+                cpu.exited = true;
+                return Ok(());
+            }
+            SYS_CLOCK_GETTIME => self.clock_gettime(cpu, a0, a1, false),
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(cpu, a0, a1, true),
+            SYS_GETTIMEOFDAY => self.gettimeofday(cpu, a0),
+            _ => Err(ENOSYS),
+        };
+        cpu.reg[10] = result.unwrap_or_else(|errno| errno.wrapping_neg() as u32);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LinuxAbi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LinuxAbi")
+            .field("root", &self.root)
+            .field("open_files", &self.files.iter().flatten().count())
+            .field("brk", &format_args!("0x{:08x}", self.brk))
+            .finish()
+    }
+}
+
+fn errno(error: io::Error) -> i32 {
+    match error.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => EISDIR,
+        ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+/// NUL terminated path from guest memory
+fn read_path(cpu: &mut CPU, addr: u32) -> Result<String, i32> {
+    let mut path = Vec::new();
+    loop {
+        let byte = cpu
+            .read_memory(addr.wrapping_add(path.len() as u32), 1)
+            .map_err(|_| EFAULT)?[0];
+        if byte == 0 {
+            break;
+        }
+        if path.len() == PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        path.push(byte);
+    }
+    String::from_utf8(path).map_err(|_| ENOENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SharedBuffer;
+    use std::fs;
+
+    const DATA: u32 = 0x8000_1000;
+
+    /// Run syscall `number` and return a0
+    fn syscall(abi: &mut LinuxAbi, cpu: &mut CPU, number: u32, args: &[u32]) -> i32 {
+        cpu.reg[17] = number;
+        cpu.reg[10..10 + args.len()].copy_from_slice(args);
+        abi.syscall(cpu).unwrap();
+        cpu.reg[10] as i32
+    }
+
+    #[test]
+    fn test_console_and_exit() {
+        let output = SharedBuffer::default();
+        let input: &[u8] = b"typed";
+        let mut abi = LinuxAbi::with_io(None, Box::new(input), Box::new(output.clone()));
+        let mut cpu = CPU::new();
+
+        cpu.write_memory(DATA, b"hello\n").unwrap();
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_WRITE, &[1, DATA, 6]), 6);
+        assert_eq!(output.0.borrow().as_slice(), b"hello\n");
+
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_READ, &[0, DATA, 16]), 5);
+        assert_eq!(cpu.read_memory(DATA, 5).unwrap(), b"typed");
+
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_WRITE, &[7, DATA, 1]),
+            -EBADF
+        );
+        assert_eq!(syscall(&mut abi, &mut cpu, 1234, &[]), -ENOSYS);
+        // no root, no files
+        cpu.write_memory(DATA, b"/etc/passwd\0").unwrap();
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u32, DATA, 0]),
+            -EACCES
+        );
+
+        syscall(&mut abi, &mut cpu, SYS_EXIT_GROUP, &[3]);
+        assert!(cpu.exited);
+        assert_eq!(cpu.exit_code, Some(3));
+    }
+
+    #[test]
+    fn test_sandboxed_files() {
+        let root = std::env::temp_dir().join(format!("rv32i-linux-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("input.txt"), b"from the host").unwrap();
+        let mut abi = LinuxAbi::new(Some(root.clone()));
+        let mut cpu = CPU::new();
+        let openat = |abi: &mut LinuxAbi, cpu: &mut CPU, path: &str, flags: u32| {
+            cpu.write_memory(DATA, format!("{path}\0").as_bytes())
+                .unwrap();
+            syscall(abi, cpu, SYS_OPENAT, &[AT_FDCWD as u32, DATA, flags])
+        };
+
+        // `..` stops at the root
+        let fd = openat(&mut abi, &mut cpu, "/../../input.txt", 0);
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_READ, &[3, DATA, 4]), 4);
+        assert_eq!(cpu.read_memory(DATA, 4).unwrap(), b"from");
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_LSEEK, &[3, -4i32 as u32, SEEK_END]),
+            9
+        );
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_FSTAT, &[3, DATA]), 0);
+        let stat = cpu.read_memory(DATA, STAT_SIZE as u32).unwrap();
+        assert_eq!(stat[16..20], (S_IFREG | 0o644).to_le_bytes());
+        assert_eq!(stat[48..56], 13u64.to_le_bytes());
+
+        let flags = O_WRONLY | O_CREAT | O_TRUNC;
+        assert_eq!(openat(&mut abi, &mut cpu, "out.txt", flags), 4);
+        cpu.write_memory(DATA, b"written").unwrap();
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_WRITE, &[4, DATA, 7]), 7);
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_CLOSE, &[4]), 0);
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_CLOSE, &[4]), -EBADF);
+        assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"written");
+
+        assert_eq!(openat(&mut abi, &mut cpu, "missing", 0), -ENOENT);
+        assert_eq!(
+            openat(&mut abi, &mut cpu, "out.txt", flags | O_EXCL),
+            -EEXIST
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dangling_symlink() {
+        let base = std::env::temp_dir().join(format!("rv32i-symlink-{}", std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("escaped.txt");
+        fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("evil")).unwrap();
+        let mut abi = LinuxAbi::new(Some(root));
+        let mut cpu = CPU::new();
+
+        cpu.write_memory(DATA, b"/evil\0").unwrap();
+        let flags = O_WRONLY | O_CREAT;
+        assert_eq!(
+            syscall(
+                &mut abi,
+                &mut cpu,
+                SYS_OPENAT,
+                &[AT_FDCWD as u32, DATA, flags]
+            ),
+            -EACCES
+        );
+        assert!(!outside.exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_brk_and_clocks() {
+        let mut abi = LinuxAbi::new(None);
+        let mut cpu = CPU::new();
+        abi.start(&mut cpu, 0x8000_0123);
+        assert_eq!(cpu.reg[2], 0x83FF_FFE0);
+
+        let heap = syscall(&mut abi, &mut cpu, SYS_BRK, &[0]) as u32;
+        assert_eq!(heap, 0x8000_1000);
+        cpu.bus.load_image(heap, &[0xAA; 16]).unwrap();
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_BRK, &[heap + 16]) as u32,
+            heap + 16
+        );
+        assert_eq!(cpu.read_memory(heap, 16).unwrap(), [0; 16]);
+        // past the end of RAM
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_BRK, &[0x9000_0000]) as u32,
+            heap + 16
+        );
+
+        assert_eq!(
+            syscall(&mut abi, &mut cpu, SYS_CLOCK_GETTIME64, &[0, DATA]),
+            0
+        );
+        let seconds = u64::from_le_bytes(cpu.read_memory(DATA, 8).unwrap().try_into().unwrap());
+        assert!(seconds > 1_600_000_000);
+        assert_eq!(syscall(&mut abi, &mut cpu, SYS_GETTIMEOFDAY, &[DATA, 0]), 0);
+        let seconds = u32::from_le_bytes(cpu.read_memory(DATA, 4).unwrap().try_into().unwrap());
+        assert!(seconds > 1_600_000_000);
+    }
+}
@@ -0,0 +1,179 @@
+//! ELF executable parsing: the loadable segments, the entry point, the symbol table and the
+//! riscv-tests `tohost` word
+
+use std::fmt;
+
+use elf::{abi, endian::AnyEndian, file::Class, parse::ParseError, ElfBytes};
+
+use crate::symbols::{Symbol, SymbolKind, SymbolTable};
+
+/// Reasons an ELF file can't run on this machine
+#[derive(Debug)]
+pub enum ElfError {
+    /// not an ELF file or a malformed one
+    Parse(ParseError),
+    /// 64-bit ELF
+    WrongClass,
+    /// big endian ELF
+    WrongEndianness,
+    /// ELF for another architecture, carries `e_machine`
+    WrongMachine(u16),
+    /// a PT_LOAD segment that doesn't fit in RAM
+    SegmentOutOfRange { addr: u64, size: u64 },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Parse(error) => write!(f, "invalid ELF file: {}", error),
+            ElfError::WrongClass => write!(f, "not a 32-bit ELF file"),
+            ElfError::WrongEndianness => write!(f, "not a little endian ELF file"),
+            ElfError::WrongMachine(machine) => {
+                write!(f, "not a RISC-V ELF file (e_machine {})", machine)
+            }
+            ElfError::SegmentOutOfRange { addr, size } => write!(
+                f,
+                "segment at 0x{:08x} with 0x{:x} bytes is outside of RAM",
+                addr, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<ParseError> for ElfError {
+    fn from(error: ParseError) -> Self {
+        ElfError::Parse(error)
+    }
+}
+
+/// A PT_LOAD segment, `data` is the file image and the rest up to `mem_size` is zero (.bss)
+#[derive(Debug)]
+pub struct Segment<'data> {
+    pub addr: u32,
+    pub data: &'data [u8],
+    pub mem_size: u32,
+}
+
+#[derive(Debug)]
+pub struct ElfImage<'data> {
+    pub entry: u32,
+    pub segments: Vec<Segment<'data>>,
+    /// address of the riscv-tests `tohost` word
+    pub tohost: Option<u32>,
+    pub symbols: SymbolTable,
+}
+
+impl<'data> ElfImage<'data> {
+    /// Parse an RV32 little endian executable
+    pub fn parse(binary_data: &'data [u8]) -> Result<Self, ElfError> {
+        let elf = ElfBytes::<AnyEndian>::minimal_parse(binary_data)?;
+        if elf.ehdr.class != Class::ELF32 {
+            return Err(ElfError::WrongClass);
+        }
+        if elf.ehdr.endianness != AnyEndian::Little {
+            return Err(ElfError::WrongEndianness);
+        }
+        if elf.ehdr.e_machine != abi::EM_RISCV {
+            return Err(ElfError::WrongMachine(elf.ehdr.e_machine));
+        }
+
+        let mut segments = Vec::new();
+        for phdr in elf.segments().into_iter().flatten() {
+            if phdr.p_type != abi::PT_LOAD {
+                continue;
+            }
+            segments.push(Segment {
+                addr: phdr.p_paddr as u32,
+                data: elf.segment_data(&phdr)?,
+                mem_size: phdr.p_memsz as u32,
+            });
+        }
+
+        let symbols = Self::parse_symbols(&elf)?;
+        // riscv-tests export `tohost`, fall back to the section for stripped binaries
+        let tohost = match symbols.lookup("tohost") {
+            Some(addr) => Some(addr),
+            None => elf
+                .section_header_by_name(".tohost")?
+                .map(|shdr| shdr.sh_addr as u32),
+        };
+
+        Ok(Self {
+            entry: elf.ehdr.e_entry as u32,
+            segments,
+            tohost,
+            symbols,
+        })
+    }
+
+    /// Defined symbols from `.symtab`, an ELF without one gives an empty table
+    fn parse_symbols(elf: &ElfBytes<AnyEndian>) -> Result<SymbolTable, ElfError> {
+        let mut symbols = SymbolTable::new();
+        let Some((symtab, strtab)) = elf.symbol_table()? else {
+            return Ok(symbols);
+        };
+
+        for sym in symtab.iter() {
+            let kind = match sym.st_symtype() {
+                abi::STT_NOTYPE => SymbolKind::Label,
+                abi::STT_OBJECT => SymbolKind::Object,
+                abi::STT_FUNC => SymbolKind::Function,
+                // sections, files, TLS
+                _ => continue,
+            };
+            let name = strtab.get(sym.st_name as usize)?;
+            // `$x` / `$d` are mapping symbols marking code and data, not names
+            if sym.is_undefined() || name.is_empty() || name.starts_with('$') {
+                continue;
+            }
+            symbols.insert(Symbol {
+                name: name.to_string(),
+                addr: sym.st_value as u32,
+                size: sym.st_size as u32,
+                kind,
+                global: sym.st_bind() != abi::STB_LOCAL,
+            });
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_WORLD: &str = "examples/hello_world/program.elf";
+
+    #[test]
+    fn test_parse_segments_and_entry() {
+        let binary_data = std::fs::read(HELLO_WORLD).unwrap();
+        let image = ElfImage::parse(&binary_data).unwrap();
+        assert_eq!(image.entry, 0x10074);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].addr, 0x10000);
+        assert_eq!(image.segments[0].data.len(), 0xa4);
+        assert_eq!(image.tohost, None);
+        assert_eq!(image.symbols.lookup("_start"), Some(0x10074));
+        assert_eq!(
+            image.symbols.describe(0x10098).as_deref(),
+            Some("hello_world+0x4")
+        );
+    }
+
+    #[test]
+    fn test_reject_foreign_binaries() {
+        let mut binary_data = std::fs::read(HELLO_WORLD).unwrap();
+        // e_machine = EM_X86_64
+        binary_data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        assert!(matches!(
+            ElfImage::parse(&binary_data),
+            Err(ElfError::WrongMachine(62))
+        ));
+        assert!(matches!(
+            ElfImage::parse(b"not an elf file"),
+            Err(ElfError::Parse(_))
+        ));
+    }
+}
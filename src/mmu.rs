@@ -0,0 +1,450 @@
+//! Sv32 address translation: a two level page table walker with a small TLB in front of it.
+//!
+//! The TLB caches leaf PTEs per 4 KiB page, tagged with the ASID unless the mapping is global.
+//! Permissions are checked on every access, so privilege, SUM and MXR changes don't need a
+//! flush. Page table updates do, through SFENCE.VMA.
+
+use crate::bus::Bus;
+use crate::csr::{CsrFile, Privilege, MSTATUS, MSTATUS_MXR, MSTATUS_SUM, SATP};
+use crate::pmp::Pmp;
+use crate::ram::MemoryAccessSize;
+use crate::trap::Exception;
+
+// satp fields
+pub const SATP_MODE_SV32: u32 = 1 << 31;
+const SATP_ASID_SHIFT: u32 = 22;
+const SATP_ASID: u32 = 0x1FF;
+const SATP_PPN: u32 = 0x3F_FFFF;
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+const LEVELS: u32 = 2;
+const VPN_BITS: u32 = 10;
+const VPN_MASK: u32 = (1 << VPN_BITS) - 1;
+
+// page table entry fields
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+const PTE_PPN_SHIFT: u32 = 10;
+
+const TLB_ENTRIES: usize = 64;
+
+/// What a virtual address is used for, decides the permission to check and the fault to raise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    /// stores and AMOs
+    Store,
+}
+
+impl Access {
+    pub fn page_fault(self, addr: u32) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    pub fn access_fault(self, addr: u32) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+}
+
+/// Whether an access of `size` bytes at `addr` touches two pages
+pub fn crosses_page(addr: u32, size: MemoryAccessSize) -> bool {
+    (addr & (PAGE_SIZE - 1)) + size.byte_size() > PAGE_SIZE
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u32,
+    asid: u32,
+    /// physical page of the 4 KiB page, superpages are split up
+    ppn: u32,
+    /// the leaf PTE, for the permission and A/D bits
+    pte: u32,
+    /// mapped by a megapage, which SFENCE.VMA of any address inside it flushes
+    superpage: bool,
+}
+
+#[derive(Debug)]
+pub struct Mmu {
+    tlb: [TlbEntry; TLB_ENTRIES],
+    /// page table walks so far, TLB misses
+    pub walks: u64,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self {
+            tlb: [TlbEntry::default(); TLB_ENTRIES],
+            walks: 0,
+        }
+    }
+
+    /// Physical address of `vaddr` for an access at `privilege`. M mode and a bare `satp` don't
+    /// translate. Page tables are read and written (A/D bits) through `bus`, as S mode loads and
+    /// stores that PMP has to allow.
+    pub fn translate(
+        &mut self,
+        bus: &mut Bus,
+        csr: &CsrFile,
+        privilege: Privilege,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        let (satp, mstatus) = (csr.get(SATP), csr.get(MSTATUS));
+        if satp & SATP_MODE_SV32 == 0 || privilege == Privilege::Machine {
+            return Ok(vaddr);
+        }
+        let asid = (satp >> SATP_ASID_SHIFT) & SATP_ASID;
+        let vpn = vaddr >> PAGE_SHIFT;
+        let slot = vpn as usize % TLB_ENTRIES;
+
+        let entry = self.tlb[slot];
+        // a store to a clean page walks again to set D
+        let hit = entry.valid
+            && entry.vpn == vpn
+            && (entry.asid == asid || entry.pte & PTE_G != 0)
+            && (access != Access::Store || entry.pte & PTE_D != 0);
+        let (ppn, pte) = if hit {
+            (entry.ppn, entry.pte)
+        } else {
+            let (ppn, pte, superpage) = self.walk(bus, csr, privilege, vaddr, access)?;
+            self.tlb[slot] = TlbEntry {
+                valid: true,
+                vpn,
+                asid,
+                ppn,
+                pte,
+                superpage,
+            };
+            (ppn, pte)
+        };
+
+        if !permitted(pte, mstatus, privilege, access) {
+            return Err(access.page_fault(vaddr));
+        }
+        // Sv32 physical addresses have 34 bits, the bus only 32
+        let paddr = (ppn as u64) << PAGE_SHIFT | (vaddr & (PAGE_SIZE - 1)) as u64;
+        u32::try_from(paddr).map_err(|_| access.access_fault(vaddr))
+    }
+
+    /// Walk the page tables for `vaddr`, returns the physical page, the leaf PTE with A/D
+    /// updated and whether it is a superpage
+    fn walk(
+        &mut self,
+        bus: &mut Bus,
+        csr: &CsrFile,
+        privilege: Privilege,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<(u32, u32, bool), Exception> {
+        self.walks += 1;
+        let (satp, mstatus) = (csr.get(SATP), csr.get(MSTATUS));
+        let fault = access.page_fault(vaddr);
+        let mut table = ((satp & SATP_PPN) as u64) << PAGE_SHIFT;
+
+        for level in (0..LEVELS).rev() {
+            let vpn = (vaddr >> (PAGE_SHIFT + level * VPN_BITS)) & VPN_MASK;
+            let pte_addr = table + vpn as u64 * 4;
+            let pte = read_pte(bus, &csr.pmp, pte_addr).ok_or(access.access_fault(vaddr))?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(fault);
+            }
+            let ppn = pte >> PTE_PPN_SHIFT;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level
+                table = (ppn as u64) << PAGE_SHIFT;
+                continue;
+            }
+
+            // leaf, superpages have to be aligned
+            if level == 1 && ppn & VPN_MASK != 0 {
+                return Err(fault);
+            }
+            if !permitted(pte, mstatus, privilege, access) {
+                return Err(fault);
+            }
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                write_pte(bus, &csr.pmp, pte_addr, updated).ok_or(access.access_fault(vaddr))?;
+            }
+            let ppn = match level {
+                1 => ppn | ((vaddr >> PAGE_SHIFT) & VPN_MASK),
+                _ => ppn,
+            };
+            return Ok((ppn, updated, level == 1));
+        }
+        // the last level has to be a leaf
+        Err(fault)
+    }
+
+    /// SFENCE.VMA: drop the cached translations of `vaddr` and/or `asid`, all of them for
+    /// `None`. Global mappings survive an ASID specific flush.
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        for entry in &mut self.tlb {
+            let vaddr_matches = vaddr.is_none_or(|vaddr| {
+                let shift = if entry.superpage {
+                    PAGE_SHIFT + VPN_BITS
+                } else {
+                    PAGE_SHIFT
+                };
+                entry.vpn >> (shift - PAGE_SHIFT) == vaddr >> shift
+            });
+            let asid_matches = asid.is_none_or(|asid| entry.asid == asid && entry.pte & PTE_G == 0);
+            if vaddr_matches && asid_matches {
+                entry.valid = false;
+            }
+        }
+    }
+}
+
+/// Permission check of a leaf PTE. U mode only gets user pages, S mode gets them for loads and
+/// stores with mstatus.SUM, and MXR makes executable pages readable.
+fn permitted(pte: u32, mstatus: u32, privilege: Privilege, access: Access) -> bool {
+    let user_page = pte & PTE_U != 0;
+    let allowed = match privilege {
+        Privilege::User => user_page,
+        Privilege::Supervisor => {
+            !user_page || (access != Access::Fetch && mstatus & MSTATUS_SUM != 0)
+        }
+        Privilege::Machine => true,
+    };
+    allowed
+        && match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        }
+}
+
+/// PTE reads and writes are checked like S mode accesses, whatever the privilege of the access
+/// being translated
+fn read_pte(bus: &mut Bus, pmp: &Pmp, addr: u64) -> Option<u32> {
+    let addr = u32::try_from(addr).ok()?;
+    if !pmp.check(addr, 4, Access::Load, Privilege::Supervisor) {
+        return None;
+    }
+    bus.read(addr, MemoryAccessSize::Word).ok()
+}
+
+fn write_pte(bus: &mut Bus, pmp: &Pmp, addr: u64, pte: u32) -> Option<()> {
+    let addr = u32::try_from(addr).ok()?;
+    if !pmp.check(addr, 4, Access::Store, Privilege::Supervisor) {
+        return None;
+    }
+    bus.write(addr, MemoryAccessSize::Word, pte).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+
+    const ROOT: u32 = 0x1000;
+    const TABLE: u32 = 0x2000;
+    const SATP: u32 = SATP_MODE_SV32 | (ROOT >> PAGE_SHIFT);
+
+    fn pte(paddr: u32, flags: u32) -> u32 {
+        (paddr >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags
+    }
+
+    /// 1 MiB of RAM at 0 with a root table at 0x1000 and a leaf table at 0x2000 for the first
+    /// 4 MiB of virtual memory
+    fn bus() -> Bus {
+        let mut bus = Bus::new();
+        bus.attach(0, Box::new(RAM::new(0x10_0000)));
+        let word = MemoryAccessSize::Word;
+        bus.write(ROOT, word, pte(TABLE, PTE_V)).unwrap();
+        // user data at 0x1000, code at 0x2000, an executable only page at 0x3000
+        bus.write(TABLE + 4, word, pte(0x8000, PTE_V | PTE_R | PTE_W | PTE_U))
+            .unwrap();
+        bus.write(TABLE + 8, word, pte(0x9000, PTE_V | PTE_R | PTE_X))
+            .unwrap();
+        bus.write(TABLE + 12, word, pte(0xA000, PTE_V | PTE_X))
+            .unwrap();
+        // a megapage at 0x400000, and a misaligned one at 0x800000
+        bus.write(ROOT + 4, word, pte(0x40_0000, PTE_V | PTE_R | PTE_W))
+            .unwrap();
+        bus.write(ROOT + 8, word, pte(0x1000, PTE_V | PTE_R))
+            .unwrap();
+        bus
+    }
+
+    fn csrs(satp: u32, mstatus: u32) -> CsrFile {
+        let mut csr = CsrFile::new();
+        csr.write(super::SATP, satp);
+        csr.write(MSTATUS, mstatus);
+        csr
+    }
+
+    #[test]
+    fn test_translation_and_permissions() {
+        let mut bus = bus();
+        let mut mmu = Mmu::new();
+        let mut translate = |mstatus, privilege, vaddr, access| {
+            mmu.translate(&mut bus, &csrs(SATP, mstatus), privilege, vaddr, access)
+        };
+        let (user, supervisor) = (Privilege::User, Privilege::Supervisor);
+
+        assert_eq!(translate(0, user, 0x1234, Access::Load), Ok(0x8234));
+        assert_eq!(translate(0, supervisor, 0x2010, Access::Fetch), Ok(0x9010));
+        assert_eq!(
+            translate(0, supervisor, 0x40_1234, Access::Store),
+            Ok(0x40_1234)
+        );
+        // bare mode and M mode don't translate
+        assert_eq!(
+            translate(0, Privilege::Machine, 0x1234, Access::Load),
+            Ok(0x1234)
+        );
+
+        // user pages from S mode need SUM, and are never executable there
+        assert_eq!(
+            translate(0, supervisor, 0x1000, Access::Load),
+            Err(Exception::LoadPageFault(0x1000))
+        );
+        assert_eq!(
+            translate(MSTATUS_SUM, supervisor, 0x1000, Access::Load),
+            Ok(0x8000)
+        );
+        assert_eq!(
+            translate(MSTATUS_SUM, supervisor, 0x1000, Access::Fetch),
+            Err(Exception::InstructionPageFault(0x1000))
+        );
+        assert_eq!(
+            translate(0, user, 0x2000, Access::Fetch),
+            Err(Exception::InstructionPageFault(0x2000))
+        );
+
+        // executable only pages are readable with MXR
+        assert_eq!(
+            translate(0, supervisor, 0x3000, Access::Load),
+            Err(Exception::LoadPageFault(0x3000))
+        );
+        assert_eq!(
+            translate(MSTATUS_MXR, supervisor, 0x3000, Access::Load),
+            Ok(0xA000)
+        );
+        assert_eq!(
+            translate(0, supervisor, 0x2000, Access::Store),
+            Err(Exception::StorePageFault(0x2000))
+        );
+
+        // invalid PTE, misaligned megapage
+        assert_eq!(
+            translate(0, supervisor, 0x4000, Access::Load),
+            Err(Exception::LoadPageFault(0x4000))
+        );
+        assert_eq!(
+            translate(0, supervisor, 0x80_0000, Access::Load),
+            Err(Exception::LoadPageFault(0x80_0000))
+        );
+    }
+
+    #[test]
+    fn test_accessed_and_dirty_bits() {
+        let mut bus = bus();
+        let mut mmu = Mmu::new();
+        let user = Privilege::User;
+        let leaf = |bus: &mut Bus| bus.read(TABLE + 4, MemoryAccessSize::Word).unwrap();
+
+        mmu.translate(&mut bus, &csrs(SATP, 0), user, 0x1000, Access::Load)
+            .unwrap();
+        assert_eq!(leaf(&mut bus) & (PTE_A | PTE_D), PTE_A);
+        mmu.translate(&mut bus, &csrs(SATP, 0), user, 0x1000, Access::Store)
+            .unwrap();
+        assert_eq!(leaf(&mut bus) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn test_tlb() {
+        let mut bus = bus();
+        let mut mmu = Mmu::new();
+        let supervisor = Privilege::Supervisor;
+        for _ in 0..3 {
+            mmu.translate(&mut bus, &csrs(SATP, 0), supervisor, 0x2000, Access::Fetch)
+                .unwrap();
+        }
+        assert_eq!(mmu.walks, 1);
+
+        // remap the page, the TLB keeps the old translation until it is flushed
+        bus.write(
+            TABLE + 8,
+            MemoryAccessSize::Word,
+            pte(0xB000, PTE_V | PTE_X),
+        )
+        .unwrap();
+        let fetch = |mmu: &mut Mmu, bus: &mut Bus| {
+            mmu.translate(bus, &csrs(SATP, 0), supervisor, 0x2000, Access::Fetch)
+        };
+        assert_eq!(fetch(&mut mmu, &mut bus), Ok(0x9000));
+        mmu.flush(Some(0x3000), None);
+        assert_eq!(fetch(&mut mmu, &mut bus), Ok(0x9000));
+        mmu.flush(Some(0x2FFF), None);
+        assert_eq!(fetch(&mut mmu, &mut bus), Ok(0xB000));
+        assert_eq!(mmu.walks, 2);
+
+        // another address space misses
+        let other = SATP | 1 << SATP_ASID_SHIFT;
+        mmu.translate(&mut bus, &csrs(other, 0), supervisor, 0x2000, Access::Fetch)
+            .unwrap();
+        assert_eq!(mmu.walks, 3);
+        mmu.flush(None, Some(1));
+        mmu.translate(&mut bus, &csrs(other, 0), supervisor, 0x2000, Access::Fetch)
+            .unwrap();
+        assert_eq!(mmu.walks, 4);
+    }
+
+    #[test]
+    fn test_pmp_protected_page_tables() {
+        use crate::csr::{PMPADDR0, PMPCFG0};
+        use crate::pmp::{PMP_NAPOT, PMP_R, PMP_TOR, PMP_W, PMP_X};
+
+        let mut bus = bus();
+        let mut csr = csrs(SATP, 0);
+        // entry 0 denies the root table page to S mode, entry 1 allows the rest of RAM
+        let rwx = (PMP_TOR | PMP_R | PMP_W | PMP_X) as u32;
+        csr.write(PMPADDR0, (ROOT >> 2) | 0x1FF);
+        csr.write(PMPADDR0 + 1, 0x10_0000 >> 2);
+        csr.write(PMPCFG0, rwx << 8 | PMP_NAPOT as u32);
+        let supervisor = Privilege::Supervisor;
+        assert_eq!(
+            Mmu::new().translate(&mut bus, &csr, supervisor, 0x2000, Access::Fetch),
+            Err(Exception::InstructionAccessFault(0x2000))
+        );
+
+        // a read only leaf table can be walked, but not get its A bit set
+        csr.write(PMPADDR0, (TABLE >> 2) | 0x1FF);
+        csr.write(PMPCFG0, rwx << 8 | (PMP_NAPOT | PMP_R) as u32);
+        assert_eq!(
+            Mmu::new().translate(&mut bus, &csr, Privilege::User, 0x1234, Access::Load),
+            Err(Exception::LoadAccessFault(0x1234))
+        );
+        let leaf = bus.read(TABLE + 4, MemoryAccessSize::Word).unwrap();
+        assert_eq!(leaf & PTE_A, 0);
+    }
+}
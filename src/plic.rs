@@ -0,0 +1,245 @@
+//! Platform-level interrupt controller with the SiFive/QEMU `virt` register layout. Collects the
+//! interrupt lines of the devices on the bus and drives the machine (context 0) and supervisor
+//! (context 1) external interrupts of hart 0.
+//!
+//! Sources are level triggered: a high line makes its source pending, and once claimed it isn't
+//! pending again until the handler completes it.
+
+use crate::bus::{BusError, Device};
+use crate::csr::{MIP_MEIP, MIP_SEIP};
+use crate::ram::MemoryAccessSize;
+
+/// where QEMU `virt` puts its PLIC
+pub const DEFAULT_PLIC_BASE: u32 = 0x0C00_0000;
+const PLIC_SIZE: u32 = 0x0400_0000;
+
+/// interrupt sources 1..31, source 0 means "no interrupt"
+pub const SOURCES: u32 = 32;
+const MAX_PRIORITY: u32 = 7;
+
+// register offsets
+pub const PRIORITY: u32 = 0x00_0000;
+pub const PENDING: u32 = 0x00_1000;
+pub const ENABLE: u32 = 0x00_2000;
+const ENABLE_STRIDE: u32 = 0x80;
+pub const THRESHOLD: u32 = 0x20_0000;
+pub const CLAIM_COMPLETE: u32 = 0x20_0004;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+/// Interrupt targets, one per hart privilege level that takes external interrupts
+const CONTEXTS: usize = 2;
+/// mip bit driven by each context
+const CONTEXT_MIP: [u32; CONTEXTS] = [MIP_MEIP, MIP_SEIP];
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Context {
+    enable: u32,
+    threshold: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Plic {
+    priority: [u32; SOURCES as usize],
+    pending: u32,
+    /// sources claimed and not completed yet
+    claimed: u32,
+    contexts: [Context; CONTEXTS],
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Highest priority pending and enabled source above the context threshold, lowest id wins
+    /// a tie
+    fn best(&self, context: usize) -> Option<u32> {
+        let context = &self.contexts[context];
+        let candidates = self.pending & context.enable;
+        (1..SOURCES)
+            .filter(|&source| candidates & (1 << source) != 0)
+            .filter(|&source| self.priority[source as usize] > context.threshold)
+            .fold(None, |best: Option<u32>, source| match best {
+                Some(best) if self.priority[best as usize] >= self.priority[source as usize] => {
+                    Some(best)
+                }
+                _ => Some(source),
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source
+            }
+            None => 0,
+        }
+    }
+
+    /// The source can be pending again from the next line update on
+    fn complete(&mut self, context: usize, source: u32) {
+        // completions of sources this context has disabled are ignored
+        if source < SOURCES && self.contexts[context].enable & (1 << source) != 0 {
+            self.claimed &= !(1 << source);
+        }
+    }
+
+    /// Context and register of an offset in the per context blocks
+    fn context_register(offset: u32, base: u32, stride: u32) -> Option<(usize, u32)> {
+        let context = ((offset - base) / stride) as usize;
+        (context < CONTEXTS).then_some((context, (offset - base) % stride))
+    }
+}
+
+impl Device for Plic {
+    fn size(&self) -> u64 {
+        PLIC_SIZE as u64
+    }
+
+    /// Registers are 32 bits wide, narrower accesses aren't supported
+    fn read(&mut self, offset: u32, size: MemoryAccessSize) -> Result<u32, BusError> {
+        if size != MemoryAccessSize::Word {
+            return Err(BusError::Denied);
+        }
+        let value = match offset {
+            PRIORITY..PENDING => self
+                .priority
+                .get(((offset - PRIORITY) / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            PENDING => self.pending,
+            ENABLE..THRESHOLD => match Self::context_register(offset, ENABLE, ENABLE_STRIDE) {
+                Some((context, 0)) => self.contexts[context].enable,
+                _ => 0,
+            },
+            THRESHOLD.. => match Self::context_register(offset, THRESHOLD, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.contexts[context].threshold,
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u32, size: MemoryAccessSize, value: u32) -> Result<(), BusError> {
+        if size != MemoryAccessSize::Word {
+            return Err(BusError::Denied);
+        }
+        match offset {
+            PRIORITY..PENDING => {
+                // source 0 doesn't exist, its priority stays 0
+                let source = ((offset - PRIORITY) / 4) as usize;
+                if (1..SOURCES as usize).contains(&source) {
+                    self.priority[source] = value.min(MAX_PRIORITY);
+                }
+            }
+            ENABLE..THRESHOLD => {
+                if let Some((context, 0)) = Self::context_register(offset, ENABLE, ENABLE_STRIDE) {
+                    self.contexts[context].enable = value & !1;
+                }
+            }
+            THRESHOLD.. => match Self::context_register(offset, THRESHOLD, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.contexts[context].threshold = value.min(MAX_PRIORITY),
+                Some((context, 4)) => self.complete(context, value),
+                _ => {}
+            },
+            // pending bits are read only
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Gateways: a high line makes its source pending unless it is being handled
+    fn set_irq_lines(&mut self, lines: u32) {
+        self.pending |= lines & !self.claimed & !1;
+    }
+
+    fn interrupts(&mut self) -> u32 {
+        (0..CONTEXTS)
+            .filter(|&context| self.best(context).is_some())
+            .fold(0, |mip, context| mip | CONTEXT_MIP[context])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(plic: &mut Plic, offset: u32, value: u32) {
+        plic.write(offset, MemoryAccessSize::Word, value).unwrap();
+    }
+
+    fn read(plic: &mut Plic, offset: u32) -> u32 {
+        plic.read(offset, MemoryAccessSize::Word).unwrap()
+    }
+
+    #[test]
+    fn test_priority_and_threshold() {
+        let mut plic = Plic::new();
+        write(&mut plic, PRIORITY + 4 * 3, 2);
+        write(&mut plic, PRIORITY + 4 * 5, 6);
+        write(&mut plic, PRIORITY + 4 * 7, 6);
+        plic.set_irq_lines(1 << 3 | 1 << 5 | 1 << 7);
+        assert_eq!(read(&mut plic, PENDING), 1 << 3 | 1 << 5 | 1 << 7);
+
+        // pending but not enabled
+        assert_eq!(plic.interrupts(), 0);
+        write(&mut plic, ENABLE, 1 << 3 | 1 << 5 | 1 << 7);
+        assert_eq!(plic.interrupts(), MIP_MEIP);
+
+        write(&mut plic, THRESHOLD, 6);
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 0);
+
+        // highest priority first, lowest id breaks the tie
+        write(&mut plic, THRESHOLD, 1);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 5);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 7);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 3);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 0);
+        assert_eq!(plic.interrupts(), 0);
+    }
+
+    #[test]
+    fn test_claim_and_complete() {
+        let mut plic = Plic::new();
+        write(&mut plic, PRIORITY + 4 * 10, 1);
+        write(&mut plic, ENABLE, 1 << 10);
+        plic.set_irq_lines(1 << 10);
+
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 10);
+        // the line is still high, but the source is being handled
+        plic.set_irq_lines(1 << 10);
+        assert_eq!(plic.interrupts(), 0);
+
+        // completing with the line still high makes it pending again
+        write(&mut plic, CLAIM_COMPLETE, 10);
+        plic.set_irq_lines(1 << 10);
+        assert_eq!(plic.interrupts(), MIP_MEIP);
+        assert_eq!(read(&mut plic, CLAIM_COMPLETE), 10);
+
+        // the device dropped its line
+        plic.set_irq_lines(0);
+        write(&mut plic, CLAIM_COMPLETE, 10);
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(read(&mut plic, PENDING), 0);
+    }
+
+    #[test]
+    fn test_register_access() {
+        let mut plic = Plic::new();
+        write(&mut plic, PRIORITY, 5);
+        assert_eq!(read(&mut plic, PRIORITY), 0);
+        write(&mut plic, PRIORITY + 4, 100);
+        assert_eq!(read(&mut plic, PRIORITY + 4), MAX_PRIORITY);
+        write(&mut plic, ENABLE, u32::MAX);
+        assert_eq!(read(&mut plic, ENABLE), !1);
+        assert_eq!(
+            plic.read(PENDING, MemoryAccessSize::Byte),
+            Err(BusError::Denied)
+        );
+    }
+}
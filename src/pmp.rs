@@ -0,0 +1,204 @@
+//! Physical memory protection: 16 regions set up by M mode firmware that limit which physical
+//! addresses S and U mode may read, write and execute. Locked regions also apply to M mode and
+//! can't be changed until reset.
+//!
+//! Page table walks are checked too, as S mode loads of the PTEs and stores of their A/D bits.
+
+use crate::csr::Privilege;
+use crate::mmu::Access;
+
+pub const ENTRIES: usize = 16;
+
+// pmpcfg fields, one byte per entry
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A: u8 = 0b11 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+// address matching modes
+pub const PMP_OFF: u8 = 0 << 3;
+pub const PMP_TOR: u8 = 1 << 3;
+pub const PMP_NA4: u8 = 2 << 3;
+pub const PMP_NAPOT: u8 = 3 << 3;
+
+#[derive(Debug, Default, Clone)]
+pub struct Pmp {
+    cfg: [u8; ENTRIES],
+    /// physical address bits [33:2]
+    addr: [u32; ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// pmpcfg`index`, the configuration of entries 4 * index to 4 * index + 3
+    pub fn read_cfg(&self, index: usize) -> u32 {
+        u32::from_le_bytes(self.cfg[4 * index..4 * index + 4].try_into().unwrap())
+    }
+
+    pub fn write_cfg(&mut self, index: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            let entry = 4 * index + i;
+            if self.cfg[entry] & PMP_L != 0 {
+                continue;
+            }
+            // W without R is reserved, it reads back as no access
+            let byte = byte & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+            self.cfg[entry] = match byte & (PMP_R | PMP_W) {
+                PMP_W => byte & !PMP_W,
+                _ => byte,
+            };
+        }
+    }
+
+    pub fn read_addr(&self, index: usize) -> u32 {
+        self.addr[index]
+    }
+
+    /// Locked entries keep their address, and so does the entry below a locked TOR entry as it is
+    /// the bottom of that range
+    pub fn write_addr(&mut self, index: usize, value: u32) {
+        let top_of_locked_tor = self
+            .cfg
+            .get(index + 1)
+            .is_some_and(|&cfg| cfg & PMP_L != 0 && cfg & PMP_A == PMP_TOR);
+        if self.cfg[index] & PMP_L == 0 && !top_of_locked_tor {
+            self.addr[index] = value;
+        }
+    }
+
+    /// Byte range `[start, end)` an entry matches, `None` while it is off
+    fn range(&self, index: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[index] as u64;
+        match self.cfg[index] & PMP_A {
+            PMP_TOR => {
+                let bottom = match index {
+                    0 => 0,
+                    _ => self.addr[index - 1] as u64,
+                };
+                Some((bottom << 2, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // the number of trailing ones encodes the size, 8 bytes and up
+                let ones = (!addr).trailing_zeros();
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (1 << (ones + 3))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `privilege` may access `size` bytes at the physical address `addr`. The lowest
+    /// numbered entry overlapping the access decides, and it has to cover all of it. S and U
+    /// mode accesses matching no entry fail, unless PMP isn't set up at all.
+    pub fn check(&self, addr: u32, size: u32, access: Access, privilege: Privilege) -> bool {
+        let (start, end) = (addr as u64, addr as u64 + size as u64);
+        for index in 0..ENTRIES {
+            let Some((bottom, top)) = self.range(index) else {
+                continue;
+            };
+            if start >= top || end <= bottom {
+                continue;
+            }
+            if start < bottom || end > top {
+                return false;
+            }
+            let cfg = self.cfg[index];
+            if privilege == Privilege::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let permission = match access {
+                Access::Fetch => PMP_X,
+                Access::Load => PMP_R,
+                Access::Store => PMP_W,
+            };
+            return cfg & permission != 0;
+        }
+        let configured = self.cfg.iter().any(|cfg| cfg & PMP_A != PMP_OFF);
+        privilege == Privilege::Machine || !configured
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configure entry `index` without touching the others
+    fn set(pmp: &mut Pmp, index: usize, cfg: u8, addr: u32) {
+        pmp.write_addr(index, addr);
+        let word = index / 4;
+        let shift = 8 * (index % 4);
+        let value = (pmp.read_cfg(word) & !(0xFF << shift)) | (cfg as u32) << shift;
+        pmp.write_cfg(word, value);
+    }
+
+    #[test]
+    fn test_address_matching() {
+        let (user, load) = (Privilege::User, Access::Load);
+        let mut pmp = Pmp::new();
+        // nothing configured, everything goes
+        assert!(pmp.check(0x8000_0000, 4, load, user));
+
+        // TOR [0x1000, 0x2000), NA4 at 0x3000, NAPOT 0x4000 bytes at 0x8000_0000
+        set(&mut pmp, 0, PMP_OFF, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_TOR | PMP_R, 0x2000 >> 2);
+        set(&mut pmp, 2, PMP_NA4 | PMP_R | PMP_W, 0x3000 >> 2);
+        set(
+            &mut pmp,
+            3,
+            PMP_NAPOT | PMP_R | PMP_X,
+            (0x8000_0000 >> 2) | 0x7FF,
+        );
+
+        assert!(pmp.check(0x1000, 4, load, user));
+        assert!(pmp.check(0x1FFC, 4, load, user));
+        assert!(!pmp.check(0x2000, 4, load, user));
+        assert!(!pmp.check(0x1000, 4, Access::Store, user));
+        // partially covered accesses fail
+        assert!(!pmp.check(0x0FFE, 4, load, user));
+
+        assert!(pmp.check(0x3000, 4, Access::Store, user));
+        assert!(!pmp.check(0x3004, 1, load, user));
+
+        assert!(pmp.check(0x8000_0000, 4, Access::Fetch, user));
+        assert!(pmp.check(0x8000_3FFC, 4, load, user));
+        assert!(!pmp.check(0x8000_4000, 4, load, user));
+        assert!(!pmp.check(0x8000_0000, 4, Access::Store, Privilege::Supervisor));
+
+        // M mode is only restricted by locked entries
+        assert!(pmp.check(0x8000_0000, 4, Access::Store, Privilege::Machine));
+        assert!(pmp.check(0x9000_0000, 4, Access::Store, Privilege::Machine));
+    }
+
+    #[test]
+    fn test_napot_whole_address_space() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, PMP_NAPOT | PMP_R | PMP_W | PMP_X, u32::MAX);
+        assert!(pmp.check(0, 4, Access::Store, Privilege::User));
+        assert!(pmp.check(0xFFFF_FFFC, 4, Access::Fetch, Privilege::User));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut pmp = Pmp::new();
+        set(&mut pmp, 0, PMP_OFF, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_TOR | PMP_R | PMP_L, 0x2000 >> 2);
+        assert!(pmp.check(0x1000, 4, Access::Load, Privilege::Machine));
+        assert!(!pmp.check(0x1000, 4, Access::Store, Privilege::Machine));
+
+        // neither the entry nor the bottom of its range can change
+        set(&mut pmp, 1, PMP_TOR | PMP_R | PMP_W, 0x3000 >> 2);
+        pmp.write_addr(0, 0);
+        assert_eq!(pmp.read_cfg(0), ((PMP_TOR | PMP_R | PMP_L) as u32) << 8);
+        assert_eq!(pmp.read_addr(1), 0x2000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+
+        // W without R isn't a valid permission
+        set(&mut pmp, 2, PMP_NA4 | PMP_W, 0);
+        assert_eq!(pmp.read_cfg(0) >> 16, PMP_NA4 as u32);
+    }
+}
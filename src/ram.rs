@@ -1,8 +1,10 @@
-// 64k Memory
-pub const RAM_SIZE: usize = 1024 * 64;
+use crate::bus::{BusError, Device};
+use crate::config::DEFAULT_RAM_SIZE;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryAccessSize {
     Byte,
+    HalfWord,
     Word,
     DoubleWord,
 }
@@ -11,6 +13,7 @@ impl MemoryAccessSize {
     pub fn size(&self) -> u32 {
         match self {
             MemoryAccessSize::Byte => 8,
+            MemoryAccessSize::HalfWord => 16,
             MemoryAccessSize::Word => 32,
             MemoryAccessSize::DoubleWord => 64,
         }
@@ -19,6 +22,7 @@ impl MemoryAccessSize {
     pub fn byte_size(&self) -> u32 {
         match self {
             MemoryAccessSize::Byte => 1,
+            MemoryAccessSize::HalfWord => 2,
             MemoryAccessSize::Word => 4,
             MemoryAccessSize::DoubleWord => 8,
         }
@@ -27,59 +31,119 @@ impl MemoryAccessSize {
 
 #[derive(Debug)]
 pub struct RAM {
-    /// allocated on heap to keep the pointer alive
-    pub data: [u8; RAM_SIZE],
+    /// allocated on heap, sized by `MachineConfig::ram_size`
+    pub data: Vec<u8>,
 }
 
 impl Default for RAM {
     fn default() -> Self {
-        Self::new()
+        Self::new(DEFAULT_RAM_SIZE)
     }
 }
 
 impl RAM {
-    pub fn new() -> Self {
+    pub fn new(size: usize) -> Self {
         Self {
-            data: [0; RAM_SIZE],
+            data: vec![0; size],
         }
     }
 
+    /// RAM size in bytes
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn write_bytes(&mut self, addr: usize, data: &[u8]) {
         // memory = memory[:addr] + data + memory[addr+len(dat):]
         self.data[addr..addr + data.len()].copy_from_slice(data);
-        let mut current_addr = addr;
-        // Print data in 32-byte chunks
-        for chunk in data.chunks(32) {
-            // print!("0x{:08X}: ", current_addr);
-
-            // Print the chunk as 4-byte words
-            for word in chunk.chunks(4) {
-                let mut buf = [0u8; 4];
-                for (i, &b) in word.iter().enumerate() {
-                    buf[i] = b;
-                }
-
-                let val = u32::from_le_bytes(buf);
-                // print!("{:08X} ", val);
-            }
-
-            // println!();
-            current_addr += 32;
-        }
     }
 
     /// Write a word (32-bit) into RAM at the given address
     pub fn write_word(&mut self, addr: usize, value: u32) {
-        let bytes = value.to_le_bytes();
-        self.data[addr..addr + 4].copy_from_slice(&bytes);
+        self.write(addr, MemoryAccessSize::Word, value);
     }
 
     /// Read a word (32-bit) from RAM at the given address
     pub fn read_word(&self, addr: usize) -> u32 {
+        self.read(addr, MemoryAccessSize::Word)
+    }
+
+    /// Read `size` bytes (little endian, zero extended) from RAM at the given address
+    pub fn read(&self, addr: usize, size: MemoryAccessSize) -> u32 {
+        let len = size.byte_size() as usize;
         let mut bytes = [0u8; 4];
-        bytes.copy_from_slice(&self.data[addr..addr + 4]);
+        bytes[..len].copy_from_slice(&self.data[addr..addr + len]);
         u32::from_le_bytes(bytes)
     }
+
+    /// Write the low `size` bytes of `value` (little endian) into RAM at the given address
+    pub fn write(&mut self, addr: usize, size: MemoryAccessSize, value: u32) {
+        let len = size.byte_size() as usize;
+        self.data[addr..addr + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+}
+
+impl Device for RAM {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read(&mut self, offset: u32, size: MemoryAccessSize) -> Result<u32, BusError> {
+        if size == MemoryAccessSize::DoubleWord {
+            return Err(BusError::Denied);
+        }
+        Ok(RAM::read(self, offset as usize, size))
+    }
+
+    fn write(&mut self, offset: u32, size: MemoryAccessSize, value: u32) -> Result<(), BusError> {
+        if size == MemoryAccessSize::DoubleWord {
+            return Err(BusError::Denied);
+        }
+        RAM::write(self, offset as usize, size, value);
+        Ok(())
+    }
+
+    fn load_image(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        self.write_bytes(offset as usize, data);
+        Ok(())
+    }
+}
+
+/// Read only memory, its contents can only be set by loading an image
+#[derive(Debug)]
+pub struct ROM {
+    memory: RAM,
+}
+
+impl ROM {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            memory: RAM { data },
+        }
+    }
+}
+
+impl Device for ROM {
+    fn size(&self) -> u64 {
+        Device::size(&self.memory)
+    }
+
+    fn read(&mut self, offset: u32, size: MemoryAccessSize) -> Result<u32, BusError> {
+        Device::read(&mut self.memory, offset, size)
+    }
+
+    fn write(
+        &mut self,
+        _offset: u32,
+        _size: MemoryAccessSize,
+        _value: u32,
+    ) -> Result<(), BusError> {
+        Err(BusError::Denied)
+    }
+
+    fn load_image(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        self.memory.load_image(offset, data)
+    }
 }
 
 #[cfg(test)]
@@ -87,7 +151,31 @@ mod tests {
     use super::*;
     #[test]
     fn test_ram_initialization() {
-        let ram = RAM::new();
-        println!("RAM initialized with size: {}", ram.data.len());
+        let ram = RAM::new(1024);
+        assert_eq!(ram.size(), 1024);
+    }
+
+    #[test]
+    fn test_ram_sub_word_access() {
+        let mut ram = RAM::new(1024);
+        ram.write_word(0, 0x8899_AABB);
+        assert_eq!(ram.read(0, MemoryAccessSize::Byte), 0xBB);
+        assert_eq!(ram.read(2, MemoryAccessSize::HalfWord), 0x8899);
+
+        ram.write(1, MemoryAccessSize::Byte, 0x1122_3344);
+        assert_eq!(ram.read_word(0), 0x8899_44BB);
+        ram.write(2, MemoryAccessSize::HalfWord, 0xFFFF_0102);
+        assert_eq!(ram.read_word(0), 0x0102_44BB);
+    }
+
+    #[test]
+    fn test_rom_is_read_only() {
+        let mut rom = ROM::new(vec![0; 16]);
+        rom.load_image(4, &[0x13, 0, 0, 0]).unwrap();
+        assert_eq!(Device::read(&mut rom, 4, MemoryAccessSize::Word), Ok(0x13));
+        assert_eq!(
+            Device::write(&mut rom, 4, MemoryAccessSize::Word, 0),
+            Err(BusError::Denied)
+        );
     }
 }
@@ -0,0 +1,131 @@
+//! Symbols from the ELF `.symtab`, for turning addresses into `function+offset` and names into
+//! addresses
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    /// untyped label, what hand written assembly produces
+    Label,
+    /// data object
+    Object,
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    /// size in bytes, 0 if unknown
+    pub size: u32,
+    pub kind: SymbolKind,
+    pub global: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    /// best symbol to describe each address: functions over objects over labels, globals over
+    /// locals
+    by_addr: BTreeMap<u32, Symbol>,
+    by_name: HashMap<String, u32>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn insert(&mut self, symbol: Symbol) {
+        // a global definition wins over a local symbol with the same name
+        if symbol.global || !self.by_name.contains_key(&symbol.name) {
+            self.by_name.insert(symbol.name.clone(), symbol.addr);
+        }
+
+        let better = match self.by_addr.get(&symbol.addr) {
+            Some(current) => (symbol.kind, symbol.global) > (current.kind, current.global),
+            None => true,
+        };
+        if better {
+            self.by_addr.insert(symbol.addr, symbol);
+        }
+    }
+
+    /// Address of the symbol called `name`
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Closest symbol at or below `addr` and the offset into it. Symbols with a known size only
+    /// cover their own bytes.
+    pub fn resolve(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let (_, symbol) = self.by_addr.range(..=addr).next_back()?;
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    /// `name+0x10` style description of `addr`
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        self.resolve(addr).map(|(symbol, offset)| match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+0x{:x}", symbol.name, offset),
+        })
+    }
+
+    /// `0x80000104 <test_2+0x8>`, or just the address if no symbol covers it
+    pub fn location(&self, addr: u32) -> String {
+        match self.describe(addr) {
+            Some(symbol) => format!("0x{:08x} <{}>", addr, symbol),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u32, size: u32, kind: SymbolKind, global: bool) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            kind,
+            global,
+        }
+    }
+
+    #[test]
+    fn test_resolve_and_lookup() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(symbol("_start", 0x100, 0, SymbolKind::Label, true));
+        symbols.insert(symbol("main", 0x200, 0x20, SymbolKind::Function, true));
+        symbols.insert(symbol(".L1", 0x200, 0, SymbolKind::Label, false));
+        symbols.insert(symbol("tohost", 0x1000, 8, SymbolKind::Object, true));
+
+        assert_eq!(symbols.lookup("main"), Some(0x200));
+        assert_eq!(symbols.lookup("tohost"), Some(0x1000));
+        assert_eq!(symbols.lookup("missing"), None);
+
+        assert_eq!(symbols.describe(0x100).as_deref(), Some("_start"));
+        assert_eq!(symbols.describe(0x1fc).as_deref(), Some("_start+0xfc"));
+        // the function wins over the local label at the same address
+        assert_eq!(symbols.describe(0x204).as_deref(), Some("main+0x4"));
+        // past the end of main
+        assert_eq!(symbols.describe(0x220), None);
+        assert_eq!(symbols.describe(0x80), None);
+
+        assert_eq!(symbols.location(0x204), "0x00000204 <main+0x4>");
+        assert_eq!(symbols.location(0x80), "0x00000080");
+    }
+}
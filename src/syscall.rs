@@ -0,0 +1,53 @@
+//! System calls the host answers for guests that have no trap handler of their own. The number
+//! is in a7, arguments in a0-a5 and the result goes back in a0, what the numbers mean is up to
+//! the `SyscallAbi` the machine was given.
+
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::trap::Exception;
+
+pub trait SyscallAbi: fmt::Debug {
+    /// Called once an ELF is loaded, `image_end` is the first address past its highest segment
+    fn start(&mut self, _cpu: &mut CPU, _image_end: u32) {}
+
+    /// Handle the ecall the hart is executing. Returning an error hands it back to the host as
+    /// if there was no syscall layer, without retiring the ecall.
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<(), Exception>;
+}
+
+/// The original toy numbering: 1 prints a0 as a number, 4 prints the string at a0, 10 exits and
+/// 93 exits with the code in a0
+#[derive(Debug, Default)]
+pub struct SimpleAbi;
+
+impl SyscallAbi for SimpleAbi {
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<(), Exception> {
+        match cpu.reg[17] {
+            1 => println!("{}", cpu.reg[10]),
+            4 => {
+                let mut s = String::new();
+                let mut addr = cpu.reg[10];
+                loop {
+                    let byte = cpu.read_memory(addr, 1)?[0];
+                    if byte == 0 {
+                        break;
+                    }
+                    s.push(byte as char);
+                    addr = addr.wrapping_add(1);
+                }
+                print!("{}", s);
+            }
+            10 => {
+                println!("Program exiting.");
+                cpu.exited = true;
+            }
+            93 => {
+                cpu.exit_code = Some(cpu.reg[10]);
+                cpu.exited = true;
+            }
+            _ => return Err(Exception::EnvironmentCall(cpu.privilege)),
+        }
+        Ok(())
+    }
+}
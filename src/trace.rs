@@ -0,0 +1,250 @@
+//! Commit log of retired instructions, in the format of `spike --log-commits`: the privilege
+//! level, pc and raw instruction, then the register and CSR writes and the memory accesses.
+//!
+//! ```text
+//! core   0: 3 0x80000004 (0x00b2a023) mem 0x80001000 0x00000007
+//! ```
+//!
+//! The disassembly line of `spike -l` is left out, spike's disassembler doesn't write operands
+//! the way `disasm` does. Instructions that trap didn't retire and aren't logged.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::compressed;
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::instruction::RV5Instruction;
+use crate::mmu::Access;
+
+/// CSRs instructions also write on the side: floating point instructions accrue fflags and
+/// dirty mstatus.FS, mret and sret update mstatus. They are logged when their value changes.
+const IMPLICIT_CSRS: [u16; 2] = [csr::FFLAGS, csr::MSTATUS];
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+/// State of the hart before an instruction, for `Tracer::retire` to compare against
+#[derive(Debug)]
+pub struct Snapshot {
+    pub pc: u32,
+    pub privilege: Privilege,
+    csrs: [u32; IMPLICIT_CSRS.len()],
+}
+
+impl Snapshot {
+    pub fn take(cpu: &CPU) -> Self {
+        Self {
+            pc: cpu.pc(),
+            privilege: cpu.privilege,
+            csrs: IMPLICIT_CSRS.map(|addr| cpu.csr.get(addr)),
+        }
+    }
+}
+
+enum Register {
+    X(u32),
+    F(u32),
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self { output }
+    }
+
+    /// Log the instruction `raw` that just retired, `before` is the hart as it was before it
+    pub fn retire(&mut self, cpu: &CPU, before: &Snapshot, raw: u32) -> io::Result<()> {
+        let width = if compressed::is_compressed(raw) { 4 } else { 8 };
+        // spike keys the writes by register << 4 | kind and prints them in key order
+        let mut writes: Vec<(u32, String)> = Vec::new();
+        if let Some(instruction) = decode(raw) {
+            match destination(&instruction) {
+                Some(Register::X(rd)) if rd != 0 => writes.push((
+                    rd << 4,
+                    format!("x{:<2} 0x{:08x}", rd, cpu.reg[rd as usize]),
+                )),
+                Some(Register::F(rd)) => writes.push((
+                    rd << 4 | 1,
+                    format!("f{:<2} 0x{:016x}", rd, cpu.freg[rd as usize]),
+                )),
+                _ => {}
+            }
+            if let Some(addr) = csr_written(&instruction) {
+                writes.push(csr_write(cpu, addr));
+            }
+        }
+        for (&addr, &old) in IMPLICIT_CSRS.iter().zip(&before.csrs) {
+            let write = csr_write(cpu, addr);
+            if cpu.csr.get(addr) != old && writes.iter().all(|(key, _)| *key != write.0) {
+                writes.push(write);
+            }
+        }
+        writes.sort_by_key(|(key, _)| *key);
+
+        let mut line = format!(
+            "core   0: {} 0x{:08x} (0x{:0width$x})",
+            before.privilege as u32, before.pc, raw
+        );
+        for (_, text) in writes {
+            line.push(' ');
+            line.push_str(&text);
+        }
+        // spike lists the loads before the stores, an AMO has one of each
+        let accesses = cpu.memory_accesses();
+        for access in accesses
+            .iter()
+            .filter(|access| access.access == Access::Load)
+        {
+            line.push_str(&format!(" mem 0x{:08x}", access.addr));
+        }
+        for access in accesses
+            .iter()
+            .filter(|access| access.access == Access::Store)
+        {
+            let digits = 2 * access.size.byte_size() as usize;
+            line.push_str(&format!(
+                " mem 0x{:08x} 0x{:0digits$x}",
+                access.addr, access.value
+            ));
+        }
+        writeln!(self.output, "{}", line)
+    }
+}
+
+fn decode(raw: u32) -> Option<RV5Instruction> {
+    let raw = match compressed::is_compressed(raw) {
+        true => compressed::expand(raw as u16).ok()?,
+        false => raw,
+    };
+    RV5Instruction::new(raw).ok()
+}
+
+/// Register the instruction writes
+fn destination(instruction: &RV5Instruction) -> Option<Register> {
+    match instruction {
+        RV5Instruction::R(r) if r.opcode == 0b1010011 => match r.funct7 >> 2 {
+            // compares, conversions to integers, fmv.x.w and fclass write x registers
+            0b10100 | 0b11000 | 0b11100 => Some(Register::X(r.rd)),
+            _ => Some(Register::F(r.rd)),
+        },
+        RV5Instruction::R(r) => Some(Register::X(r.rd)),
+        RV5Instruction::R4(r4) => Some(Register::F(r4.rd)),
+        RV5Instruction::I(i) => match i.opcode {
+            0b0000111 => Some(Register::F(i.rd)),
+            0b0001111 => None,
+            _ => Some(Register::X(i.rd)),
+        },
+        RV5Instruction::U(u) => Some(Register::X(u.rd)),
+        RV5Instruction::J(j) => Some(Register::X(j.rd)),
+        RV5Instruction::CSR(i) => Some(Register::X(i.rd)),
+        _ => None,
+    }
+}
+
+/// Commit log entry for a CSR write, with its spike key
+fn csr_write(cpu: &CPU, addr: u16) -> (u32, String) {
+    let name = csr::name(addr).unwrap_or_else(|| format!("0x{:03x}", addr));
+    let text = format!("c{}_{} 0x{:08x}", addr, name, cpu.csr.get(addr));
+    ((addr as u32) << 4 | 4, text)
+}
+
+/// CSR a Zicsr instruction writes, csrrs and csrrc with x0 or 0 only read
+fn csr_written(instruction: &RV5Instruction) -> Option<u16> {
+    match instruction {
+        RV5Instruction::CSR(i) if i.funct3 & 0b11 == 0b01 || i.rs1 != 0 => Some(i.imm as u16),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SharedBuffer;
+
+    fn commit_log(source: &str) -> Vec<String> {
+        let mut cpu = CPU::new();
+        cpu.load_assembly(source).unwrap();
+        let output = SharedBuffer::default();
+        cpu.set_tracer(Some(Tracer::new(Box::new(output.clone()))));
+        while !cpu.is_exited() {
+            cpu.execute_ins().unwrap();
+        }
+        let log = String::from_utf8(output.0.borrow().clone()).unwrap();
+        log.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_commit_log() {
+        let lines = commit_log(
+            "
+                li    a1, 7
+                lui   t0, 0x80001
+                sw    a1, 0(t0)
+                amoadd.w a2, a1, (t0)
+                csrw  mscratch, a1
+                .half 0x4515     # c.li a0, 5
+                .half 0x0001     # c.nop
+                li    a7, 93
+                ecall
+            ",
+        );
+        assert_eq!(
+            lines,
+            [
+                "core   0: 3 0x80000000 (0x00700593) x11 0x00000007",
+                "core   0: 3 0x80000004 (0x800012b7) x5  0x80001000",
+                "core   0: 3 0x80000008 (0x00b2a023) mem 0x80001000 0x00000007",
+                "core   0: 3 0x8000000c (0x00b2a62f) x12 0x00000007 mem 0x80001000 \
+                 mem 0x80001000 0x0000000e",
+                "core   0: 3 0x80000010 (0x34059073) c832_mscratch 0x00000007",
+                "core   0: 3 0x80000014 (0x4515) x10 0x00000005",
+                "core   0: 3 0x80000016 (0x0001)",
+                "core   0: 3 0x80000018 (0x05d00893) x17 0x0000005d",
+                "core   0: 3 0x8000001c (0x00000073)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_implicit_csr_writes() {
+        // lines as spike --log-commits prints them: the first FP instruction dirties mstatus.FS,
+        // an inexact fdiv.s accrues fflags.NX, both before the f register in spike's key order.
+        // fsd and fld are a single 8 byte access.
+        let lines = commit_log(
+            "
+                li    a1, 1
+                li    a2, 3
+                .word 0xd005f0d3 # fcvt.s.w f1, a1
+                .word 0xd0067153 # fcvt.s.w f2, a2
+                .word 0x1820f1d3 # fdiv.s f3, f1, f2
+                lui   t0, 0x80001
+                .word 0x0032b027 # fsd f3, 0(t0)
+                .word 0x0002b207 # fld f4, 0(t0)
+                .half 0x4515     # c.li a0, 5
+                li    a7, 93
+                ecall
+            ",
+        );
+        assert_eq!(
+            lines[2..9],
+            [
+                "core   0: 3 0x80000008 (0xd005f0d3) f1  0xffffffff3f800000 \
+                 c768_mstatus 0x80006000",
+                "core   0: 3 0x8000000c (0xd0067153) f2  0xffffffff40400000",
+                "core   0: 3 0x80000010 (0x1820f1d3) c1_fflags 0x00000001 \
+                 f3  0xffffffff3eaaaaab",
+                "core   0: 3 0x80000014 (0x800012b7) x5  0x80001000",
+                "core   0: 3 0x80000018 (0x0032b027) mem 0x80001000 0xffffffff3eaaaaab",
+                "core   0: 3 0x8000001c (0x0002b207) f4  0xffffffff3eaaaaab mem 0x80001000",
+                "core   0: 3 0x80000020 (0x4515) x10 0x00000005",
+            ]
+        );
+    }
+}
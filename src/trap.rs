@@ -0,0 +1,153 @@
+use std::fmt;
+
+use crate::csr::Privilege;
+
+/// Synchronous exceptions raised while fetching, decoding or executing an instruction.
+///
+/// The payload is the value the privileged spec puts in `mtval`: the faulting address for
+/// misaligned / access fault / page fault exceptions and the instruction bits for illegal
+/// instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u32),
+    InstructionAccessFault(u32),
+    IllegalInstruction(u32),
+    Breakpoint,
+    LoadAddressMisaligned(u32),
+    LoadAccessFault(u32),
+    StoreAddressMisaligned(u32),
+    StoreAccessFault(u32),
+    /// ecall, from the privilege level it was executed at
+    EnvironmentCall(Privilege),
+    InstructionPageFault(u32),
+    LoadPageFault(u32),
+    StorePageFault(u32),
+}
+
+impl Exception {
+    /// Exception code as written to `mcause`
+    pub fn code(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCall(privilege) => 8 + *privilege as u32,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Trap value as written to `mtval`
+    pub fn tval(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAddressMisaligned(addr)
+            | Exception::StoreAccessFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StorePageFault(addr) => addr,
+            Exception::IllegalInstruction(instruction) => instruction,
+            Exception::Breakpoint | Exception::EnvironmentCall(_) => 0,
+        }
+    }
+}
+
+/// Interrupt bit of `mcause`
+pub const INTERRUPT_BIT: u32 = 1 << 31;
+
+/// Asynchronous interrupts, taken between instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    /// Interrupt code as written to `mcause`, without the interrupt bit
+    pub fn code(&self) -> u32 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::MachineSoftware => 3,
+            Interrupt::SupervisorTimer => 5,
+            Interrupt::MachineTimer => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::MachineExternal => 11,
+        }
+    }
+
+    /// Highest priority interrupt in a set of mip bits: machine before supervisor, and external,
+    /// then software, then timer within a privilege level
+    pub fn highest(pending: u32) -> Option<Self> {
+        [
+            Interrupt::MachineExternal,
+            Interrupt::MachineSoftware,
+            Interrupt::MachineTimer,
+            Interrupt::SupervisorExternal,
+            Interrupt::SupervisorSoftware,
+            Interrupt::SupervisorTimer,
+        ]
+        .into_iter()
+        .find(|interrupt| pending & (1 << interrupt.code()) != 0)
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::SupervisorSoftware => write!(f, "supervisor software interrupt"),
+            Interrupt::MachineSoftware => write!(f, "machine software interrupt"),
+            Interrupt::SupervisorTimer => write!(f, "supervisor timer interrupt"),
+            Interrupt::MachineTimer => write!(f, "machine timer interrupt"),
+            Interrupt::SupervisorExternal => write!(f, "supervisor external interrupt"),
+            Interrupt::MachineExternal => write!(f, "machine external interrupt"),
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::InstructionAddressMisaligned(addr) => {
+                write!(f, "instruction address misaligned (0x{:08x})", addr)
+            }
+            Exception::InstructionAccessFault(addr) => {
+                write!(f, "instruction access fault (0x{:08x})", addr)
+            }
+            Exception::IllegalInstruction(instruction) => {
+                write!(f, "illegal instruction (0x{:08x})", instruction)
+            }
+            Exception::Breakpoint => write!(f, "breakpoint"),
+            Exception::LoadAddressMisaligned(addr) => {
+                write!(f, "load address misaligned (0x{:08x})", addr)
+            }
+            Exception::LoadAccessFault(addr) => write!(f, "load access fault (0x{:08x})", addr),
+            Exception::StoreAddressMisaligned(addr) => {
+                write!(f, "store address misaligned (0x{:08x})", addr)
+            }
+            Exception::StoreAccessFault(addr) => write!(f, "store access fault (0x{:08x})", addr),
+            Exception::EnvironmentCall(privilege) => {
+                write!(f, "environment call from {}-mode", privilege)
+            }
+            Exception::InstructionPageFault(addr) => {
+                write!(f, "instruction page fault (0x{:08x})", addr)
+            }
+            Exception::LoadPageFault(addr) => write!(f, "load page fault (0x{:08x})", addr),
+            Exception::StorePageFault(addr) => write!(f, "store page fault (0x{:08x})", addr),
+        }
+    }
+}
+
+impl std::error::Error for Exception {}
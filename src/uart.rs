@@ -0,0 +1,310 @@
+//! NS16550A compatible UART, byte wide registers as on the QEMU `virt` board.
+//!
+//! Transmitted bytes go straight to the host, so the transmitter is always empty. Received bytes
+//! come from a channel, either fed by the host program or by a thread reading any `Read`.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::bus::{BusError, Device};
+use crate::ram::MemoryAccessSize;
+
+/// where QEMU `virt` puts its UART, and what most firmware expects
+pub const DEFAULT_UART_BASE: u32 = 0x1000_0000;
+const UART_SIZE: u32 = 0x100;
+/// interrupt controller source of the UART on QEMU `virt`
+pub const DEFAULT_UART_IRQ: u32 = 10;
+
+// register offsets
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+// interrupt enable
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+// interrupt identification, lowest bit clear means an interrupt is pending
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// line status
+pub const LSR_DATA_READY: u8 = 1 << 0;
+pub const LSR_THR_EMPTY: u8 = 1 << 5;
+pub const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+pub struct Uart {
+    /// bytes from the host, the reader thread starts on the first poll
+    input: Option<Box<dyn Read + Send>>,
+    rx: Receiver<u8>,
+    tx: Sender<u8>,
+    /// received bytes the guest hasn't read yet
+    rx_fifo: VecDeque<u8>,
+    output: Box<dyn Write>,
+
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    /// THR empty interrupt, raised by enabling it or by a transmit and cleared by reading IIR
+    thr_empty_pending: bool,
+}
+
+impl Uart {
+    /// UART without a host input, bytes sent through the returned sender show up in RBR
+    pub fn new(output: Box<dyn Write>) -> (Self, Sender<u8>) {
+        let (tx, rx) = mpsc::channel();
+        let uart = Self {
+            input: None,
+            rx,
+            tx: tx.clone(),
+            rx_fifo: VecDeque::new(),
+            output,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thr_empty_pending: false,
+        };
+        (uart, tx)
+    }
+
+    /// UART receiving from `input`, read on a background thread so the guest never blocks
+    pub fn with_input(input: Box<dyn Read + Send>, output: Box<dyn Write>) -> Self {
+        let (mut uart, _) = Self::new(output);
+        uart.input = Some(input);
+        uart
+    }
+
+    /// UART on the host stdin and stdout
+    pub fn stdio() -> Self {
+        Self::with_input(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    /// Move bytes that arrived from the host into the receive FIFO
+    fn poll(&mut self) {
+        if let Some(mut input) = self.input.take() {
+            let tx = self.tx.clone();
+            thread::spawn(move || {
+                let mut byte = [0u8];
+                while let Ok(1) = input.read(&mut byte) {
+                    if tx.send(byte[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        self.rx_fifo.extend(self.rx.try_iter());
+    }
+
+    /// Line to the interrupt controller
+    pub fn interrupt_pending(&mut self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+
+    fn interrupt_id(&mut self) -> u8 {
+        self.poll();
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn read_register(&mut self, offset: u32) -> u8 {
+        match offset {
+            RBR_THR_DLL if self.dlab() => self.divisor as u8,
+            RBR_THR_DLL => {
+                self.poll();
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            IER_DLM if self.dlab() => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                let fifo = if self.fifo_enabled {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll();
+                let data_ready = if self.rx_fifo.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                data_ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            // in loopback the modem outputs (DTR, RTS, OUT1, OUT2) come back as CTS, DSR, RI, DCD
+            MSR if self.mcr & MCR_LOOPBACK != 0 => {
+                let mcr = self.mcr;
+                ((mcr & 0b10) << 3)
+                    | ((mcr & 0b01) << 5)
+                    | ((mcr & 0b100) << 4)
+                    | ((mcr & 0b1000) << 4)
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: u8) {
+        match offset {
+            RBR_THR_DLL if self.dlab() => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR_THR_DLL => {
+                if self.mcr & MCR_LOOPBACK != 0 {
+                    self.rx_fifo.push_back(value);
+                } else {
+                    // the guest can't do anything about a closed host output
+                    let _ = self.output.write_all(&[value]);
+                    let _ = self.output.flush();
+                }
+                self.thr_empty_pending = true;
+            }
+            IER_DLM if self.dlab() => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            IER_DLM => {
+                // enabling the THR empty interrupt fires it right away, the transmitter is idle
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0F;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.poll();
+                    self.rx_fifo.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            SCR => self.scr = value,
+            // LSR and MSR are read only
+            _ => {}
+        }
+    }
+}
+
+impl Device for Uart {
+    fn size(&self) -> u64 {
+        UART_SIZE as u64
+    }
+
+    /// Registers are a byte wide, wider accesses read the register zero extended
+    fn read(&mut self, offset: u32, _size: MemoryAccessSize) -> Result<u32, BusError> {
+        Ok(self.read_register(offset) as u32)
+    }
+
+    fn write(&mut self, offset: u32, _size: MemoryAccessSize, value: u32) -> Result<(), BusError> {
+        self.write_register(offset, value as u8);
+        Ok(())
+    }
+
+    fn irq_line(&mut self) -> bool {
+        // don't start reading the host input for a guest that never enabled interrupts
+        self.ier != 0 && self.interrupt_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SharedBuffer;
+
+    #[test]
+    fn test_transmit_and_receive() {
+        let output = SharedBuffer::default();
+        let (mut uart, input) = Uart::new(Box::new(output.clone()));
+
+        for byte in b"hi\n" {
+            uart.write_register(RBR_THR_DLL, *byte);
+        }
+        assert_eq!(output.0.borrow().as_slice(), b"hi\n");
+        assert_eq!(
+            uart.read_register(LSR),
+            LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+        );
+
+        input.send(b'x').unwrap();
+        assert_eq!(uart.read_register(LSR) & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.read_register(RBR_THR_DLL), b'x');
+        assert_eq!(uart.read_register(LSR) & LSR_DATA_READY, 0);
+    }
+
+    #[test]
+    fn test_divisor_latch_and_interrupts() {
+        let (mut uart, input) = Uart::new(Box::new(io::sink()));
+        uart.write_register(LCR, LCR_DLAB | 0b11);
+        uart.write_register(RBR_THR_DLL, 0x03);
+        uart.write_register(IER_DLM, 0x00);
+        uart.write_register(LCR, 0b11);
+        assert_eq!(uart.divisor, 3);
+        assert_eq!(uart.read_register(IER_DLM), 0);
+
+        assert!(!uart.interrupt_pending());
+        uart.write_register(IER_DLM, IER_RX_AVAILABLE);
+        input.send(b'a').unwrap();
+        assert!(uart.interrupt_pending());
+        assert_eq!(uart.read_register(IIR_FCR), IIR_RX_AVAILABLE);
+        uart.read_register(RBR_THR_DLL);
+        assert!(!uart.interrupt_pending());
+
+        // THR empty fires once when enabled and is acknowledged by reading IIR
+        uart.write_register(IER_DLM, IER_THR_EMPTY);
+        assert_eq!(uart.read_register(IIR_FCR), IIR_THR_EMPTY);
+        assert_eq!(uart.read_register(IIR_FCR), IIR_NONE);
+    }
+
+    #[test]
+    fn test_reader_input() {
+        let mut uart = Uart::with_input(
+            Box::new(io::Cursor::new(b"ok".to_vec())),
+            Box::new(io::sink()),
+        );
+        let mut received = Vec::new();
+        // the reader thread delivers asynchronously
+        for _ in 0..1000 {
+            if uart.read_register(LSR) & LSR_DATA_READY != 0 {
+                received.push(uart.read_register(RBR_THR_DLL));
+            }
+            if received.len() == 2 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(received, b"ok");
+    }
+}